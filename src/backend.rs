@@ -0,0 +1,245 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use regex::Regex;
+use x11rb::protocol::xproto::{Keycode, Window};
+
+use crate::x11::{InstanceInfo, Rect, WindowError, MWM_HINTS_DECORATIONS};
+
+/// Everything rulti asks of the window system, so the manager and instances can run against a fake one.
+pub trait WindowBackend: Send + Sync {
+    /// Windows whose title contains `name`, numbered by the first capture of the regex in their game directory
+    fn find_instances(&self, name: &str, instance_num_regex: &Regex) -> Result<Vec<InstanceInfo>, WindowError>;
+    fn set_title(&self, window: Window, title: &str) -> Result<(), WindowError>;
+
+    /// Asks the window manager to focus the window.
+    fn activate(&self, window: Window) -> Result<(), WindowError>;
+    fn wait_for_active(&self, window: Window, timeout: Duration) -> Result<(), WindowError>;
+    /// The projector window, if one is open
+    fn find_wall_window(&self) -> Result<Option<Window>, WindowError>;
+
+    fn geometry(&self, window: Window) -> Result<Rect, WindowError>;
+    /// The monitor the center of the window is on
+    fn monitor_geometry(&self, window: Window) -> Result<Rect, WindowError>;
+    fn primary_monitor_geometry(&self) -> Result<Rect, WindowError>;
+    /// Moves and resizes the window, waiting until the window manager did it.
+    fn set_geometry(&self, window: Window, rect: Rect, timeout: Duration) -> Result<(), WindowError>;
+    /// Moves and optionally resizes the window without waiting.
+    fn move_window(&self, window: Window, rect: Rect, resize: bool) -> Result<(), WindowError>;
+    fn set_fullscreen(&self, window: Window, fullscreen: bool, timeout: Duration) -> Result<(), WindowError>;
+    /// Removes the decorations, returning the `_MOTIF_WM_HINTS` to restore them with.
+    fn remove_decorations(&self, window: Window) -> Result<Option<[u32; 5]>, WindowError>;
+    fn restore_decorations(&self, window: Window, original: Option<[u32; 5]>) -> Result<(), WindowError>;
+
+    /// Sends key presses (`true`) and releases (`false`) to the window in order.
+    fn send_keys(&self, window: Window, keys: &[(Keycode, bool)]) -> Result<(), WindowError>;
+    fn send_keypress(&self, window: Window, key: Keycode) -> Result<(), WindowError> {
+        self.send_keys(window, &[(key, true), (key, false)])
+    }
+
+    /// Grabs the key together with Control on the whole screen.
+    fn grab_key(&self, key: Keycode) -> Result<(), WindowError>;
+    fn ungrab_key(&self, key: Keycode) -> Result<(), WindowError>;
+    /// The next pressed grabbed key, without blocking
+    fn poll_hotkey(&self) -> Option<Keycode>;
+    fn pointer_position(&self) -> Result<(i16, i16), WindowError>;
+}
+
+/// A window of the fake backend
+#[derive(Clone, Debug)]
+pub struct FakeWindow {
+    pub info: InstanceInfo,
+    pub title: String,
+    pub rect: Rect,
+    /// Geometry from before the window went fullscreen, set while it is fullscreen
+    pub windowed: Option<Rect>,
+    pub motif_hints: Option<[u32; 5]>,
+    /// Every key sent to the window
+    pub keys: Vec<(Keycode, bool)>,
+}
+
+#[derive(Debug)]
+pub struct FakeState {
+    pub windows: BTreeMap<Window, FakeWindow>,
+    pub monitor: Rect,
+    pub active: Option<Window>,
+    pub wall_window: Option<Window>,
+    /// Makes the window manager ignore activation requests, so waiting for focus times out
+    pub focus_refused: bool,
+    pub grabbed: BTreeSet<Keycode>,
+    pub pending_hotkeys: VecDeque<Keycode>,
+    pub pointer: (i16, i16),
+}
+
+/// An in-memory window system with a single monitor and a window manager that does everything immediately
+pub struct FakeBackend {
+    state: Mutex<FakeState>,
+}
+
+impl FakeBackend {
+    pub fn new(monitor: Rect) -> Self {
+        Self {
+            state: Mutex::new(FakeState {
+                windows: BTreeMap::new(),
+                monitor,
+                active: None,
+                wall_window: None,
+                focus_refused: false,
+                grabbed: BTreeSet::new(),
+                pending_hotkeys: VecDeque::new(),
+                pointer: (0, 0),
+            }),
+        }
+    }
+
+    pub fn add_window(&self, info: InstanceInfo, title: &str, rect: Rect) {
+        let window = FakeWindow {
+            info: info.clone(),
+            title: title.to_string(),
+            rect,
+            windowed: None,
+            motif_hints: None,
+            keys: Vec::new(),
+        };
+        self.state().windows.insert(info.window, window);
+    }
+
+    /// Queues a key press for `poll_hotkey`, dropped like on X11 when the key isn't grabbed.
+    pub fn press_hotkey(&self, key: Keycode) {
+        let mut state = self.state();
+        if state.grabbed.contains(&key) {
+            state.pending_hotkeys.push_back(key);
+        }
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap()
+    }
+
+    fn with_window<T>(&self, window: Window, f: impl FnOnce(&mut FakeWindow, Rect) -> T) -> Result<T, WindowError> {
+        let mut state = self.state();
+        let monitor = state.monitor;
+        let fake_window = state.windows.get_mut(&window).ok_or(WindowError::NoWindow(window))?;
+        Ok(f(fake_window, monitor))
+    }
+}
+
+impl WindowBackend for FakeBackend {
+    fn find_instances(&self, name: &str, instance_num_regex: &Regex) -> Result<Vec<InstanceInfo>, WindowError> {
+        Ok(self
+            .state()
+            .windows
+            .values()
+            .filter(|window| window.title.contains(name) && instance_num_regex.is_match(&window.info.gamedir))
+            .map(|window| window.info.clone())
+            .collect())
+    }
+
+    fn set_title(&self, window: Window, title: &str) -> Result<(), WindowError> {
+        self.with_window(window, |fake_window, _| fake_window.title = title.to_string())
+    }
+
+    fn activate(&self, window: Window) -> Result<(), WindowError> {
+        let mut state = self.state();
+        if !state.windows.contains_key(&window) && state.wall_window != Some(window) {
+            return Err(WindowError::NoWindow(window));
+        }
+        if !state.focus_refused {
+            state.active = Some(window);
+        }
+        Ok(())
+    }
+
+    fn wait_for_active(&self, window: Window, _timeout: Duration) -> Result<(), WindowError> {
+        match self.state().active == Some(window) {
+            true => Ok(()),
+            false => Err(WindowError::Timeout(window)),
+        }
+    }
+
+    fn find_wall_window(&self) -> Result<Option<Window>, WindowError> {
+        Ok(self.state().wall_window)
+    }
+
+    fn geometry(&self, window: Window) -> Result<Rect, WindowError> {
+        self.with_window(window, |fake_window, _| fake_window.rect)
+    }
+
+    fn monitor_geometry(&self, window: Window) -> Result<Rect, WindowError> {
+        self.with_window(window, |_, monitor| monitor)
+    }
+
+    fn primary_monitor_geometry(&self) -> Result<Rect, WindowError> {
+        Ok(self.state().monitor)
+    }
+
+    fn set_geometry(&self, window: Window, rect: Rect, _timeout: Duration) -> Result<(), WindowError> {
+        self.move_window(window, rect, true)
+    }
+
+    fn move_window(&self, window: Window, rect: Rect, resize: bool) -> Result<(), WindowError> {
+        self.with_window(window, |fake_window, _| {
+            // Like window managers do, fullscreen windows keep covering the monitor
+            if fake_window.windowed.is_some() {
+                return;
+            }
+            fake_window.rect.x = rect.x;
+            fake_window.rect.y = rect.y;
+            if resize {
+                fake_window.rect.width = rect.width;
+                fake_window.rect.height = rect.height;
+            }
+        })
+    }
+
+    fn set_fullscreen(&self, window: Window, fullscreen: bool, _timeout: Duration) -> Result<(), WindowError> {
+        self.with_window(window, |fake_window, monitor| {
+            match (fullscreen, fake_window.windowed) {
+                (true, None) => {
+                    fake_window.windowed = Some(fake_window.rect);
+                    fake_window.rect = monitor;
+                }
+                (false, Some(windowed)) => {
+                    fake_window.rect = windowed;
+                    fake_window.windowed = None;
+                }
+                _ => {}
+            }
+        })
+    }
+
+    fn remove_decorations(&self, window: Window) -> Result<Option<[u32; 5]>, WindowError> {
+        self.with_window(window, |fake_window, _| {
+            fake_window.motif_hints.replace([MWM_HINTS_DECORATIONS, 0, 0, 0, 0])
+        })
+    }
+
+    fn restore_decorations(&self, window: Window, original: Option<[u32; 5]>) -> Result<(), WindowError> {
+        self.with_window(window, |fake_window, _| fake_window.motif_hints = original)
+    }
+
+    fn send_keys(&self, window: Window, keys: &[(Keycode, bool)]) -> Result<(), WindowError> {
+        self.with_window(window, |fake_window, _| fake_window.keys.extend_from_slice(keys))
+    }
+
+    fn grab_key(&self, key: Keycode) -> Result<(), WindowError> {
+        self.state().grabbed.insert(key);
+        Ok(())
+    }
+
+    fn ungrab_key(&self, key: Keycode) -> Result<(), WindowError> {
+        self.state().grabbed.remove(&key);
+        Ok(())
+    }
+
+    fn poll_hotkey(&self) -> Option<Keycode> {
+        self.state().pending_hotkeys.pop_front()
+    }
+
+    fn pointer_position(&self) -> Result<(i16, i16), WindowError> {
+        Ok(self.state().pointer)
+    }
+}
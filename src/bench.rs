@@ -0,0 +1,201 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::channel;
+
+use crate::{backend::WindowBackend, config::Config, instance::InstanceState, instancemanager::{InstanceManager, SystemEffects}, x11::X11Backend};
+
+/// When a benchmark stops, whichever comes first
+#[derive(Clone, Copy, Debug)]
+pub struct BenchLimit {
+    pub resets: Option<u64>,
+    pub duration: Option<Duration>,
+}
+
+impl BenchLimit {
+    fn reached(&self, resets: u64, elapsed: Duration) -> bool {
+        self.resets.is_some_and(|limit| resets >= limit) || self.duration.is_some_and(|limit| elapsed >= limit)
+    }
+}
+
+#[derive(Default)]
+pub struct BenchReport {
+    pub elapsed: Duration,
+    /// Reset to preview time of every finished reset in milliseconds
+    pub preview_ms: Vec<u64>,
+    pub instances: BTreeMap<u32, Vec<u64>>,
+}
+
+impl BenchReport {
+    pub fn resets(&self) -> u64 {
+        self.preview_ms.len() as u64
+    }
+
+    pub fn resets_per_minute(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        self.resets() as f64 * 60.0 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// The value below which `percent` of the sorted values fall
+fn percentile(sorted: &[u64], percent: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (sorted.len() as f64 * percent / 100.0).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn sorted(values: &[u64]) -> Vec<u64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let preview_ms = sorted(&self.preview_ms);
+        writeln!(f, "Resets:      {} in {:.1}s", self.resets(), self.elapsed.as_secs_f64())?;
+        writeln!(f, "Throughput:  {:.1} resets/min", self.resets_per_minute())?;
+        writeln!(
+            f,
+            "Preview:     p50 {}ms, p90 {}ms, p99 {}ms, max {}ms",
+            percentile(&preview_ms, 50.0),
+            percentile(&preview_ms, 90.0),
+            percentile(&preview_ms, 99.0),
+            preview_ms.last().copied().unwrap_or_default()
+        )?;
+        writeln!(f)?;
+        writeln!(f, "{:>8}  {:>6}  {:>10}  {:>8}  {:>8}", "instance", "resets", "resets/min", "p50", "p90")?;
+        for (instance_num, preview_ms) in &self.instances {
+            let preview_ms = sorted(preview_ms);
+            let resets_per_minute = if self.elapsed.is_zero() {
+                0.0
+            } else {
+                preview_ms.len() as f64 * 60.0 / self.elapsed.as_secs_f64()
+            };
+            writeln!(
+                f,
+                "{:>8}  {:>6}  {:>10.1}  {:>6}ms  {:>6}ms",
+                instance_num,
+                preview_ms.len(),
+                resets_per_minute,
+                percentile(&preview_ms, 50.0),
+                percentile(&preview_ms, 90.0)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Resets every instance again as soon as its preview is ready, until the limit is reached.
+/// With `hold_playing` the first instance is left alone as if it was being played,
+/// which measures the resets in the background of a run.
+pub async fn run(config: &Config, limit: BenchLimit, hold_playing: bool) -> Result<BenchReport, String> {
+    let backend = Arc::new(X11Backend::connect().map_err(|err| err.to_string())?);
+    let instances = backend
+        .find_instances(&config.instances.window_name, &config.instances.instance_num_regex())
+        .map_err(|err| format!("Failed to find instances: {err}"))?;
+    if instances.is_empty() {
+        return Err("No instances found".into());
+    }
+    if hold_playing && instances.len() < 2 {
+        return Err("Benchmarking with a played instance needs at least 2 instances".into());
+    }
+
+    // Nothing but the resets should take time, so everything that follows the wall stays off
+    let config = Config {
+        moving_wall: false,
+        projector: false,
+        obs: None,
+        ..config.clone()
+    };
+    let mut preview_channel = channel(100);
+    let mut percent_channel = channel(100);
+    let mut instance_manager = InstanceManager::initialize(
+        preview_channel.0,
+        percent_channel.0,
+        instances,
+        backend,
+        Box::new(SystemEffects::new()),
+        None,
+        &config,
+    );
+    let mut benched_instances = instance_manager.instances.clone();
+    if hold_playing {
+        benched_instances.remove(0).set_state(InstanceState::Playing);
+    }
+
+    let mut report = BenchReport::default();
+    let mut reset_started: HashMap<u32, Instant> = HashMap::new();
+    let start = Instant::now();
+    for instance in benched_instances {
+        reset_started.insert(instance.instance_info.instance_num, Instant::now());
+        instance_manager.reset_instance(instance);
+    }
+
+    while !limit.reached(report.resets(), start.elapsed()) {
+        while let Ok(instance_num) = preview_channel.1.try_recv() {
+            let Some(instance) = instance_manager.get_instance_by_instance_num(instance_num) else {
+                continue;
+            };
+            if let Some(started) = reset_started.insert(instance_num, Instant::now()) {
+                let preview_ms = started.elapsed().as_millis() as u64;
+                report.preview_ms.push(preview_ms);
+                report.instances.entry(instance_num).or_default().push(preview_ms);
+            }
+            instance_manager.reset_instance(instance);
+        }
+        // The instances block on a full percent channel, so it has to be drained even though it isn't used
+        while percent_channel.1.try_recv().is_ok() {}
+        instance_manager.update_affinities();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    report.elapsed = start.elapsed();
+    instance_manager.shutdown();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_of_nothing_is_zero() {
+        assert_eq!(percentile(&[], 50.0), 0);
+        assert_eq!(percentile(&[], 99.0), 0);
+    }
+
+    #[test]
+    fn percentile_of_one_value_is_that_value() {
+        for percent in [0.0, 1.0, 50.0, 99.0, 100.0] {
+            assert_eq!(percentile(&[42], percent), 42);
+        }
+    }
+
+    #[test]
+    fn percentile_uses_the_nearest_rank() {
+        let values: Vec<u64> = (1..=10).map(|value| value * 100).collect();
+        assert_eq!(percentile(&values, 0.0), 100);
+        assert_eq!(percentile(&values, 10.0), 100);
+        assert_eq!(percentile(&values, 11.0), 200);
+        assert_eq!(percentile(&values, 50.0), 500);
+        assert_eq!(percentile(&values, 90.0), 900);
+        assert_eq!(percentile(&values, 95.0), 1000);
+        assert_eq!(percentile(&values, 100.0), 1000);
+    }
+
+    #[test]
+    fn sorted_leaves_the_input_alone() {
+        let values = [30, 10, 20, 10];
+        assert_eq!(sorted(&values), vec![10, 10, 20, 30]);
+        assert_eq!(values, [30, 10, 20, 10]);
+        assert_eq!(percentile(&sorted(&values), 50.0), 10);
+    }
+}
@@ -0,0 +1,381 @@
+//! A stand-in for a Minecraft instance with WorldPreview, so rulti can run without the game.
+//!
+//! Every simulated instance is its own process with a window titled like the game, `_NET_WM_PID` set
+//! and `RSG N/.minecraft` as its working directory, where it writes `wpstateout.txt` like WorldPreview does.
+
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{self, Command},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use clap::Parser;
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xproto::{
+            AtomEnum, ChangeWindowAttributesAux, ClientMessageEvent, ConnectionExt, CreateWindowAux, EventMask,
+            PropMode, Window, WindowClass,
+        },
+        Event,
+    },
+    rust_connection::RustConnection,
+    wrapper::ConnectionExt as _,
+    COPY_DEPTH_FROM_PARENT,
+};
+
+const TITLE: &str = "Minecraft* 1.16.1";
+const TICK: Duration = Duration::from_millis(5);
+/// How often the preview percentage is written while it goes up
+const PERCENT_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Parser, Clone)]
+#[command(name = "rulti-sim", about = "Simulated Minecraft instances for running rulti without the game")]
+struct Args {
+    /// Instance numbers to simulate, every one in its own process
+    #[arg(required = true)]
+    instances: Vec<u32>,
+    /// Directory the `RSG N/.minecraft` game directories are created in
+    #[arg(long, default_value = ".")]
+    root: PathBuf,
+    /// X keycode that resets the world, F6 in rulti
+    #[arg(long, default_value_t = 72)]
+    reset_key: u8,
+    /// X keycode that together with Escape hides the menu or pauses, F3 in rulti
+    #[arg(long, default_value_t = 69)]
+    f3_key: u8,
+    #[arg(long, default_value_t = 9)]
+    escape_key: u8,
+    /// X keycode that toggles fullscreen like F11 in the game
+    #[arg(long, default_value_t = 95)]
+    fullscreen_key: u8,
+    /// Time between the reset key and the world starting to generate
+    #[arg(long, default_value_t = 100)]
+    leave_ms: u64,
+    /// Time the world generates before the preview shows up
+    #[arg(long, default_value_t = 1000)]
+    generate_ms: u64,
+    /// Time the preview takes to go from 0 to 100 percent and join the world
+    #[arg(long, default_value_t = 3000)]
+    preview_ms: u64,
+    /// Every duration is scaled by a random factor up to this far from 1
+    #[arg(long, default_value_t = 0.2)]
+    jitter: f64,
+    /// Exit without a trace after this many resets, like a crashed game
+    #[arg(long)]
+    crash_after: Option<u64>,
+    /// Share of reset keypresses that are ignored
+    #[arg(long, default_value_t = 0.0)]
+    drop_key_rate: f64,
+    /// Share of resets that never finish generating until the next reset
+    #[arg(long, default_value_t = 0.0)]
+    stall_rate: f64,
+    /// Share of resets that join the world without writing a preview
+    #[arg(long, default_value_t = 0.0)]
+    skip_preview_rate: f64,
+    /// Seed for the jitter and failures, the instance number is added to it
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Set on the processes spawned for each instance
+    #[arg(long, hide = true)]
+    instance_process: Option<u32>,
+}
+
+/// xorshift64*, good enough for jitter and failure injection
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    /// A number in `0.0..1.0`
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, rate: f64) -> bool {
+        self.next() < rate
+    }
+}
+
+/// What the simulated game is doing, mirroring the states WorldPreview writes
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Title,
+    Leaving { until: Instant },
+    Generating { until: Option<Instant> },
+    Previewing { started: Instant, duration: Duration },
+    InWorld { paused: bool },
+}
+
+struct Sim {
+    args: Args,
+    instance_num: u32,
+    state_file: PathBuf,
+    rng: Rng,
+    state: State,
+    written: String,
+    last_percent_write: Instant,
+    resets: u64,
+    f3_held: bool,
+}
+
+impl Sim {
+    fn jittered(&mut self, ms: u64) -> Duration {
+        let factor = 1.0 + self.args.jitter * (self.rng.next() * 2.0 - 1.0);
+        Duration::from_secs_f64(ms as f64 / 1000.0 * factor.max(0.0))
+    }
+
+    fn write_state(&mut self, contents: String) {
+        if contents == self.written {
+            return;
+        }
+        if let Err(err) = fs::write(&self.state_file, &contents) {
+            println!("Failed to write {}: {err}", self.state_file.display());
+        }
+        self.written = contents;
+    }
+
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+        let contents = match state {
+            State::Title => "title".into(),
+            // The game still shows the old world until it starts generating
+            State::Leaving { .. } => return,
+            State::Generating { .. } => "generating,0".into(),
+            State::Previewing { .. } => "previewing,0".into(),
+            State::InWorld { paused: true } => "inworld,paused".into(),
+            State::InWorld { paused: false } => "inworld,unpaused".into(),
+        };
+        self.last_percent_write = Instant::now();
+        self.write_state(contents);
+    }
+
+    fn reset(&mut self) {
+        if self.rng.chance(self.args.drop_key_rate) {
+            println!("Instance {}: dropping the reset key", self.instance_num);
+            return;
+        }
+        self.resets += 1;
+        if self.args.crash_after.is_some_and(|crash_after| self.resets > crash_after) {
+            println!("Instance {}: crashing after {} resets", self.instance_num, self.resets - 1);
+            process::exit(1);
+        }
+        let until = Instant::now() + self.jittered(self.args.leave_ms);
+        self.set_state(State::Leaving { until });
+    }
+
+    fn escape(&mut self) {
+        match self.state {
+            // F3+Esc pauses without the menu, plain Esc toggles the pause menu
+            State::InWorld { .. } if self.f3_held => self.set_state(State::InWorld { paused: true }),
+            State::InWorld { paused } => self.set_state(State::InWorld { paused: !paused }),
+            // Hides the menu during the preview, which WorldPreview doesn't write
+            _ => {}
+        }
+    }
+
+    /// Moves on to the next state once the current one is over.
+    fn tick(&mut self) {
+        let now = Instant::now();
+        match self.state {
+            State::Leaving { until } if now >= until => {
+                let until = if self.rng.chance(self.args.stall_rate) {
+                    println!("Instance {}: stalling world generation", self.instance_num);
+                    None
+                } else {
+                    Some(now + self.jittered(self.args.generate_ms))
+                };
+                self.set_state(State::Generating { until });
+            }
+            State::Generating { until: Some(until) } if now >= until => {
+                if self.rng.chance(self.args.skip_preview_rate) {
+                    self.set_state(State::InWorld { paused: false });
+                } else {
+                    let duration = self.jittered(self.args.preview_ms);
+                    self.set_state(State::Previewing { started: now, duration });
+                }
+            }
+            State::Previewing { started, duration } => {
+                let elapsed = now - started;
+                if elapsed >= duration {
+                    self.set_state(State::InWorld { paused: false });
+                } else if now - self.last_percent_write >= PERCENT_INTERVAL {
+                    let percent = (elapsed.as_secs_f64() / duration.as_secs_f64() * 100.0) as u32;
+                    self.last_percent_write = now;
+                    self.write_state(format!("previewing,{percent}"));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Background colour showing the state on the wall, assuming a 24 bit TrueColor visual
+    fn color(&self) -> u32 {
+        match self.state {
+            State::Title | State::Leaving { .. } => 0x202020,
+            State::Generating { .. } => 0x6b4a2b,
+            State::Previewing { started, duration } => {
+                let percent = (started.elapsed().as_secs_f64() / duration.as_secs_f64()).min(1.0);
+                0x003000 + (((0x90 as f64 * percent) as u32) << 8)
+            }
+            State::InWorld { paused: true } => 0x3a5a80,
+            State::InWorld { paused: false } => 0x78a7ff,
+        }
+    }
+}
+
+fn intern(conn: &RustConnection, name: &str) -> u32 {
+    conn.intern_atom(false, name.as_bytes()).unwrap().reply().unwrap().atom
+}
+
+fn create_window(conn: &RustConnection, root: Window) -> Window {
+    let window = conn.generate_id().unwrap();
+    conn.create_window(
+        COPY_DEPTH_FROM_PARENT,
+        window,
+        root,
+        0,
+        0,
+        854,
+        480,
+        0,
+        WindowClass::INPUT_OUTPUT,
+        0,
+        &CreateWindowAux::new()
+            .background_pixel(0x202020)
+            .event_mask(EventMask::KEY_PRESS | EventMask::KEY_RELEASE | EventMask::STRUCTURE_NOTIFY),
+    )
+    .unwrap();
+    let utf8_string = intern(conn, "UTF8_STRING");
+    conn.change_property8(PropMode::REPLACE, window, AtomEnum::WM_NAME, AtomEnum::STRING, TITLE.as_bytes())
+        .unwrap();
+    conn.change_property8(PropMode::REPLACE, window, intern(conn, "_NET_WM_NAME"), utf8_string, TITLE.as_bytes())
+        .unwrap();
+    conn.change_property8(PropMode::REPLACE, window, AtomEnum::WM_CLASS, AtomEnum::STRING, b"minecraft\0Minecraft\0")
+        .unwrap();
+    conn.change_property32(
+        PropMode::REPLACE,
+        window,
+        intern(conn, "_NET_WM_PID"),
+        AtomEnum::CARDINAL,
+        &[process::id()],
+    )
+    .unwrap();
+    let wm_delete_window = intern(conn, "WM_DELETE_WINDOW");
+    conn.change_property32(PropMode::REPLACE, window, intern(conn, "WM_PROTOCOLS"), AtomEnum::ATOM, &[wm_delete_window])
+        .unwrap();
+    conn.map_window(window).unwrap();
+    conn.flush().unwrap();
+    window
+}
+
+/// Asks the window manager to toggle fullscreen, like the game does on F11
+fn toggle_fullscreen(conn: &RustConnection, root: Window, window: Window) {
+    // _NET_WM_STATE_TOGGLE = 2, source indication 1 = application
+    let event = ClientMessageEvent::new(
+        32,
+        window,
+        intern(conn, "_NET_WM_STATE"),
+        [2, intern(conn, "_NET_WM_STATE_FULLSCREEN"), 0, 1, 0],
+    );
+    let mask = EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY;
+    if let Err(err) = conn.send_event(false, root, mask, event).and_then(|_| conn.flush()) {
+        println!("Failed to toggle fullscreen: {err}");
+    }
+}
+
+fn run_instance(args: Args, instance_num: u32) {
+    let gamedir = args.root.join(format!("RSG {instance_num}")).join(".minecraft");
+    fs::create_dir_all(&gamedir).unwrap();
+    let gamedir = gamedir.canonicalize().unwrap();
+    // rulti finds the game directory and with it the instance number through the working directory
+    env::set_current_dir(&gamedir).unwrap();
+
+    let (conn, screen_num) = x11rb::connect(None).unwrap_or_else(|err| {
+        println!("Failed to connect to the X server: {err}");
+        process::exit(1);
+    });
+    let root = conn.setup().roots[screen_num].root;
+    let window = create_window(&conn, root);
+    let wm_delete_window = intern(&conn, "WM_DELETE_WINDOW");
+
+    let seed = args.seed.unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |time| time.as_nanos() as u64)
+    });
+    let mut sim = Sim {
+        rng: Rng::new(seed.wrapping_add(instance_num as u64)),
+        state_file: gamedir.join("wpstateout.txt"),
+        args,
+        instance_num,
+        state: State::Title,
+        written: String::new(),
+        last_percent_write: Instant::now(),
+        resets: 0,
+        f3_held: false,
+    };
+    sim.set_state(State::Title);
+    println!("Instance {instance_num}: window {window}, pid {}", process::id());
+
+    let mut color = 0;
+    loop {
+        while let Some(event) = conn.poll_for_event().unwrap() {
+            match event {
+                Event::KeyPress(event) if event.detail == sim.args.reset_key => sim.reset(),
+                Event::KeyPress(event) if event.detail == sim.args.f3_key => sim.f3_held = true,
+                Event::KeyRelease(event) if event.detail == sim.args.f3_key => sim.f3_held = false,
+                Event::KeyPress(event) if event.detail == sim.args.escape_key => sim.escape(),
+                Event::KeyPress(event) if event.detail == sim.args.fullscreen_key => {
+                    toggle_fullscreen(&conn, root, window)
+                }
+                Event::ClientMessage(event) if event.data.as_data32()[0] == wm_delete_window => return,
+                Event::DestroyNotify(_) => return,
+                _ => {}
+            }
+        }
+        sim.tick();
+        if sim.color() != color {
+            color = sim.color();
+            let _ = conn.change_window_attributes(window, &ChangeWindowAttributesAux::new().background_pixel(color));
+            let _ = conn.clear_area(false, window, 0, 0, 0, 0);
+            let _ = conn.flush();
+        }
+        thread::sleep(TICK);
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Some(instance_num) = args.instance_process {
+        run_instance(args, instance_num);
+        return;
+    }
+
+    // Every instance needs its own pid and working directory, so they run as separate processes
+    let exe = env::current_exe().unwrap();
+    let mut children: Vec<_> = args
+        .instances
+        .iter()
+        .map(|instance_num| {
+            Command::new(&exe)
+                .args(env::args_os().skip(1))
+                .arg("--instance-process")
+                .arg(instance_num.to_string())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    let mut failed = false;
+    for child in &mut children {
+        failed |= !child.wait().is_ok_and(|status| status.success());
+    }
+    if failed {
+        process::exit(1);
+    }
+}
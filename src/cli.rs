@@ -0,0 +1,284 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand};
+use serde_json::Value;
+use x11rb::connection::Connection;
+
+use crate::{
+    bench::{self, BenchLimit},
+    config::{default_config_path, Config},
+    recording,
+    stats::{read_records, Summary},
+    tuner,
+    worldcleaner::{self, WorldCleanupConfig},
+    x11::{find_instances, InstanceInfo},
+};
+
+#[derive(Parser)]
+#[command(name = "rulti", about = "Multi-instance wall for Minecraft speedrunning on X11")]
+pub struct Cli {
+    /// Config file to use instead of `$XDG_CONFIG_HOME/rulti/rulti.toml`
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the wall (the default)
+    Run,
+    /// Print the discovered instances
+    List,
+    /// Check that the X server, window manager, instances and config are set up for rulti
+    Doctor,
+    /// Send a command to the running rulti over the control socket, e.g. `send lock 3`
+    Send {
+        #[arg(required = true, trailing_var_arg = true)]
+        action: Vec<String>,
+    },
+    /// Summarize the recorded resets, previews and runs
+    Stats,
+    /// Delete old worlds from the saves folders of the running instances once
+    Clean {
+        /// Only print what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Reset all instances continuously and report the reset throughput
+    Bench {
+        /// Stop after this many resets (500 when neither limit is given)
+        #[arg(long)]
+        resets: Option<u64>,
+        /// Stop after this many minutes
+        #[arg(long)]
+        minutes: Option<f64>,
+        /// Leave one instance alone as if it was being played
+        #[arg(long)]
+        playing: bool,
+    },
+    /// Benchmark thread counts for the instances and write the fastest ones to the config file
+    Tune {
+        /// How long every tried setting is benchmarked
+        #[arg(long, default_value_t = 0.5)]
+        minutes_per_trial: f64,
+        /// Only print the best settings
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Feed a session recorded with `record_file` back into the wall without any instances or X server
+    Replay {
+        recording: PathBuf,
+        /// Stop this many seconds into the recording
+        #[arg(long)]
+        until: Option<f64>,
+        /// Write the events of the replay to this file as newline-delimited JSON
+        #[arg(long)]
+        events: Option<PathBuf>,
+    },
+}
+
+impl Cli {
+    pub fn config_path(&self) -> Option<PathBuf> {
+        self.config.clone().or_else(default_config_path)
+    }
+}
+
+/// Loads the config, exiting with the error when it is invalid.
+pub fn load_config(path: Option<&Path>) -> Config {
+    match path {
+        Some(path) => match Config::load(path) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("{err}");
+                process::exit(1);
+            }
+        },
+        None => Config::default(),
+    }
+}
+
+/// Finds the running instances, exiting when the X server can't be asked.
+fn discover_instances(config: &Config) -> Vec<InstanceInfo> {
+    let (conn, screen_num) = match x11rb::connect(None) {
+        Ok(connected) => connected,
+        Err(err) => {
+            println!("Failed to connect to the X server: {err}");
+            process::exit(1);
+        }
+    };
+    let root = conn.setup().roots[screen_num].root;
+    let mut instances = match find_instances(
+        &conn,
+        root,
+        &config.instances.window_name,
+        &config.instances.instance_num_regex(),
+    ) {
+        Ok(instances) => instances,
+        Err(err) => {
+            println!("Failed to find instances: {err}");
+            process::exit(1);
+        }
+    };
+    instances.sort_by_key(|instance| instance.instance_num);
+    instances
+}
+
+pub fn list(config: &Config) {
+    let instances = discover_instances(config);
+
+    println!("{:>4}  {:>8}  {:>10}  {:<16}  gamedir", "num", "pid", "window", "state");
+    for instance in instances {
+        let state_file = Path::new(&instance.gamedir).join("wpstateout.txt");
+        let state = match fs::read_to_string(&state_file) {
+            Ok(state) => state.trim().to_string(),
+            Err(_) => "no wpstateout.txt".into(),
+        };
+        println!(
+            "{:>4}  {:>8}  {:>#10x}  {:<16}  {}",
+            instance.instance_num, instance.pid, instance.window, state, instance.gamedir
+        );
+    }
+}
+
+/// Sends one command line and prints the reply. `subscribe` keeps printing events until rulti goes away.
+pub fn send(config: &Config, action: &[String]) {
+    let Some(path) = &config.control_socket else {
+        println!("The control socket is disabled in the config");
+        process::exit(1);
+    };
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(err) => {
+            println!("Failed to connect to {}, is rulti running? {err}", path.display());
+            process::exit(1);
+        }
+    };
+    let line = action.join(" ");
+    if let Err(err) = writeln!(stream, "{line}") {
+        println!("Failed to send the command: {err}");
+        process::exit(1);
+    }
+
+    let mut ok = false;
+    for reply in BufReader::new(stream).lines() {
+        let Ok(reply) = reply else {
+            break;
+        };
+        println!("{reply}");
+        ok = serde_json::from_str::<Value>(&reply)
+            .map(|reply| reply["ok"] != false)
+            .unwrap_or(false);
+        if line.trim() != "subscribe" {
+            break;
+        }
+    }
+    if !ok {
+        process::exit(1);
+    }
+}
+
+pub fn stats(config: &Config) {
+    let Some(path) = &config.stats_file else {
+        println!("Stats recording is disabled in the config");
+        process::exit(1);
+    };
+    match read_records(path) {
+        Ok(records) => print!("{}", Summary::from_records(&records)),
+        Err(err) => {
+            println!("Failed to read {}: {err}", path.display());
+            process::exit(1);
+        }
+    }
+}
+
+pub fn clean(config: &Config, dry_run: bool) {
+    let gamedirs = discover_instances(config)
+        .into_iter()
+        .map(|instance| (instance.instance_num, PathBuf::from(instance.gamedir)))
+        .collect();
+    let cleanup = WorldCleanupConfig {
+        dry_run: dry_run || config.world_cleanup.dry_run,
+        ..config.world_cleanup.clone()
+    };
+    worldcleaner::set_idle_io_priority();
+    let deleted = worldcleaner::clean_once(&cleanup, &gamedirs, config.stats_file.as_deref());
+    let verb = if cleanup.dry_run { "Would delete" } else { "Deleted" };
+    println!("{verb} {deleted} old worlds");
+}
+
+const DEFAULT_BENCH_RESETS: u64 = 500;
+
+fn minutes_arg(name: &str, minutes: f64) -> Duration {
+    if !minutes.is_finite() || minutes <= 0.0 {
+        println!("--{name} has to be positive, got {minutes}");
+        process::exit(1);
+    }
+    Duration::from_secs_f64(minutes * 60.0)
+}
+
+pub async fn bench(config: &Config, resets: Option<u64>, minutes: Option<f64>, playing: bool) {
+    let duration = minutes.map(|minutes| minutes_arg("minutes", minutes));
+    let limit = BenchLimit {
+        resets: resets.or(if duration.is_none() { Some(DEFAULT_BENCH_RESETS) } else { None }),
+        duration,
+    };
+    match bench::run(config, limit, playing).await {
+        Ok(report) => print!("{report}"),
+        Err(err) => {
+            println!("{err}");
+            process::exit(1);
+        }
+    }
+}
+
+pub async fn tune(config: &Config, config_path: Option<&Path>, minutes_per_trial: f64, dry_run: bool) {
+    let trial_duration = minutes_arg("minutes-per-trial", minutes_per_trial);
+    let affinity = match tuner::tune(config, trial_duration).await {
+        Ok(affinity) => affinity,
+        Err(err) => {
+            println!("{err}");
+            process::exit(1);
+        }
+    };
+    println!();
+    println!("Best settings:");
+    println!("  wall_threads = {}", affinity.wall_threads);
+    println!("  background_threads = {}", affinity.background_threads);
+    if dry_run {
+        return;
+    }
+    let Some(config_path) = config_path else {
+        println!("No config file to write the settings to, pass --config");
+        process::exit(1);
+    };
+    if let Err(err) = tuner::write_affinity(config_path, &affinity) {
+        println!("{err}");
+        process::exit(1);
+    }
+    println!("Wrote them to {}", config_path.display());
+}
+
+pub fn replay(recording: &Path, until: Option<f64>, events: Option<&Path>) {
+    let until = until.map(|seconds| {
+        if !seconds.is_finite() || seconds < 0.0 {
+            println!("--until can't be negative, got {seconds}");
+            process::exit(1);
+        }
+        Duration::from_secs_f64(seconds)
+    });
+    match recording::replay(recording, until, events) {
+        Ok(status) => println!("{}", serde_json::to_string_pretty(&status).unwrap()),
+        Err(err) => {
+            println!("{err}");
+            process::exit(1);
+        }
+    }
+}
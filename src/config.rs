@@ -0,0 +1,621 @@
+use std::{
+    collections::HashMap,
+    env, fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use x11rb::protocol::xproto::Keycode;
+
+use crate::{
+    control,
+    layout::{LayoutConfig, LockedBarConfig},
+    obs::ObsConfig,
+    resetcounter::ResetCounterConfig,
+    speedrunigt::SpeedRunIgtConfig,
+    worldcleaner::WorldCleanupConfig,
+    x11::Rect,
+};
+
+const CONFIG_FILE_NAME: &str = "rulti.toml";
+/// How often the config file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FullscreenMode {
+    /// Let the window manager fullscreen the window through `_NET_WM_STATE_FULLSCREEN`
+    #[default]
+    Fullscreen,
+    /// Resize the window to cover the RandR output it is on
+    Borderless,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub fullscreen_mode: FullscreenMode,
+    /// Remove window manager decorations from every instance at startup
+    pub borderless: bool,
+    /// Arrange the instance windows themselves into the wall layout instead of only writing `wall_queue.json`
+    pub moving_wall: bool,
+    /// Draw the wall in rulti's own window instead of relying on an OBS projector
+    pub projector: bool,
+    /// How many instances are reset together by the reset bag hotkey
+    pub bag_size: usize,
+    /// The wall area, taken from the primary RandR monitor when not set
+    pub screen: Option<Rect>,
+    /// Where the wall state for OBS scripts is written
+    pub wall_file: PathBuf,
+    /// Unix socket accepting line commands, disabled when not set
+    pub control_socket: Option<PathBuf>,
+    /// Every event is appended to this file as newline-delimited JSON, disabled when not set
+    pub event_log: Option<PathBuf>,
+    /// Every input rulti acts on is recorded to this file for `rulti replay`, disabled when not set
+    pub record_file: Option<PathBuf>,
+    /// Loopback address of the HTTP server for browser-source overlays, e.g. `127.0.0.1:7878`, disabled when not set
+    pub http: Option<SocketAddr>,
+    /// CSV file every reset, preview, lock, play and exit is recorded in, disabled when not set
+    pub stats_file: Option<PathBuf>,
+    pub reset_counter: ResetCounterConfig,
+    pub speedrunigt: SpeedRunIgtConfig,
+    pub world_cleanup: WorldCleanupConfig,
+    pub instances: InstancesConfig,
+    pub layout: LayoutConfig,
+    pub locked_bar: LockedBarConfig,
+    pub hotkeys: HotkeysConfig,
+    pub affinity: AffinityPolicy,
+    pub timing: TimingConfig,
+    /// Drive OBS scene items through obs-websocket, disabled when not set
+    pub obs: Option<ObsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            fullscreen_mode: FullscreenMode::default(),
+            borderless: false,
+            moving_wall: false,
+            projector: false,
+            bag_size: 4,
+            screen: None,
+            wall_file: PathBuf::from("wall_queue.json"),
+            control_socket: control::default_socket_path(),
+            event_log: None,
+            record_file: None,
+            http: None,
+            stats_file: default_data_dir().map(|dir| dir.join("stats.csv")),
+            reset_counter: ResetCounterConfig::default(),
+            speedrunigt: SpeedRunIgtConfig::default(),
+            world_cleanup: WorldCleanupConfig::default(),
+            instances: InstancesConfig::default(),
+            layout: LayoutConfig::default(),
+            locked_bar: LockedBarConfig::default(),
+            hotkeys: HotkeysConfig::default(),
+            affinity: AffinityPolicy::default(),
+            timing: TimingConfig::default(),
+            obs: None,
+        }
+    }
+}
+
+/// How instance windows are found and when their previews count as loaded
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InstancesConfig {
+    /// Windows whose title contains this are instances
+    pub window_name: String,
+    /// Matched against the game directory, the first capture group is the instance number
+    pub instance_num_pattern: String,
+    /// Preview percentage after which the wall stops redrawing the instance
+    pub freeze_percent: usize,
+}
+
+impl Default for InstancesConfig {
+    fn default() -> Self {
+        Self {
+            window_name: "Minecraft".into(),
+            instance_num_pattern: "RSG (.*?)/".into(),
+            freeze_percent: 80,
+        }
+    }
+}
+
+impl InstancesConfig {
+    pub fn instance_num_regex(&self) -> Regex {
+        Regex::new(&self.instance_num_pattern).unwrap()
+    }
+}
+
+/// X keycodes of the hotkeys, all grabbed with Control held
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HotkeysConfig {
+    pub reset_bag: Keycode,
+    pub exit_instance: Keycode,
+    pub lock_or_thin: Keycode,
+    pub toggle_tall: Keycode,
+    pub toggle_wide: Keycode,
+    pub cycle_window_mode: Keycode,
+}
+
+impl Default for HotkeysConfig {
+    fn default() -> Self {
+        Self {
+            reset_bag: 66,
+            exit_instance: 30,
+            lock_or_thin: 49,
+            toggle_tall: 28,
+            toggle_wide: 33,
+            cycle_window_mode: 23,
+        }
+    }
+}
+
+impl HotkeysConfig {
+    pub fn all(&self) -> [(&'static str, Keycode); 6] {
+        [
+            ("reset_bag", self.reset_bag),
+            ("exit_instance", self.exit_instance),
+            ("lock_or_thin", self.lock_or_thin),
+            ("toggle_tall", self.toggle_tall),
+            ("toggle_wide", self.toggle_wide),
+            ("cycle_window_mode", self.cycle_window_mode),
+        ]
+    }
+
+    /// The hotkey bound to the keycode
+    pub fn action(&self, key: Keycode) -> Option<&'static str> {
+        self.all()
+            .into_iter()
+            .find(|(_, keycode)| *keycode == key)
+            .map(|(action, _)| action)
+    }
+}
+
+/// CPU masks and thread counts given to instances depending on what they are doing
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AffinityPolicy {
+    /// Threads every instance gets at startup
+    pub startup_threads: u32,
+    /// Threads per instance while on the wall
+    pub wall_threads: u32,
+    /// Threads for the first locked instance while on the wall, so it is ready when played
+    pub locked_threads: u32,
+    /// Threads for resetting instances while an instance is being played
+    pub background_threads: u32,
+    /// CPU mask of the playing instance
+    pub playing_mask: usize,
+    /// CPU mask of idle instances while an instance is being played
+    pub idle_mask: usize,
+}
+
+impl Default for AffinityPolicy {
+    fn default() -> Self {
+        Self {
+            startup_threads: 30,
+            wall_threads: 2,
+            locked_threads: 30,
+            background_threads: 15,
+            playing_mask: ((1 << 28) - 1) << 4,
+            idle_mask: (1 << 4) - 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimingConfig {
+    /// How long the window manager gets to fullscreen or restore a window
+    pub fullscreen_timeout_ms: u64,
+    /// How long the window manager gets to focus a window
+    pub focus_timeout_ms: u64,
+    /// How often `wpstateout.txt` is read while an instance resets
+    pub state_poll_ms: u64,
+    /// How often the game processes are checked for crashes
+    pub crash_check_ms: u64,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        Self {
+            fullscreen_timeout_ms: 2000,
+            focus_timeout_ms: 1000,
+            state_poll_ms: 50,
+            crash_check_ms: 1000,
+        }
+    }
+}
+
+impl TimingConfig {
+    pub fn fullscreen_timeout(&self) -> Duration {
+        Duration::from_millis(self.fullscreen_timeout_ms)
+    }
+
+    pub fn focus_timeout(&self) -> Duration {
+        Duration::from_millis(self.focus_timeout_ms)
+    }
+
+    pub fn state_poll_interval(&self) -> Duration {
+        Duration::from_millis(self.state_poll_ms)
+    }
+
+    pub fn crash_check_interval(&self) -> Duration {
+        Duration::from_millis(self.crash_check_ms)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Failed to read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Invalid config {}: {}", path.display(), e),
+            ConfigError::Invalid(path, message) => write!(f, "Invalid config {}: {}", path.display(), message),
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/rulti/rulti.toml`, falling back to `~/.config/rulti/rulti.toml`
+pub fn default_config_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("rulti").join(CONFIG_FILE_NAME))
+}
+
+/// `$XDG_DATA_HOME/rulti`, falling back to `~/.local/share/rulti`
+pub fn default_data_dir() -> Option<PathBuf> {
+    let data_dir = env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))?;
+    Some(data_dir.join("rulti"))
+}
+
+impl Config {
+    /// Reads and validates the config file. A missing file gives the defaults.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        Ok(Self::read(path)?.unwrap_or_default())
+    }
+
+    /// Reads and validates the config file, or returns `None` when there is no file.
+    fn read(path: &Path) -> Result<Option<Self>, ConfigError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(ConfigError::Io(path.to_path_buf(), err)),
+        };
+        let config: Config = toml::from_str(&contents).map_err(|err| ConfigError::Parse(path.to_path_buf(), err))?;
+        config
+            .validate()
+            .map_err(|message| ConfigError::Invalid(path.to_path_buf(), message))?;
+        Ok(Some(config))
+    }
+
+    /// Checks the values serde can't, returning a message naming the offending key.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.bag_size == 0 {
+            return Err("bag_size must be at least 1".into());
+        }
+        if let Some(screen) = self.screen {
+            if screen.width == 0 || screen.height == 0 {
+                return Err("screen must have a non-zero width and height".into());
+            }
+        }
+        if self.instances.freeze_percent > 100 {
+            return Err("instances.freeze_percent must be at most 100".into());
+        }
+        match Regex::new(&self.instances.instance_num_pattern) {
+            Ok(regex) if regex.captures_len() < 2 => {
+                return Err("instances.instance_num_pattern needs a capture group for the instance number".into())
+            }
+            Ok(_) => {}
+            Err(err) => return Err(format!("instances.instance_num_pattern is not a valid regex: {err}")),
+        }
+        self.validate_layout()?;
+
+        let mut keycodes = HashMap::new();
+        for (action, keycode) in self.hotkeys.all() {
+            // X never sends keycodes below 8
+            if keycode < 8 {
+                return Err(format!("hotkeys.{action} = {keycode} is not a valid X keycode"));
+            }
+            if let Some(other) = keycodes.insert(keycode, action) {
+                return Err(format!("hotkeys.{action} and hotkeys.{other} are both bound to {keycode}"));
+            }
+        }
+
+        let threads = [
+            ("startup_threads", self.affinity.startup_threads),
+            ("wall_threads", self.affinity.wall_threads),
+            ("locked_threads", self.affinity.locked_threads),
+            ("background_threads", self.affinity.background_threads),
+        ];
+        for (name, threads) in threads {
+            if threads == 0 || threads as usize >= usize::BITS as usize {
+                return Err(format!("affinity.{name} must be between 1 and {}", usize::BITS - 1));
+            }
+        }
+        if self.affinity.playing_mask == 0 || self.affinity.idle_mask == 0 {
+            return Err("affinity masks must leave at least one CPU".into());
+        }
+
+        let timing = [
+            ("fullscreen_timeout_ms", self.timing.fullscreen_timeout_ms),
+            ("focus_timeout_ms", self.timing.focus_timeout_ms),
+            ("state_poll_ms", self.timing.state_poll_ms),
+            ("crash_check_ms", self.timing.crash_check_ms),
+        ];
+        for (name, millis) in timing {
+            if millis == 0 {
+                return Err(format!("timing.{name} must be greater than 0"));
+            }
+        }
+
+        for output in &self.reset_counter.outputs {
+            if output.template.is_empty() {
+                return Err(format!("reset_counter.outputs template for {} is empty", output.path.display()));
+            }
+        }
+
+        if let Err(err) = Regex::new(&self.world_cleanup.name_pattern) {
+            return Err(format!("world_cleanup.name_pattern is not a valid regex: {err}"));
+        }
+        if self.world_cleanup.interval_s == 0 {
+            return Err("world_cleanup.interval_s must be greater than 0".into());
+        }
+
+        if let Some(http) = self.http {
+            // The API can play and reset instances and has no authentication
+            if !http.ip().is_loopback() {
+                return Err(format!("http = \"{http}\" must be a loopback address like 127.0.0.1"));
+            }
+        }
+
+        if let Some(obs) = &self.obs {
+            if !obs.address.contains(':') {
+                return Err(format!("obs.address = {:?} must be host:port", obs.address));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_layout(&self) -> Result<(), String> {
+        match self.layout {
+            LayoutConfig::Grid { rows, cols } => {
+                if rows == Some(0) || cols == Some(0) {
+                    return Err("layout.rows and layout.cols must be at least 1".into());
+                }
+            }
+            LayoutConfig::BagGrid {
+                bag_size,
+                bag_cols,
+                bags_horizontal,
+                bags_vertical,
+            } => {
+                if bag_size == 0 || bag_cols == 0 || bags_horizontal == 0 || bags_vertical == 0 {
+                    return Err(
+                        "layout.bag_size, bag_cols, bags_horizontal and bags_vertical must be at least 1".into(),
+                    );
+                }
+                // The wall would draw bags that aren't the ones the reset bag hotkey resets
+                if bag_size != self.bag_size {
+                    return Err(format!(
+                        "layout.bag_size = {bag_size} must be the same as bag_size = {}",
+                        self.bag_size
+                    ));
+                }
+            }
+            LayoutConfig::FocusGrid {
+                focus_width_percent,
+                locked_bar_height_percent,
+                ..
+            } => {
+                if focus_width_percent > 100 || locked_bar_height_percent > 100 {
+                    return Err("layout percentages must be at most 100".into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the two configs differ in anything that can't be applied while running
+    pub fn needs_restart(&self, other: &Config) -> bool {
+        let reloaded = Config {
+            bag_size: other.bag_size,
+            screen: other.screen,
+            layout: other.layout.clone(),
+            locked_bar: other.locked_bar.clone(),
+            hotkeys: other.hotkeys.clone(),
+            affinity: other.affinity.clone(),
+            ..self.clone()
+        };
+        reloaded != *other
+    }
+}
+
+/// Notices when the config file changes and loads the new version.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: PathBuf) -> Self {
+        let modified = modified_time(&path);
+        Self {
+            path,
+            modified,
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Returns the new config once the file has changed. Invalid configs are reported and skipped,
+    /// and so is a missing file, which editors that save by renaming leave behind for a moment.
+    pub fn poll(&mut self) -> Option<Config> {
+        if self.last_check.elapsed() < RELOAD_INTERVAL {
+            return None;
+        }
+        self.last_check = Instant::now();
+        let modified = modified_time(&self.path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        match Config::read(&self.path) {
+            Ok(Some(config)) => Some(config),
+            Ok(None) => {
+                println!("Not reloading the config: {} is gone, keeping the current one", self.path.display());
+                None
+            }
+            Err(err) => {
+                println!("Not reloading the config: {err}");
+                None
+            }
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn invalid(config: Config) -> String {
+        config.validate().unwrap_err()
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn validation_names_the_offending_key() {
+        assert!(invalid(Config {
+            bag_size: 0,
+            ..Config::default()
+        })
+        .starts_with("bag_size"));
+        let mut config = Config::default();
+        config.instances.instance_num_pattern = "RSG .*?/".into();
+        assert!(invalid(config).contains("capture group"));
+        let mut config = Config::default();
+        config.hotkeys.exit_instance = config.hotkeys.reset_bag;
+        assert!(invalid(config).contains("are both bound to"));
+        let mut config = Config::default();
+        config.timing.state_poll_ms = 0;
+        assert!(invalid(config).starts_with("timing.state_poll_ms"));
+        assert!(invalid(Config {
+            http: Some("0.0.0.0:7878".parse().unwrap()),
+            ..Config::default()
+        })
+        .contains("loopback"));
+        assert!(invalid(Config {
+            bag_size: 2,
+            layout: LayoutConfig::BagGrid {
+                bag_size: 4,
+                bag_cols: 2,
+                bags_horizontal: 2,
+                bags_vertical: 2,
+            },
+            ..Config::default()
+        })
+        .starts_with("layout.bag_size"));
+    }
+
+    #[test]
+    fn only_the_wall_settings_apply_while_running() {
+        let config = Config::default();
+        let mut reloaded = Config {
+            bag_size: 2,
+            layout: LayoutConfig::Grid {
+                rows: Some(2),
+                cols: None,
+            },
+            ..config.clone()
+        };
+        reloaded.hotkeys.reset_bag = 40;
+        reloaded.affinity.idle_mask = 1;
+        assert!(!config.needs_restart(&reloaded));
+        reloaded.wall_file = "elsewhere.json".into();
+        assert!(config.needs_restart(&reloaded));
+        assert!(config.needs_restart(&Config {
+            projector: true,
+            ..config.clone()
+        }));
+    }
+
+    #[test]
+    fn load_reports_the_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("rulti.toml");
+        assert_eq!(Config::load(&path).unwrap(), Config::default());
+
+        fs::write(&path, "bag_size = 2\n[layout]\ntype = \"grid\"\nrows = 2\n").unwrap();
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.bag_size, 2);
+        assert_eq!(config.hotkeys, HotkeysConfig::default());
+
+        fs::write(&path, "bag_sise = 2\n").unwrap();
+        assert!(matches!(Config::load(&path), Err(ConfigError::Parse(..))));
+        fs::write(&path, "bag_size = 0\n").unwrap();
+        assert!(matches!(Config::load(&path), Err(ConfigError::Invalid(..))));
+    }
+
+    /// Writes the config with a modification time of its own, so the watcher can't miss the change
+    fn write_config(path: &Path, contents: &str, age_s: u64) {
+        fs::write(path, contents).unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(age_s);
+        File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    fn poll(watcher: &mut ConfigWatcher) -> Option<Config> {
+        watcher.last_check = Instant::now() - RELOAD_INTERVAL;
+        watcher.poll()
+    }
+
+    #[test]
+    fn watcher_reloads_changes_and_keeps_the_config_while_the_file_is_gone() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("rulti.toml");
+        write_config(&path, "wall_file = \"a.json\"\n", 30);
+        let mut watcher = ConfigWatcher::new(path.clone());
+        assert_eq!(poll(&mut watcher), None);
+
+        write_config(&path, "wall_file = \"b.json\"\n", 20);
+        assert_eq!(poll(&mut watcher).unwrap().wall_file, PathBuf::from("b.json"));
+        assert_eq!(poll(&mut watcher), None);
+
+        // Saved by renaming a new file over the old one
+        fs::remove_file(&path).unwrap();
+        assert_eq!(poll(&mut watcher), None);
+        write_config(&path, "wall_file = \"c.json\"\n", 10);
+        assert_eq!(poll(&mut watcher).unwrap().wall_file, PathBuf::from("c.json"));
+
+        write_config(&path, "bag_size = 0\n", 5);
+        assert_eq!(poll(&mut watcher), None);
+        write_config(&path, "wall_file = \"d.json\"\n", 0);
+        assert_eq!(poll(&mut watcher).unwrap().wall_file, PathBuf::from("d.json"));
+    }
+}
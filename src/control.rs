@@ -0,0 +1,272 @@
+use std::{
+    env, fmt,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::Ordering::SeqCst,
+};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc::Sender, oneshot},
+};
+
+use crate::{events::EventBus, instance::InstanceState, instancemanager::InstanceManager};
+
+const SOCKET_NAME: &str = "rulti.sock";
+
+/// `$XDG_RUNTIME_DIR/rulti.sock`, or nothing when there is no runtime directory to put it in
+pub fn default_socket_path() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join(SOCKET_NAME))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlCommand {
+    Lock(u32),
+    Unlock(u32),
+    Play(u32),
+    Reset(u32),
+    ResetBag,
+    ResetAll,
+    Exit,
+    Status,
+    Layout,
+    /// Turns the connection into a stream of newline-delimited JSON events
+    Subscribe,
+}
+
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or("Empty command")?;
+        let mut instance_num = || {
+            words
+                .next()
+                .ok_or(format!("{name} needs an instance number"))?
+                .parse::<u32>()
+                .map_err(|err| format!("Invalid instance number: {err}"))
+        };
+        let command = match name {
+            "lock" => ControlCommand::Lock(instance_num()?),
+            "unlock" => ControlCommand::Unlock(instance_num()?),
+            "play" => ControlCommand::Play(instance_num()?),
+            "reset" => ControlCommand::Reset(instance_num()?),
+            "reset-bag" => ControlCommand::ResetBag,
+            "reset-all" => ControlCommand::ResetAll,
+            "exit" => ControlCommand::Exit,
+            "status" => ControlCommand::Status,
+            "layout" => ControlCommand::Layout,
+            "subscribe" => ControlCommand::Subscribe,
+            _ => return Err(format!("Unknown command: {name}")),
+        };
+        match words.next() {
+            Some(extra) => Err(format!("Unexpected argument: {extra}")),
+            None => Ok(command),
+        }
+    }
+}
+
+/// The line the command is parsed from
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControlCommand::Lock(instance_num) => write!(f, "lock {instance_num}"),
+            ControlCommand::Unlock(instance_num) => write!(f, "unlock {instance_num}"),
+            ControlCommand::Play(instance_num) => write!(f, "play {instance_num}"),
+            ControlCommand::Reset(instance_num) => write!(f, "reset {instance_num}"),
+            ControlCommand::ResetBag => write!(f, "reset-bag"),
+            ControlCommand::ResetAll => write!(f, "reset-all"),
+            ControlCommand::Exit => write!(f, "exit"),
+            ControlCommand::Status => write!(f, "status"),
+            ControlCommand::Layout => write!(f, "layout"),
+            ControlCommand::Subscribe => write!(f, "subscribe"),
+        }
+    }
+}
+
+/// A command from a socket client together with where its JSON reply goes
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    pub reply_sender: oneshot::Sender<Value>,
+}
+
+/// Removes the socket file when rulti shuts down.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Listens on the socket and forwards every command line to the main loop, which owns the `InstanceManager`.
+pub fn spawn(path: &Path, request_sender: Sender<ControlRequest>, events: EventBus) -> std::io::Result<ControlSocket> {
+    // A socket left behind by a crashed rulti would make the bind fail
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve_client(stream, request_sender.clone(), events.clone()));
+                }
+                Err(err) => println!("Failed to accept a control connection: {err}"),
+            }
+        }
+    });
+    Ok(ControlSocket {
+        path: path.to_path_buf(),
+    })
+}
+
+async fn serve_client(stream: UnixStream, request_sender: Sender<ControlRequest>, events: EventBus) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match line.parse::<ControlCommand>() {
+            Ok(ControlCommand::Subscribe) => {
+                let mut subscription = events.subscribe();
+                if writer.write_all(json!({ "ok": true }).to_string().as_bytes()).await.is_err()
+                    || writer.write_all(b"\n").await.is_err()
+                {
+                    return;
+                }
+                while let Some(event) = subscription.recv().await {
+                    if writer.write_all(event.to_line().as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+                return;
+            }
+            Ok(command) => {
+                let (reply_sender, reply_receiver) = oneshot::channel();
+                let request = ControlRequest {
+                    command,
+                    reply_sender,
+                };
+                if request_sender.send(request).await.is_err() {
+                    return;
+                }
+                reply_receiver
+                    .await
+                    .unwrap_or_else(|_| error_reply("rulti is shutting down"))
+            }
+            Err(err) => error_reply(&err),
+        };
+        let mut reply = reply.to_string();
+        reply.push('\n');
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn error_reply(error: &str) -> Value {
+    json!({ "ok": false, "error": error })
+}
+
+/// Runs a command against the manager the same way the matching hotkey would.
+pub fn handle_command(instance_manager: &mut InstanceManager, command: ControlCommand) -> Value {
+    let on_wall = instance_manager.get_playing_instance().is_none();
+    let instance_num = match command {
+        ControlCommand::Lock(instance_num)
+        | ControlCommand::Unlock(instance_num)
+        | ControlCommand::Play(instance_num)
+        | ControlCommand::Reset(instance_num) => Some(instance_num),
+        _ => None,
+    };
+    let instance = instance_num.map(|instance_num| instance_manager.get_instance_by_instance_num(instance_num));
+    if let Some(None) = instance {
+        return error_reply(&format!("No instance {}", instance_num.unwrap()));
+    }
+    let instance_state = instance.clone().flatten().map(|instance| instance.state.load(SeqCst));
+
+    let result = match command {
+        ControlCommand::Lock(instance_num) => {
+            instance_manager.preview_unlocked_wall_queue.remove_by_instance_num(instance_num);
+            instance_manager.lock(instance_num);
+            Ok(())
+        }
+        ControlCommand::Unlock(instance_num) => {
+            instance_manager.unlock_to_wall(instance_num);
+            Ok(())
+        }
+        ControlCommand::Play(_) if !on_wall => return error_reply("Another instance is already playing"),
+        ControlCommand::Play(instance_num) if instance_state != Some(InstanceState::Idle) => {
+            return error_reply(&format!("Instance {instance_num} isn't done resetting"));
+        }
+        ControlCommand::Play(_) => instance_manager.play_instance(instance.flatten().unwrap()),
+        ControlCommand::Reset(instance_num) if instance_state == Some(InstanceState::Playing) => {
+            return error_reply(&format!("Instance {instance_num} is playing, exit it first"));
+        }
+        ControlCommand::Reset(instance_num) => {
+            instance_manager.unlock(instance_num);
+            instance_manager.preview_unlocked_wall_queue.remove_by_instance_num(instance_num);
+            instance_manager.reset_instance(instance.flatten().unwrap());
+            Ok(())
+        }
+        ControlCommand::ResetBag if !on_wall => return error_reply("Can't reset a bag while playing"),
+        ControlCommand::ResetBag => instance_manager.reset_bag(),
+        ControlCommand::ResetAll => {
+            instance_manager.reset_all_instances();
+            Ok(())
+        }
+        ControlCommand::Exit => instance_manager.exit_instance(),
+        ControlCommand::Status => return status(instance_manager),
+        ControlCommand::Layout => {
+            return json!({ "ok": true, "instances": instance_manager.wall_instances });
+        }
+        ControlCommand::Subscribe => return error_reply("subscribe is only available on the socket"),
+    };
+    match result {
+        Ok(_) => {
+            instance_manager.update_wall();
+            json!({ "ok": true })
+        }
+        Err(err) => error_reply(&err.to_string()),
+    }
+}
+
+fn status(instance_manager: &InstanceManager) -> Value {
+    let instances: Vec<Value> = instance_manager
+        .instances
+        .iter()
+        .map(|instance| {
+            json!({
+                "instance_num": instance.instance_info.instance_num,
+                "state": instance.state.load(SeqCst).to_string(),
+                "locked": instance.locked.load(SeqCst),
+                "preview_percent": instance.preview_percent.load(SeqCst),
+                "window_mode": instance.window_mode.load(SeqCst).to_string(),
+            })
+        })
+        .collect();
+    json!({
+        "ok": true,
+        "playing": instance_manager
+            .get_playing_instance()
+            .map(|instance| instance.instance_info.instance_num),
+        "locked": instance_manager
+            .locked_instances
+            .iter()
+            .map(|instance| instance.instance_info.instance_num)
+            .collect::<Vec<_>>(),
+        "queue_len": instance_manager.preview_unlocked_wall_queue.len(),
+        "reset_count": instance_manager.reset_counter.session,
+        "total_reset_count": instance_manager.reset_counter.total,
+        "instances": instances,
+    })
+}
@@ -0,0 +1,187 @@
+use std::{fs, os::unix::fs::MetadataExt, path::Path, process};
+
+use x11rb::{
+    connection::{Connection, RequestConnection},
+    protocol::{
+        composite, damage, randr, render,
+        xproto::{AtomEnum, ConnectionExt, Window},
+    },
+    rust_connection::RustConnection,
+};
+
+use crate::{
+    config::Config,
+    x11::{find_instance_windows, grab_key, ungrab_key},
+};
+
+/// Supported atoms the instance handling relies on
+const REQUIRED_EWMH_ATOMS: [&str; 3] = ["_NET_ACTIVE_WINDOW", "_NET_WM_STATE", "_NET_WM_STATE_FULLSCREEN"];
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn ok(&mut self, message: &str) {
+        println!("[ok]   {message}");
+    }
+
+    fn warn(&mut self, message: &str) {
+        self.warnings += 1;
+        println!("[warn] {message}");
+    }
+
+    fn fail(&mut self, message: &str) {
+        self.failures += 1;
+        println!("[fail] {message}");
+    }
+}
+
+/// Prints a check list and exits with 1 when anything rulti needs is missing.
+pub fn run(config: &Config, config_path: Option<&Path>) {
+    let mut report = Report::default();
+    match config_path {
+        Some(path) if path.exists() => report.ok(&format!("Config {} is valid", path.display())),
+        Some(path) => report.ok(&format!("No config at {}, using the defaults", path.display())),
+        None => report.warn("Neither XDG_CONFIG_HOME nor HOME is set, using the default config"),
+    }
+
+    let (conn, screen_num) = match x11rb::connect(None) {
+        Ok(connected) => connected,
+        Err(err) => {
+            report.fail(&format!("Failed to connect to the X server: {err}"));
+            finish(report);
+        }
+    };
+    report.ok("Connected to the X server");
+    let root = conn.setup().roots[screen_num].root;
+
+    check_extensions(&conn, config, &mut report);
+    check_window_manager(&conn, root, &mut report);
+    check_hotkeys(&conn, root, config, &mut report);
+    check_instances(&conn, root, config, &mut report);
+    finish(report);
+}
+
+fn finish(report: Report) -> ! {
+    println!("{} failures, {} warnings", report.failures, report.warnings);
+    process::exit(if report.failures > 0 { 1 } else { 0 });
+}
+
+fn check_extensions(conn: &RustConnection, config: &Config, report: &mut Report) {
+    let extensions = [
+        (randr::X11_EXTENSION_NAME, true),
+        (composite::X11_EXTENSION_NAME, config.projector),
+        (render::X11_EXTENSION_NAME, config.projector),
+        (damage::X11_EXTENSION_NAME, config.projector),
+    ];
+    for (name, required) in extensions {
+        match conn.extension_information(name) {
+            Ok(Some(_)) => report.ok(&format!("{name} extension is available")),
+            Ok(None) if required => report.fail(&format!("{name} extension is missing")),
+            Ok(None) => report.warn(&format!("{name} extension is missing, the projector won't work")),
+            Err(err) => report.fail(&format!("Failed to query the {name} extension: {err}")),
+        }
+    }
+}
+
+fn check_window_manager(conn: &RustConnection, root: Window, report: &mut Report) {
+    let supported = intern(conn, "_NET_SUPPORTED").and_then(|atom| {
+        let reply = conn
+            .get_property(false, root, atom, AtomEnum::ATOM, 0, 4096)
+            .ok()?
+            .reply()
+            .ok()?;
+        let atoms = reply.value32()?.collect::<Vec<_>>();
+        Some(atoms)
+    });
+    let Some(supported) = supported.filter(|supported| !supported.is_empty()) else {
+        report.fail("The window manager doesn't advertise EWMH support through _NET_SUPPORTED");
+        return;
+    };
+    for name in REQUIRED_EWMH_ATOMS {
+        match intern(conn, name) {
+            Some(atom) if supported.contains(&atom) => report.ok(&format!("Window manager supports {name}")),
+            _ => report.fail(&format!("Window manager doesn't support {name}")),
+        }
+    }
+}
+
+fn check_hotkeys(conn: &RustConnection, root: Window, config: &Config, report: &mut Report) {
+    for (hotkey, key) in config.hotkeys.all() {
+        // A grab fails with BadAccess when another client already holds the key
+        match grab_key(conn, key, root) {
+            Ok(_) => {
+                let _ = ungrab_key(conn, key, root);
+                report.ok(&format!("Ctrl+key {key} for {hotkey} is free"));
+            }
+            Err(err) => report.fail(&format!("Ctrl+key {key} for {hotkey} can't be grabbed: {err}")),
+        }
+    }
+}
+
+fn check_instances(conn: &RustConnection, root: Window, config: &Config, report: &mut Report) {
+    let windows = match find_instance_windows(
+        conn,
+        root,
+        &config.instances.window_name,
+        &config.instances.instance_num_regex(),
+    ) {
+        Ok(windows) => windows,
+        Err(err) => {
+            report.fail(&format!("Failed to look for instances: {err}"));
+            return;
+        }
+    };
+    if windows.is_empty() {
+        report.fail(&format!("No windows named {:?} found", config.instances.window_name));
+        return;
+    }
+    let mut instances = Vec::new();
+    for (window, instance) in windows {
+        match instance {
+            Ok(instance) => instances.push(instance),
+            Err(err) => report.fail(&format!(
+                "Window {window:#x} is named {:?} but isn't an instance: {err}",
+                config.instances.window_name
+            )),
+        }
+    }
+    if instances.is_empty() {
+        return;
+    }
+    report.ok(&format!("Found {} instances", instances.len()));
+
+    let our_uid = fs::metadata("/proc/self").map(|metadata| metadata.uid());
+    for instance in instances {
+        let num = instance.instance_num;
+        let state_file = Path::new(&instance.gamedir).join("wpstateout.txt");
+        if state_file.exists() {
+            report.ok(&format!("Instance {num} has {}", state_file.display()));
+        } else {
+            report.fail(&format!(
+                "Instance {num} has no {}, is WorldPreview installed?",
+                state_file.display()
+            ));
+        }
+
+        // Changing the affinity of another user's process needs CAP_SYS_NICE
+        let instance_uid = fs::metadata(format!("/proc/{}", instance.pid)).map(|metadata| metadata.uid());
+        match (&our_uid, instance_uid) {
+            (Ok(ours), Ok(theirs)) if *ours == 0 || *ours == theirs => {
+                report.ok(&format!("Instance {num} (pid {}) affinity can be changed", instance.pid))
+            }
+            (Ok(_), Ok(_)) => report.warn(&format!(
+                "Instance {num} (pid {}) belongs to another user, its affinity can't be changed",
+                instance.pid
+            )),
+            _ => report.warn(&format!("Instance {num} (pid {}) has no /proc entry", instance.pid)),
+        }
+    }
+}
+
+fn intern(conn: &RustConnection, name: &str) -> Option<u32> {
+    Some(conn.intern_atom(false, name.as_bytes()).ok()?.reply().ok()?.atom)
+}
@@ -0,0 +1,176 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    sync::broadcast::{
+        self,
+        error::{RecvError, TryRecvError},
+        Receiver,
+    },
+};
+
+/// How many events a slow subscriber can fall behind before it starts missing them
+const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    StateChanged { instance_num: u32, from: String, to: String },
+    PreviewPercent { instance_num: u32, percent: usize },
+    Locked { instance_num: u32 },
+    Unlocked { instance_num: u32 },
+    Reset { instance_num: u32 },
+    BagReset { instance_nums: Vec<u32> },
+    Played { instance_num: u32 },
+    Exited { instance_num: u32 },
+    /// The game process of the instance is gone
+    Crashed { instance_num: u32 },
+}
+
+/// An event with the time it was published in milliseconds since the Unix epoch
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimedEvent {
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl TimedEvent {
+    /// One line of newline-delimited JSON
+    pub fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(self).unwrap();
+        line.push('\n');
+        line
+    }
+}
+
+/// Where timestamps come from. Clones of a fake clock share its time.
+#[derive(Clone, Debug, Default)]
+pub enum Clock {
+    #[default]
+    System,
+    /// Only moves when it is set, so replayed sessions get the recorded timestamps
+    Fake(Arc<AtomicU64>),
+}
+
+impl Clock {
+    pub fn fake(now_ms: u64) -> Self {
+        Clock::Fake(Arc::new(AtomicU64::new(now_ms)))
+    }
+
+    /// Milliseconds since the Unix epoch
+    pub fn now_ms(&self) -> u64 {
+        match self {
+            Clock::System => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as u64)
+                .unwrap_or_default(),
+            Clock::Fake(now_ms) => now_ms.load(SeqCst),
+        }
+    }
+
+    /// Moves a fake clock to the given time. The system clock can't be set.
+    pub fn set_ms(&self, ms: u64) {
+        if let Clock::Fake(now_ms) = self {
+            now_ms.store(ms, SeqCst);
+        }
+    }
+}
+
+/// Publishes events to every subscriber. Cloning gives another handle to the same bus.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<TimedEvent>,
+    clock: Arc<Mutex<Clock>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            sender,
+            clock: Arc::new(Mutex::new(Clock::System)),
+        }
+    }
+
+    /// Timestamps the events of every handle of the bus with the clock from now on.
+    pub fn set_clock(&self, clock: Clock) {
+        *self.clock.lock().unwrap() = clock;
+    }
+
+    pub fn publish(&self, event: Event) {
+        let timestamp_ms = self.clock.lock().unwrap().now_ms();
+        // Nobody listening is fine, the event is simply dropped
+        let _ = self.sender.send(TimedEvent { timestamp_ms, event });
+    }
+
+    pub fn subscribe(&self) -> EventSubscription {
+        EventSubscription {
+            receiver: self.sender.subscribe(),
+        }
+    }
+}
+
+pub struct EventSubscription {
+    receiver: Receiver<TimedEvent>,
+}
+
+impl EventSubscription {
+    /// Waits for the next event, skipping over any the subscriber was too slow to receive.
+    /// Returns `None` once the bus is gone.
+    pub async fn recv(&mut self) -> Option<TimedEvent> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(missed)) => println!("Event subscriber missed {missed} events"),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// The next event if one was published already, skipping over any the subscriber was too slow to receive.
+    pub fn try_recv(&mut self) -> Option<TimedEvent> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(event) => return Some(event),
+                Err(TryRecvError::Lagged(missed)) => println!("Event subscriber missed {missed} events"),
+                Err(TryRecvError::Empty | TryRecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Appends every event to the file as newline-delimited JSON.
+pub fn spawn_log_writer(bus: &EventBus, path: PathBuf) {
+    let mut subscription = bus.subscribe();
+    tokio::spawn(async move {
+        let mut file = match OpenOptions::new().create(true).append(true).open(&path).await {
+            Ok(file) => file,
+            Err(err) => {
+                println!("Failed to open the event log {}: {err}", path.display());
+                return;
+            }
+        };
+        while let Some(event) = subscription.recv().await {
+            if let Err(err) = file.write_all(event.to_line().as_bytes()).await {
+                println!("Failed to write to the event log {}: {err}", path.display());
+                return;
+            }
+        }
+    });
+}
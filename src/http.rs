@@ -0,0 +1,112 @@
+use std::net::SocketAddr;
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::Sender, oneshot},
+};
+
+use crate::{
+    control::{ControlCommand, ControlRequest},
+    events::EventBus,
+};
+
+/// A page that can be added to OBS as a browser source
+const OVERLAY_PAGE: &str = include_str!("overlay.html");
+
+/// Serves `/state`, `/events` as server-sent events and the overlay page on `/`.
+/// The state comes from the main loop through the same requests as the control socket.
+pub fn spawn(address: SocketAddr, request_sender: Sender<ControlRequest>, events: EventBus) -> std::io::Result<()> {
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve_client(stream, request_sender.clone(), events.clone()));
+                }
+                Err(err) => println!("Failed to accept an HTTP connection: {err}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn serve_client(stream: TcpStream, request_sender: Sender<ControlRequest>, events: EventBus) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).await.is_err() {
+        return;
+    }
+    // The headers don't matter to us, but they have to be read before replying
+    let mut header = String::new();
+    loop {
+        header.clear();
+        match reader.read_line(&mut header).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) if header.trim().is_empty() => break,
+            Ok(_) => {}
+        }
+    }
+
+    let mut words = request_line.split_whitespace();
+    let (method, path) = (words.next().unwrap_or_default(), words.next().unwrap_or_default());
+    let response = match (method, path) {
+        ("GET", "/") => response("200 OK", "text/html; charset=utf-8", OVERLAY_PAGE),
+        ("GET", "/state") => {
+            let state = request_state(&request_sender).await;
+            response("200 OK", "application/json", &state.to_string())
+        }
+        ("GET", "/events") => {
+            stream_events(writer, events).await;
+            return;
+        }
+        ("GET", _) => response("404 Not Found", "text/plain", "Not found"),
+        _ => response("405 Method Not Allowed", "text/plain", "Only GET is supported"),
+    };
+    let _ = writer.write_all(response.as_bytes()).await;
+}
+
+async fn request_state(request_sender: &Sender<ControlRequest>) -> Value {
+    let (reply_sender, reply_receiver) = oneshot::channel();
+    let request = ControlRequest {
+        command: ControlCommand::Status,
+        reply_sender,
+    };
+    if request_sender.send(request).await.is_err() {
+        return json!({ "ok": false, "error": "rulti is shutting down" });
+    }
+    reply_receiver
+        .await
+        .unwrap_or_else(|_| json!({ "ok": false, "error": "rulti is shutting down" }))
+}
+
+async fn stream_events(mut writer: impl AsyncWriteExt + Unpin, events: EventBus) {
+    let mut subscription = events.subscribe();
+    let head = "HTTP/1.1 200 OK\r\n\
+        Content-Type: text/event-stream\r\n\
+        Cache-Control: no-cache\r\n\
+        Connection: keep-alive\r\n\r\n";
+    if writer.write_all(head.as_bytes()).await.is_err() {
+        return;
+    }
+    while let Some(event) = subscription.recv().await {
+        let message = format!("data: {}\n\n", serde_json::to_string(&event).unwrap());
+        if writer.write_all(message.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\n\
+        Content-Type: {content_type}\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
@@ -1,26 +1,45 @@
 use std::{
     sync::{atomic::{AtomicBool, Ordering::SeqCst, AtomicU32, AtomicUsize}, Arc, Mutex},
     thread,
-    time::{self, Duration, SystemTime}, fs::File, io::Read,
+    time::{self, Duration, SystemTime}, fs::{self, File}, io::{self, Read}, path::Path,
 };
 
 use crate::{
-    x11::InstanceInfo,
+    backend::WindowBackend,
+    config::{Config, FullscreenMode, TimingConfig},
+    events::{Event, EventBus},
+    recording::{Input, Recorder},
+    x11::{InstanceInfo, Rect, WindowError},
 };
 use atomic_enum::atomic_enum;
 use tokio::sync::mpsc::{error::TryRecvError, Receiver, Sender};
 
+const KEY_ESCAPE: u8 = 9;
+const KEY_F3: u8 = 69;
+const KEY_F6: u8 = 72;
+
 pub struct Instance {
     pub instance_info: InstanceInfo,
+    backend: Arc<dyn WindowBackend>,
+    fullscreen_mode: FullscreenMode,
+    timing: TimingConfig,
+    /// Preview percentage after which the instance counts as loaded
+    pub freeze_percent: usize,
+    events: EventBus,
+    windowed_geometry: Mutex<Option<Rect>>,
+    decorations_removed: AtomicBool,
+    wall_geometry: Mutex<Option<(Rect, bool)>>,
+    original_motif_hints: Mutex<Option<[u32; 5]>>,
     pub state: AtomicInstanceState,
     pub locked: AtomicBool,
-    pub thin: AtomicBool,
+    pub window_mode: AtomicWindowMode,
     pub thread_count:AtomicU32,
     pub affinity_mask : AtomicUsize,
     pub last_world_preview_modification : Arc<Mutex<SystemTime>>,
     pub last_world_preview_state : Arc<Mutex<String>>,
     pub preview_percent: AtomicUsize,
     pub has_sent_percent: AtomicBool,
+    recorder: Option<Recorder>,
 }
 #[derive(strum_macros::Display)]
 #[atomic_enum]
@@ -32,93 +51,256 @@ pub enum InstanceState {
     Preview,
     Playing,
 }
-const READY: &str = "Minecraft* 1.16.1 - Singleplayer";
+/// What a world preview state did to a resetting instance
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetStep {
+    /// Nothing changed
+    Waiting,
+    /// The new world started generating
+    Generating,
+    /// The preview of the new world is shown
+    PreviewReady,
+    /// The preview loaded past the freeze percentage
+    Frozen(usize),
+    /// The instance is paused in its new world, or wasn't resetting at all
+    Done,
+}
+/// Window geometry presets for the playing instance, each relative to the monitor the instance is on
+#[derive(strum_macros::Display)]
+#[atomic_enum]
+#[derive(PartialEq)]
+pub enum WindowMode {
+    Normal,
+    Thin,
+    Tall,
+    Wide,
+}
+
+impl WindowMode {
+    pub fn next(self) -> WindowMode {
+        match self {
+            WindowMode::Normal => WindowMode::Thin,
+            WindowMode::Thin => WindowMode::Tall,
+            WindowMode::Tall => WindowMode::Wide,
+            WindowMode::Wide => WindowMode::Normal,
+        }
+    }
+
+    pub fn rect(self, monitor: Rect) -> Rect {
+        let (width, height) = match self {
+            WindowMode::Normal => return monitor,
+            // 400x1080 on a 1080p monitor
+            WindowMode::Thin => (monitor.width as u32 * 5 / 24, monitor.height as u32),
+            // 384x8640 on a 1080p monitor, for eye measuring
+            WindowMode::Tall => (monitor.width as u32 / 5, monitor.height as u32 * 8),
+            // 1920x270 on a 1080p monitor, for planar
+            WindowMode::Wide => (monitor.width as u32, monitor.height as u32 / 4),
+        };
+        let width = width.min(i16::MAX as u32) as i32;
+        let height = height.min(i16::MAX as u32) as i32;
+        Rect {
+            x: (monitor.x as i32 + (monitor.width as i32 - width) / 2) as i16,
+            y: (monitor.y as i32 + (monitor.height as i32 - height) / 2) as i16,
+            width: width as u16,
+            height: height as u16,
+        }
+    }
+}
+
 impl Instance {
-    pub fn new(instance_info:InstanceInfo) -> Self {
+    pub fn new(
+        instance_info: InstanceInfo,
+        backend: Arc<dyn WindowBackend>,
+        config: &Config,
+        events: EventBus,
+        recorder: Option<Recorder>,
+    ) -> Self {
         Self {
-            instance_info: instance_info,
+            instance_info,
+            backend,
+            fullscreen_mode: config.fullscreen_mode,
+            timing: config.timing,
+            freeze_percent: config.instances.freeze_percent,
+            events,
+            windowed_geometry: Mutex::new(None),
+            decorations_removed: AtomicBool::new(false),
+            wall_geometry: Mutex::new(None),
+            original_motif_hints: Mutex::new(None),
             state: AtomicInstanceState::new(InstanceState::Idle),
             locked: AtomicBool::new(false),
-            thin: AtomicBool::new(false),
+            window_mode: AtomicWindowMode::new(WindowMode::Normal),
             thread_count:AtomicU32::new(0),
             affinity_mask:AtomicUsize::new(0),
             last_world_preview_modification : Arc::new(Mutex::new(SystemTime::now())),
             last_world_preview_state : Arc::new(Mutex::new(String::new())),
             preview_percent:AtomicUsize::new(0),
             has_sent_percent:AtomicBool::new(false),
+            recorder,
+        }
+    }
+    /// Stores the new state and publishes the transition if it is one.
+    pub fn set_state(&self, state: InstanceState) {
+        let from = self.state.swap(state, SeqCst);
+        if from != state {
+            self.events.publish(Event::StateChanged {
+                instance_num: self.instance_info.instance_num,
+                from: from.to_string(),
+                to: state.to_string(),
+            });
         }
     }
+
+    /// Whether the game process still exists
+    pub fn is_alive(&self) -> bool {
+        Path::new(&format!("/proc/{}", self.instance_info.pid)).exists()
+    }
+
     fn send_f3_esc(&self) {
-        todo!("Implement f3 esc");
-        
-        // send_keydown(self.hwnd, VK_F3);
-        // send_keydown(self.hwnd, VK_ESCAPE);
-        // send_keyup(self.hwnd, VK_ESCAPE);
-        // send_keyup(self.hwnd, VK_F3);
+        let window = self.instance_info.window;
+        let keys = [(KEY_F3, true), (KEY_ESCAPE, true), (KEY_ESCAPE, false), (KEY_F3, false)];
+        if let Err(err) = self.backend.send_keys(window, &keys) {
+            println!("Failed to send f3 esc: {err}");
+        }
     }
 
-    pub fn thin(&self) {
-        self.thin.store(!self.thin.load(SeqCst), SeqCst);
+    /// Switches to the given window mode, or back to normal if it is already active.
+    pub fn toggle_window_mode(&self, mode: WindowMode) -> Result<(), WindowError> {
+        if self.window_mode.load(SeqCst) == mode {
+            self.set_window_mode(WindowMode::Normal)
+        } else {
+            self.set_window_mode(mode)
+        }
+    }
 
-        match self.thin.load(SeqCst) {
-            true => {
-                todo!("Implement thinning");
-                // unsafe { MoveWindow(self.hwnd, 1920 / 2 - (300 / 2), 0, 400, 1080, true) };
-            }
-            false => {
-                todo!("Implement thinning");
-                // unsafe { MoveWindow(self.hwnd, 0, 0, 1920, 1080, true) };
+    pub fn cycle_window_mode(&self) -> Result<(), WindowError> {
+        self.set_window_mode(self.window_mode.load(SeqCst).next())
+    }
+
+    pub fn set_window_mode(&self, mode: WindowMode) -> Result<(), WindowError> {
+        if self.window_mode.load(SeqCst) == mode {
+            return Ok(());
+        }
+        let window = self.instance_info.window;
+        let monitor = self.backend.monitor_geometry(window)?;
+        match mode {
+            WindowMode::Normal => self.enter_fullscreen()?,
+            _ => {
+                if self.fullscreen_mode == FullscreenMode::Fullscreen {
+                    // The window manager ignores ConfigureWindow requests on fullscreen windows
+                    self.backend.set_fullscreen(window, false, self.timing.fullscreen_timeout())?;
+                }
+                self.backend.set_geometry(window, mode.rect(monitor), self.timing.fullscreen_timeout())?;
             }
         }
+        self.window_mode.store(mode, SeqCst);
+        Ok(())
     }
 
-    pub fn getWorldPreviewState(&self)->String{
-        todo!("Implement getWorldPreviewState");
+    fn enter_fullscreen(&self) -> Result<(), WindowError> {
+        let window = self.instance_info.window;
+        match self.fullscreen_mode {
+            FullscreenMode::Fullscreen => self.backend.set_fullscreen(window, true, self.timing.fullscreen_timeout()),
+            FullscreenMode::Borderless => {
+                let monitor = self.backend.monitor_geometry(window)?;
+                self.backend.set_geometry(window, monitor, self.timing.fullscreen_timeout())
+            }
+        }
+    }
 
-        let i = self.instance_info.instance_num;
-        let file_path = format!("C:\\Users\\Boyen\\Downloads\\MultiMC\\instances\\RSG {i}\\.minecraft\\wpstateout.txt");
-        let mut file = File::open(&file_path).unwrap();
-        let time = file.metadata().unwrap().modified().unwrap();
-        if time > self.last_world_preview_modification.lock().unwrap().clone(){
+    /// What the game last wrote to `wpstateout.txt`, read again only when the file changed
+    pub fn get_world_preview_state(&self) -> io::Result<String> {
+        let file_path = format!("{}/wpstateout.txt", self.instance_info.gamedir);
+        let mut file = File::open(&file_path)?;
+        let time = file.metadata()?.modified()?;
+        if time > *self.last_world_preview_modification.lock().unwrap(){
             let mut contents = String::new();
             let mut stored_time = self.last_world_preview_modification.lock().unwrap();
             *stored_time = time;
-            match file.read_to_string(&mut contents){
-                Ok(_) => {}
-                Err(err) => {
-                    println!("Failed to read world preview state {file_path}");
-                    println!("Error: {err}");
-                    return String::new();
-                }
-            }
+            file.read_to_string(&mut contents)?;
 
             let mut stored_state = self.last_world_preview_state.lock().unwrap();
+            if *stored_state != contents {
+                if let Some(recorder) = &self.recorder {
+                    recorder.record(Input::WorldPreviewState {
+                        instance_num: self.instance_info.instance_num,
+                        state: contents.clone(),
+                    });
+                }
+            }
             *stored_state = contents.clone();
             // println!("World preview state: {contents} (from file)");
-            return contents;
+            return Ok(contents);
         }
-        return self.last_world_preview_state.lock().unwrap().clone();
+        Ok(self.last_world_preview_state.lock().unwrap().clone())
     }
-    pub async fn reset(
-        &self,
-        mut cancel_receiver: Receiver<()>,
-        on_preview_ready_sender: Sender<u32>,
-        on_preview_percent_sender: Sender<u32>,
-    ) {
+    /// Sends the reset key unless the instance is already resetting. Returns whether a new reset was started.
+    pub fn start_reset(&self) -> bool {
+        self.has_sent_percent.store(false,SeqCst);
         if self.state.load(SeqCst) == InstanceState::Resetting
             || self.state.load(SeqCst) == InstanceState::LoadingScreen
         {
             println!("Trigger reset during reset, taking over");
-        } else {
-            // Start resetting
-            todo!("Implement reset");
-            // send_keypress(self.hwnd, VK_F6);
-            self.state.store(InstanceState::Resetting, SeqCst);
+            return false;
         }
-        self.has_sent_percent.store(false,SeqCst);
+        // Start resetting
+        if let Err(err) = self.backend.send_keypress(self.instance_info.window, KEY_F6) {
+            println!("Failed to send reset key: {err}");
+        }
+        self.set_state(InstanceState::Resetting);
+        true
+    }
 
+    /// Advances the reset according to what the game last wrote to `wpstateout.txt`.
+    pub fn on_world_preview_state(&self, state: &str) -> ResetStep {
+        match self.state.load(SeqCst) {
+            InstanceState::Resetting if state.contains("generating") => {
+                self.set_state(InstanceState::LoadingScreen);
+                ResetStep::Generating
+            }
+            InstanceState::LoadingScreen if state.contains("previewing") => {
+                self.set_state(InstanceState::Preview);
+                // Hide the menu
+                self.send_f3_esc();
+                ResetStep::PreviewReady
+            }
+            InstanceState::Preview if state.contains("previewing") && !self.has_sent_percent.load(SeqCst) => {
+                let percent = state.split(",").collect::<Vec<&str>>()[1]
+                    .parse::<usize>()
+                    .unwrap();
+                // println!("Preview percent: {percent}%");
+                if self.preview_percent.swap(percent, SeqCst) != percent {
+                    self.events.publish(Event::PreviewPercent {
+                        instance_num: self.instance_info.instance_num,
+                        percent,
+                    });
+                }
+                if percent > self.freeze_percent {
+                    self.has_sent_percent.store(true, SeqCst);
+                    return ResetStep::Frozen(percent);
+                }
+                ResetStep::Waiting
+            }
+            InstanceState::Preview if state.contains("inworld") => {
+                self.set_state(InstanceState::Idle);
+                // Pause the game
+                self.send_f3_esc();
+                ResetStep::Done
+            }
+            InstanceState::Resetting | InstanceState::LoadingScreen | InstanceState::Preview => ResetStep::Waiting,
+            _ => ResetStep::Done,
+        }
+    }
+
+    /// Polls the world preview state until the reset started by `start_reset` is done or cancelled.
+    pub async fn reset(
+        &self,
+        mut cancel_receiver: Receiver<()>,
+        on_preview_ready_sender: Sender<u32>,
+        on_preview_percent_sender: Sender<u32>,
+    ) {
+        let mut reported_read_error = false;
         loop {
-            thread::sleep(Duration::from_millis(50));
+            tokio::time::sleep(self.timing.state_poll_interval()).await;
 
             match cancel_receiver.try_recv() {
                 Ok(_) | Err(TryRecvError::Disconnected) => {
@@ -128,124 +310,114 @@ impl Instance {
                 _ => {}
             }
             match self.state.load(SeqCst) {
-                InstanceState::Resetting => {
-
-
-                    
-                    if self.getWorldPreviewState().contains("generating") {
-                        self.state.store(InstanceState::LoadingScreen, SeqCst);
-                        continue;
-                    }
-                }
-                InstanceState::LoadingScreen => {
-                    if self.getWorldPreviewState().contains("previewing")  {
-                        self.state.store(InstanceState::Preview, SeqCst);
-                        // Hide the menu
-                        self.send_f3_esc();
-                        match on_preview_ready_sender.send(self.instance_info.instance_num).await {
-                            Ok(_) => {}
-                            Err(_) => {
-                                panic!("Failed to send preview ready signal");
-                            }
-                        }
-                        continue;
+                InstanceState::Resetting | InstanceState::LoadingScreen | InstanceState::Preview => {}
+                _ => break,
+            }
+            let state = match self.get_world_preview_state() {
+                Ok(state) => state,
+                Err(err) => {
+                    // The game may not have written the file yet, so the reset keeps waiting for it
+                    if !reported_read_error {
+                        println!(
+                            "Failed to read the world preview state of instance {}: {err}",
+                            self.instance_info.instance_num
+                        );
+                        reported_read_error = true;
                     }
+                    continue;
                 }
-                InstanceState::Preview => {
-                    let state = self.getWorldPreviewState();
-                    if state.contains("previewing") && !self.has_sent_percent.load(SeqCst) {
-                        let percent = state.split(",").collect::<Vec<&str>>()[1]
-                            .parse::<usize>()
-                            .unwrap();
-                        // println!("Preview percent: {percent}%");
-                        self.preview_percent.store(percent, SeqCst);
-                        if percent>80 {
-                            on_preview_percent_sender.send(percent as u32).await.unwrap();
-                            self.has_sent_percent.store(true, SeqCst);
-
+            };
+            match self.on_world_preview_state(&state) {
+                ResetStep::PreviewReady => {
+                    match on_preview_ready_sender.send(self.instance_info.instance_num).await {
+                        Ok(_) => {}
+                        Err(_) => {
+                            panic!("Failed to send preview ready signal");
                         }
-                        continue;
-                    }
-                    if self.getWorldPreviewState().contains("inworld") {
-                        self.state.store(InstanceState::Idle, SeqCst);
-                        // Pause the game
-                        self.send_f3_esc();
-                        break;
                     }
                 }
-                _ => break,
+                ResetStep::Frozen(percent) => on_preview_percent_sender.send(percent as u32).await.unwrap(),
+                ResetStep::Done => break,
+                ResetStep::Waiting | ResetStep::Generating => {}
             }
         }
     }
 
-    pub fn play(&self) {
-        match self.state.load(SeqCst) {
-            InstanceState::Idle => {
-                println!("Playing");
-                // print current time in miliseconds
-                println!(
-                    "Current time: {}",
-                    time::SystemTime::now()
-                        .duration_since(time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis()
-                );
-
-                todo!("Implement play");
-
-                // let foreground_window = unsafe { GetForegroundWindow() };
-                // let window_thread_process_id =
-                //     unsafe { GetWindowThreadProcessId(foreground_window, zeroed()) };
-                // let current_thread = unsafe { GetCurrentThreadId() };
-                // if window_thread_process_id != current_thread {
-                //     unsafe { AttachThreadInput(window_thread_process_id, current_thread, true) };
-                // }
-
-                // // unsafe { ShowWindow(self.hwnd, SW_SHOW) };
-                // unsafe { SetForegroundWindow(self.hwnd) };
-                // unsafe { BringWindowToTop(self.hwnd) };
-                // unsafe { AttachThreadInput(window_thread_process_id, current_thread, false) };
-                // while (unsafe { GetForegroundWindow() } != self.hwnd) {
-                //     thread::sleep(time::Duration::from_millis(2));
-                // }
-                // // print current time in miliseconds
-                // println!(
-                //     "Current time: {}",
-                //     time::SystemTime::now()
-                //         .duration_since(time::UNIX_EPOCH)
-                //         .unwrap()
-                //         .as_millis()
-                // );
-                // println!("Making fullscreen");
-
-                // send_keydown(self.hwnd, VK_F11);
-                // send_keyup(self.hwnd, VK_F11);
-
-                // send_keydown(self.hwnd, VK_ESCAPE);
-                // send_keyup(self.hwnd, VK_ESCAPE);
-                // thread::sleep(time::Duration::from_millis(2));
-                // send_keydown(self.hwnd, VK_ESCAPE);
-                // send_keyup(self.hwnd, VK_ESCAPE);
-                // thread::sleep(time::Duration::from_millis(2));
-                // send_keydown(self.hwnd, VK_ESCAPE);
-                // send_keyup(self.hwnd, VK_ESCAPE);
-                // println!("Setting state to playing");
-                // self.state.store(InstanceState::Playing, SeqCst);
-                // println!("Done with playing");
+    pub fn play(&self) -> Result<(), WindowError> {
+        if self.state.load(SeqCst) == InstanceState::Idle {
+            println!("Playing");
+            // print current time in miliseconds
+            println!(
+                "Current time: {}",
+                time::SystemTime::now()
+                    .duration_since(time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis()
+            );
+
+            let window = self.instance_info.window;
+            self.backend.activate(window)?;
+            self.backend.wait_for_active(window, self.timing.focus_timeout())?;
+            println!("Making fullscreen");
+            *self.windowed_geometry.lock().unwrap() = Some(self.backend.geometry(window)?);
+            self.enter_fullscreen()?;
+
+            for _ in 0..3 {
+                self.backend.send_keypress(window, KEY_ESCAPE)?;
+                thread::sleep(Duration::from_millis(2));
             }
-            _ => {}
+            println!("Setting state to playing");
+            self.set_state(InstanceState::Playing);
         }
+        Ok(())
     }
 
-    pub fn exit(&self) {
+    pub fn exit(&self) -> Result<(), WindowError> {
         println!("Exiting");
-        todo!("Implement Exit");
 
-        if self.thin.load(SeqCst) {
-            self.thin();
+        let window = self.instance_info.window;
+        *self.wall_geometry.lock().unwrap() = None;
+        let window_mode = self.window_mode.swap(WindowMode::Normal, SeqCst);
+        let windowed_geometry = self.windowed_geometry.lock().unwrap().take();
+        if self.fullscreen_mode == FullscreenMode::Fullscreen && window_mode == WindowMode::Normal {
+            // The window manager restores the geometry from before fullscreen by itself
+            return self.backend.set_fullscreen(window, false, self.timing.fullscreen_timeout());
+        }
+        match windowed_geometry {
+            Some(rect) => self.backend.set_geometry(window, rect, self.timing.fullscreen_timeout()),
+            None => Ok(()),
+        }
+    }
+
+    /// Moves the window to its place on the moving wall, skipping the request if it is already there.
+    pub fn move_to(&self, rect: Rect, resize: bool) -> Result<(), WindowError> {
+        let mut wall_geometry = self.wall_geometry.lock().unwrap();
+        if *wall_geometry == Some((rect, resize)) {
+            return Ok(());
+        }
+        self.backend.move_window(self.instance_info.window, rect, resize)?;
+        *wall_geometry = Some((rect, resize));
+        Ok(())
+    }
+
+    pub fn set_borderless(&self) -> Result<(), WindowError> {
+        if self.decorations_removed.load(SeqCst) {
+            return Ok(());
         }
-        // send_keydown(self.hwnd, VK_F11);
-        // send_keyup(self.hwnd, VK_F11);
+        let original = self.backend.remove_decorations(self.instance_info.window)?;
+        *self.original_motif_hints.lock().unwrap() = original;
+        self.decorations_removed.store(true, SeqCst);
+        Ok(())
+    }
+
+    pub fn restore_decorations(&self) -> Result<(), WindowError> {
+        if !self.decorations_removed.load(SeqCst) {
+            return Ok(());
+        }
+        let original = self.original_motif_hints.lock().unwrap().take();
+        self.backend.restore_decorations(self.instance_info.window, original)?;
+        self.decorations_removed.store(false, SeqCst);
+        Ok(())
     }
 
     pub fn lock(&self) {
@@ -257,32 +429,239 @@ impl Instance {
     }
 
     pub fn set_threadcount(&self, thread_count: u32){
-        if self.thread_count.load(SeqCst) ==  thread_count{
-            return;
-        }
         self.thread_count.store(thread_count, SeqCst);
 
-        let affinity = (1 << thread_count) - 1;
-        let mask = if thread_count == 2 { affinity << (self.instance_info.instance_num * 2) } else { affinity };
+        let affinity = 1usize.checked_shl(thread_count).map_or(usize::MAX, |bit| bit - 1);
+        let mask = if thread_count == 2 {
+            affinity.checked_shl(self.instance_info.instance_num * 2).unwrap_or(0)
+        } else {
+            affinity
+        };
         self.set_affinity(mask);
     }
 
+    /// Pins every thread of the game to the CPUs in the mask. CPUs the machine doesn't have are ignored,
+    /// and a mask without any of its CPUs allows all of them.
     pub fn set_affinity(&self, affinity_mask : usize){
-        // if self.affinity_mask.load(SeqCst) ==  affinity_mask{
-        //     return;
-        // }
-        // self.affinity_mask.store(affinity_mask, SeqCst);
-
-        // let process_handle = unsafe { OpenProcess(PROCESS_ACCESS_RIGHTS(0x0200), false, self.process_id) };
-        
-        // match process_handle {
-        //     Ok(handle) => {
-        //         unsafe { SetProcessAffinityMask(handle, affinity_mask) };
-        //         unsafe { CloseHandle(handle) };
-        //     }
-        //     Err(e) => {
-        //         println!("Error: {}", e);
-        //     }
-        // }
+        let online = online_cpu_mask();
+        let affinity_mask = match affinity_mask & online {
+            0 => online,
+            mask => mask,
+        };
+        // Replayed instances have no process of their own
+        if self.affinity_mask.swap(affinity_mask, SeqCst) == affinity_mask || self.instance_info.pid == 0 {
+            return;
+        }
+
+        let mut cpu_set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+        for cpu in 0..usize::BITS as usize {
+            if affinity_mask & (1 << cpu) != 0 {
+                unsafe { libc::CPU_SET(cpu, &mut cpu_set) };
+            }
+        }
+        // The JVM runs the game on many threads, and each of them has its own affinity
+        let tasks = match fs::read_dir(format!("/proc/{}/task", self.instance_info.pid)) {
+            Ok(tasks) => tasks,
+            Err(err) => {
+                println!("Failed to list the threads of instance {}: {err}", self.instance_info.instance_num);
+                return;
+            }
+        };
+        for task in tasks.flatten() {
+            let Ok(tid) = task.file_name().to_string_lossy().parse::<libc::pid_t>() else {
+                continue;
+            };
+            let result = unsafe { libc::sched_setaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &cpu_set) };
+            if result != 0 {
+                let err = io::Error::last_os_error();
+                // Threads exit all the time, only the ones that are still there matter
+                if err.raw_os_error() != Some(libc::ESRCH) {
+                    println!("Failed to set the affinity of instance {}: {err}", self.instance_info.instance_num);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Mask of the CPUs rulti may use, which instances can use as well
+fn online_cpu_mask() -> usize {
+    let cpus = thread::available_parallelism().map_or(1, |cpus| cpus.get()) as u32;
+    1usize.checked_shl(cpus).map_or(usize::MAX, |bit| bit - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+    use crate::backend::FakeBackend;
+
+    const WINDOW: u32 = 101;
+    const F3_ESC: [(u8, bool); 4] = [(KEY_F3, true), (KEY_ESCAPE, true), (KEY_ESCAPE, false), (KEY_F3, false)];
+
+    fn instance() -> (Arc<FakeBackend>, Instance) {
+        instance_in("/tmp/RSG 1/", &Config::default())
+    }
+
+    fn instance_in(gamedir: &str, config: &Config) -> (Arc<FakeBackend>, Instance) {
+        let monitor = Rect {
+            x: 0,
+            y: 0,
+            width: 1920,
+            height: 1080,
+        };
+        let info = InstanceInfo {
+            window: WINDOW,
+            pid: 0,
+            gamedir: gamedir.into(),
+            instance_num: 1,
+        };
+        let backend = Arc::new(FakeBackend::new(monitor));
+        backend.add_window(info.clone(), "Minecraft* 1.16.1", monitor);
+        let instance = Instance::new(info, backend.clone(), config, EventBus::new(), None);
+        (backend, instance)
+    }
+
+    fn keys(backend: &FakeBackend) -> Vec<(u8, bool)> {
+        std::mem::take(&mut backend.state().windows.get_mut(&WINDOW).unwrap().keys)
+    }
+
+    #[test]
+    fn reset_goes_through_every_state() {
+        let (backend, instance) = instance();
+        let mut events = instance.events.subscribe();
+        assert_eq!(instance.on_world_preview_state("inworld,paused"), ResetStep::Done);
+        assert_eq!(instance.state.load(SeqCst), InstanceState::Idle);
+
+        assert!(instance.start_reset());
+        assert_eq!(keys(&backend), vec![(KEY_F6, true), (KEY_F6, false)]);
+        // The old world is still written until the game starts generating
+        assert_eq!(instance.on_world_preview_state("inworld,paused"), ResetStep::Waiting);
+        assert_eq!(instance.on_world_preview_state("previewing,100"), ResetStep::Waiting);
+        assert_eq!(instance.state.load(SeqCst), InstanceState::Resetting);
+
+        assert_eq!(instance.on_world_preview_state("generating"), ResetStep::Generating);
+        assert_eq!(instance.on_world_preview_state("generating"), ResetStep::Waiting);
+        assert_eq!(instance.state.load(SeqCst), InstanceState::LoadingScreen);
+
+        assert_eq!(instance.on_world_preview_state("previewing,0"), ResetStep::PreviewReady);
+        assert_eq!(instance.state.load(SeqCst), InstanceState::Preview);
+        // The menu is hidden as soon as the preview shows
+        assert_eq!(keys(&backend), F3_ESC);
+
+        assert_eq!(instance.on_world_preview_state("previewing,50"), ResetStep::Waiting);
+        assert_eq!(instance.preview_percent.load(SeqCst), 50);
+        assert_eq!(instance.on_world_preview_state("previewing,81"), ResetStep::Frozen(81));
+        // Frozen is only reported once per reset
+        assert_eq!(instance.on_world_preview_state("previewing,95"), ResetStep::Waiting);
+        assert_eq!(instance.preview_percent.load(SeqCst), 81);
+
+        assert_eq!(instance.on_world_preview_state("inworld,unpaused"), ResetStep::Done);
+        assert_eq!(instance.state.load(SeqCst), InstanceState::Idle);
+        assert_eq!(keys(&backend), F3_ESC);
+        assert_eq!(instance.on_world_preview_state("inworld,paused"), ResetStep::Done);
+        assert!(keys(&backend).is_empty());
+
+        let mut transitions = Vec::new();
+        while let Some(event) = events.try_recv() {
+            match event.event {
+                Event::StateChanged { from, to, .. } => transitions.push(format!("{from} -> {to}")),
+                Event::PreviewPercent { percent, .. } => transitions.push(format!("{percent}%")),
+                event => panic!("unexpected event {event:?}"),
+            }
+        }
+        assert_eq!(
+            transitions,
+            vec![
+                "Idle -> Resetting",
+                "Resetting -> LoadingScreen",
+                "LoadingScreen -> Preview",
+                "50%",
+                "81%",
+                "Preview -> Idle",
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_during_reset_takes_over() {
+        let (backend, instance) = instance();
+        assert!(instance.start_reset());
+        assert_eq!(instance.on_world_preview_state("generating"), ResetStep::Generating);
+        keys(&backend);
+        assert!(!instance.start_reset());
+        assert!(keys(&backend).is_empty());
+        assert_eq!(instance.state.load(SeqCst), InstanceState::LoadingScreen);
+
+        // A reset from the preview starts over and reports the freeze again
+        assert_eq!(instance.on_world_preview_state("previewing,90"), ResetStep::PreviewReady);
+        assert_eq!(instance.on_world_preview_state("previewing,90"), ResetStep::Frozen(90));
+        keys(&backend);
+        assert!(instance.start_reset());
+        assert_eq!(keys(&backend), vec![(KEY_F6, true), (KEY_F6, false)]);
+        assert_eq!(instance.on_world_preview_state("generating"), ResetStep::Generating);
+        assert_eq!(instance.on_world_preview_state("previewing,90"), ResetStep::PreviewReady);
+        assert_eq!(instance.on_world_preview_state("previewing,90"), ResetStep::Frozen(90));
+    }
+
+    #[test]
+    fn play_and_exit_restore_the_window() {
+        let (backend, instance) = instance();
+        let windowed = Rect {
+            x: 100,
+            y: 200,
+            width: 854,
+            height: 480,
+        };
+        backend.move_window(WINDOW, windowed, true).unwrap();
+        instance.play().unwrap();
+        assert_eq!(instance.state.load(SeqCst), InstanceState::Playing);
+        assert_eq!(backend.state().active, Some(WINDOW));
+        let state = backend.state();
+        assert_eq!(state.windows[&WINDOW].rect, state.monitor);
+        drop(state);
+        assert_eq!(keys(&backend), [(KEY_ESCAPE, true), (KEY_ESCAPE, false)].repeat(3));
+
+        instance.exit().unwrap();
+        assert_eq!(backend.state().windows[&WINDOW].rect, windowed);
+    }
+
+    /// Writes the state file as the game would, `seconds` into the future so every write is newer than the last
+    fn write_state(dir: &Path, state: &str, seconds: u64) {
+        let path = dir.join("wpstateout.txt");
+        fs::write(&path, state).unwrap();
+        let modified = SystemTime::now() + Duration::from_secs(seconds);
+        File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reset_waits_for_the_state_file_to_show_up() {
+        let dir = TempDir::new().unwrap();
+        let mut config = Config::default();
+        config.timing.state_poll_ms = 1;
+        let (_backend, instance) = instance_in(dir.path().to_str().unwrap(), &config);
+        let instance = Arc::new(instance);
+        let (_cancel_sender, cancel_receiver) = channel(1);
+        let (preview_sender, mut preview_receiver) = channel(1);
+        let (percent_sender, _percent_receiver) = channel(1);
+        assert!(instance.start_reset());
+        let reset = tokio::spawn({
+            let instance = instance.clone();
+            async move { instance.reset(cancel_receiver, preview_sender, percent_sender).await }
+        });
+
+        // A fresh instance has no state file until the game writes one
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!reset.is_finished());
+        assert_eq!(instance.state.load(SeqCst), InstanceState::Resetting);
+
+        write_state(dir.path(), "generating", 1);
+        write_state(dir.path(), "previewing,0", 2);
+        assert_eq!(preview_receiver.recv().await, Some(1));
+        write_state(dir.path(), "inworld,paused", 3);
+        reset.await.unwrap();
+        assert_eq!(instance.state.load(SeqCst), InstanceState::Idle);
     }
 }
@@ -1,17 +1,18 @@
 #![allow(dead_code)]
 use std::{
     collections::HashMap,
-    fs::File,
-    io::Write,
+    env,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
     sync::{atomic::Ordering::SeqCst, Arc},
 };
 
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::{channel, Sender};
-use x11rb::connection::Connection;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::{x11::{InstanceInfo, set_window_title}, instance::{Instance, InstanceState}};
+use crate::{backend::WindowBackend, config::{AffinityPolicy, Config}, resetcounter::ResetCounter, events::{Event, EventBus}, obs::{self, ObsCommand, ObsHandle}, layout::{build_layout, Layout, LayoutConfig}, movingwall, projector::WallProjector, recording::Recorder, x11::{InstanceInfo, Rect, WindowError}, instance::{Instance, InstanceState, WindowMode}};
 
 const GAME_TITLE: &str = "Minecraft*";
 
@@ -23,18 +24,50 @@ pub struct InstanceManager {
     instance_becomes_preview_sender: Sender<u32>,
     instance_preview_percent_sender: Sender<u32>,
     affinity_map: HashMap<u32, u32>,
+    affinity: AffinityPolicy,
+    pub wall_instances: Vec<WallFileInstance>,
+    moving_wall: bool,
+    pub projector: Option<WallProjector>,
+    layout: Box<dyn Layout + Send>,
+    screen: Rect,
+    wall_file: WallFileWriter,
+    obs: Option<ObsHandle>,
+    pub events: EventBus,
+    /// Instances whose crash has already been reported
+    crashed: Vec<u32>,
+    pub reset_counter: ResetCounter,
+    pub backend: Arc<dyn WindowBackend>,
+    effects: Box<dyn SessionEffects>,
 }
 
 impl InstanceManager {
-    fn new(preview_becomes_ready_sender: Sender<u32>,instance_preview_percent_sender:Sender<u32>) -> Self {
+    fn new(preview_becomes_ready_sender: Sender<u32>,instance_preview_percent_sender:Sender<u32>, backend: Arc<dyn WindowBackend>, effects: Box<dyn SessionEffects>) -> Self {
         Self {
             instances: Vec::new(),
             reset_cancel_channels: HashMap::new(),
             locked_instances: Vec::new(),
             preview_unlocked_wall_queue: WallQueue::new(),
             instance_becomes_preview_sender: preview_becomes_ready_sender,
-            instance_preview_percent_sender: instance_preview_percent_sender,
+            instance_preview_percent_sender,
             affinity_map: HashMap::new(),
+            affinity: AffinityPolicy::default(),
+            wall_instances: Vec::new(),
+            moving_wall: false,
+            projector: None,
+            layout: LayoutConfig::default().build(),
+            screen: Rect {
+                x: 0,
+                y: 0,
+                width: 1920,
+                height: 1080,
+            },
+            wall_file: WallFileWriter::new(PathBuf::from("wall_queue.json")),
+            obs: None,
+            events: EventBus::new(),
+            crashed: Vec::new(),
+            reset_counter: ResetCounter::new(&Default::default()),
+            backend,
+            effects,
         }
     }
 
@@ -43,22 +76,22 @@ impl InstanceManager {
 
         match playing_instance {
             Some(instance) => {
-                instance.set_affinity(((1 << 28) - 1) << 4);
+                instance.set_affinity(self.affinity.playing_mask);
                 self.instances
                     .iter()
                     .for_each(|instance| match instance.state.load(SeqCst) {
                         InstanceState::Idle | InstanceState::Preview => {
-                            instance.set_affinity((1 << 4) - 1)
+                            instance.set_affinity(self.affinity.idle_mask)
                         }
                         InstanceState::Resetting | InstanceState::LoadingScreen => {
-                            instance.set_threadcount((1 << 4) - 1)
+                            instance.set_threadcount(self.affinity.background_threads)
                         }
                         InstanceState::Playing => (),
                     });
             }
             None => {
                 self.instances.iter().for_each(
-                    |instance| instance.set_threadcount(2), // match instance.state.load(SeqCst) {
+                    |instance| instance.set_threadcount(self.affinity.wall_threads), // match instance.state.load(SeqCst) {
                                                             // InstanceState::Idle => instance.set_affinity(4),
                                                             // InstanceState::Preview => match instance.locked.load(SeqCst) {
                                                             //     true => instance.set_affinity(16),
@@ -71,27 +104,39 @@ impl InstanceManager {
                                                             // }
                 );
 
-                match self.locked_instances.get(0) {
-                    Some(instance) => {
-                        instance.set_threadcount(30);
-                    }
-                    None => (),
+                if let Some(instance) = self.locked_instances.first() {
+                    instance.set_threadcount(self.affinity.locked_threads);
                 }
             }
         }
     }
 
-    pub fn initialize(preview_becomes_ready_sender: Sender<u32>,instance_preview_percent_sender:Sender<u32>,instance_infos: Vec<InstanceInfo>, conn: &impl Connection) -> Self {
-        let mut instance_manager = Self::new(preview_becomes_ready_sender,instance_preview_percent_sender);
+    pub fn initialize(preview_becomes_ready_sender: Sender<u32>,instance_preview_percent_sender:Sender<u32>,instance_infos: Vec<InstanceInfo>, backend: Arc<dyn WindowBackend>, effects: Box<dyn SessionEffects>, recorder: Option<Recorder>, config: &Config) -> Self {
+        let mut instance_manager = Self::new(preview_becomes_ready_sender,instance_preview_percent_sender, backend.clone(), effects);
+        instance_manager.moving_wall = config.moving_wall;
+        instance_manager.apply_config(config);
+        instance_manager.wall_file = WallFileWriter::new(config.wall_file.clone());
+        instance_manager.reset_counter = ResetCounter::new(&config.reset_counter);
+        instance_manager.obs = config.obs.clone().map(obs::spawn);
+        if config.screen.is_none() {
+            match backend.primary_monitor_geometry() {
+                Ok(screen) => instance_manager.screen = screen,
+                Err(err) => println!("Failed to get the monitor size, assuming 1920x1080: {err}"),
+            }
+        }
 
         for instance_info in instance_infos {
             let title = format !("Minecraft* - Instance {}\0", instance_info.instance_num);
             println!("window: {}", instance_info.window);
-            set_window_title(conn, instance_info.window, &title ).unwrap();
+            backend.set_title(instance_info.window, &title ).unwrap();
 
-            let instance = Instance::new(instance_info);
-            instance.set_threadcount(30);
-            // hwndutils::set_borderless(instance_info.hwnd);
+            let instance = Instance::new(instance_info, backend.clone(), config, instance_manager.events.clone(), recorder.clone());
+            instance.set_threadcount(config.affinity.startup_threads);
+            if config.borderless {
+                if let Err(err) = instance.set_borderless() {
+                    println!("Failed to remove decorations from instance {}: {err}", instance.instance_info.instance_num);
+                }
+            }
             // MoveWindow(instance_info.hwnd, 0, 680, 1920, 400, true);
             // click_top_left(instance_info.hwnd);
             let instance_arc= Arc::new(instance);
@@ -103,8 +148,27 @@ impl InstanceManager {
         instance_manager
     }
 
+    /// Takes over the parts of the config that can change while rulti is running.
+    pub fn apply_config(&mut self, config: &Config) {
+        self.layout = build_layout(&config.layout, &config.locked_bar);
+        self.preview_unlocked_wall_queue.bag_size = config.bag_size;
+        self.affinity = config.affinity.clone();
+        if let Some(screen) = config.screen {
+            self.screen = screen;
+        }
+    }
+
+    /// Puts the instance windows back the way they were before rulti touched them.
+    pub fn shutdown(&self) {
+        for instance in &self.instances {
+            if let Err(err) = instance.restore_decorations() {
+                println!("Failed to restore decorations of instance {}: {err}", instance.instance_info.instance_num);
+            }
+        }
+    }
+
     pub fn reset_all_instances(&mut self) {
-        let cloned_instances = self.instances.iter().cloned().collect::<Vec<_>>();
+        let cloned_instances = self.instances.to_vec();
         self.preview_unlocked_wall_queue.clear();
         for instance in cloned_instances {
             self.reset_instance(instance.clone());
@@ -112,12 +176,13 @@ impl InstanceManager {
     }
 
     pub fn reset_wall_bag(&mut self) {
-        let cloned_instances = self
-            .preview_unlocked_wall_queue
-            .pop()
-            .iter()
-            .cloned()
-            .collect::<Vec<_>>();
+        let cloned_instances = self.preview_unlocked_wall_queue.pop();
+        self.events.publish(Event::BagReset {
+            instance_nums: cloned_instances
+                .iter()
+                .map(|instance| instance.instance_info.instance_num)
+                .collect(),
+        });
         for instance in cloned_instances {
             // println!("Resetting instance: {}", instance.instance_num);
             self.reset_instance(instance.clone());
@@ -125,33 +190,233 @@ impl InstanceManager {
     }
 
     pub fn reset_instance(&mut self, instance: Arc<Instance>) {
-        match self.reset_cancel_channels.get(&instance.instance_info.instance_num) {
-            Some(sender) => match sender.send(()) {
-                _ => (),
-            },
-            _ => (),
+        if let Some(sender) = self.reset_cancel_channels.get(&instance.instance_info.instance_num) {
+            let _ = sender.try_send(());
         }
 
+        // Taking over a reset that is still running doesn't make it another one
+        if instance.start_reset() {
+            self.reset_counter.increment();
+            self.events.publish(Event::Reset {
+                instance_num: instance.instance_info.instance_num,
+            });
+        }
         let cancel_channel = channel(1); // TODO: Figure out bound size
         self.reset_cancel_channels
             .insert(instance.instance_info.instance_num, cancel_channel.0);
-        let sender = self.instance_becomes_preview_sender.clone();
-        let percent_sender = self.instance_preview_percent_sender.clone();
-        tokio::spawn(async move {
-            instance.reset(cancel_channel.1, sender,percent_sender).await;
+        self.effects.spawn_reset(
+            instance,
+            cancel_channel.1,
+            self.instance_becomes_preview_sender.clone(),
+            self.instance_preview_percent_sender.clone(),
+        );
+    }
+
+    /// Takes the playing instance out of fullscreen, focuses the wall projector and resets the instance.
+    pub fn exit_instance(&mut self) -> Result<(), WindowError> {
+        match self.get_playing_instance() {
+            Some(instance_arc) => {
+                println!("Exiting instance: {}", instance_arc.instance_info.instance_num);
+                // The instance stays the playing one until its window is actually back on the wall
+                instance_arc.exit()?;
+                self.effects.set_sleepbg_lock(false);
+                instance_arc.set_state(InstanceState::Idle);
+                if !self.moving_wall {
+                    self.update_wall();
+                }
+                self.events.publish(Event::Exited {
+                    instance_num: instance_arc.instance_info.instance_num,
+                });
+                if let Some(obs) = &self.obs {
+                    obs.send(ObsCommand::Exit);
+                }
+
+                if !self.moving_wall {
+                    match self.backend.find_wall_window()? {
+                        Some(wall_window) => self.backend.activate(wall_window)?,
+                        None => println!("Could not find the wall projector window"),
+                    }
+                }
+                self.reset_instance(instance_arc);
+                self.update_wall();
+            }
+            None => {
+                println!("No playing instances to exit");
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the wall layout and hands it to the moving wall or the projector when they are enabled.
+    pub fn update_wall(&mut self) {
+        self.wall_instances = build_wall_layout(
+            &self.preview_unlocked_wall_queue,
+            &self.locked_instances,
+            &self.instances,
+            self.layout.as_ref(),
+            self.screen,
+        );
+        self.wall_file.write(&self.wall_instances);
+        self.reset_counter.flush();
+        if self.moving_wall {
+            movingwall::arrange_windows(&self.instances, &self.wall_instances);
+        }
+        if let Some(projector) = &self.projector {
+            projector.update_layout(self.wall_instances.clone());
+        }
+        if let Some(obs) = &self.obs {
+            obs.send(ObsCommand::Layout(self.wall_instances.clone()));
+        }
+    }
+
+    pub fn on_preview_ready(&mut self, instance_num: u32) {
+        match self.get_instance_by_instance_num(instance_num) {
+            Some(instance_arc) => {
+                self.preview_unlocked_wall_queue.push(instance_arc);
+            }
+            None => {
+                panic!("Received a preview_becomes_ready_channel message for an instance that doesn't exist");
+            }
+        }
+    }
+
+    /// Resets the first bag on the wall, or plays the first idle locked instance once the wall runs dry.
+    pub fn reset_bag(&mut self) -> Result<(), WindowError> {
+        println!("Resetting all instances");
+        if self.preview_unlocked_wall_queue.can_pop() {
+            self.reset_wall_bag();
+        }
+        if !self.preview_unlocked_wall_queue.can_pop() {
+            match self.get_first_idle_locked_instance() {
+                Some(instance_arc) => self.play_instance(instance_arc)?,
+                None => println!("No idle instances to play"),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn play_instance(&mut self, instance_arc: Arc<Instance>) -> Result<(), WindowError> {
+        if instance_arc.state.load(SeqCst) != InstanceState::Idle {
+            return Err(WindowError::NotIdle(instance_arc.instance_info.window));
+        }
+        // An instance that couldn't be focused stays where it was on the wall
+        instance_arc.play()?;
+        self.unlock(instance_arc.instance_info.instance_num);
+        self.preview_unlocked_wall_queue
+            .remove_by_instance_num(instance_arc.instance_info.instance_num);
+        instance_arc.set_affinity(self.affinity.playing_mask);
+        self.effects.set_sleepbg_lock(true);
+        self.events.publish(Event::Played {
+            instance_num: instance_arc.instance_info.instance_num,
         });
+        if let Some(obs) = &self.obs {
+            obs.send(ObsCommand::Play(instance_arc.instance_info.instance_num));
+        }
+        Ok(())
     }
+
+    /// Locks the wall instance under the given root window coordinates.
+    pub fn lock_at(&mut self, x: i16, y: i16) {
+        let (x, y) = (x as usize, y as usize);
+        let instance_num = self
+            .wall_instances
+            .iter()
+            .find(|wall_instance| {
+                (wall_instance.x < x && wall_instance.x + wall_instance.width > x)
+                    && (wall_instance.y < y && wall_instance.y + wall_instance.height > y)
+            })
+            .map(|wall_instance| wall_instance.instance_num);
+        match instance_num {
+            Some(instance_num) => {
+                println!("Found instance: {}", instance_num);
+                self.preview_unlocked_wall_queue.remove_by_instance_num(instance_num);
+                self.lock(instance_num);
+            }
+            None => {
+                println!("No instance on the wall at ({x},{y})");
+            }
+        }
+    }
+
+    pub fn toggle_window_mode(&self, mode: WindowMode) -> Result<(), WindowError> {
+        match self.get_playing_instance() {
+            Some(instance_arc) => instance_arc.toggle_window_mode(mode),
+            None => {
+                println!("No playing instances to make {mode}");
+                Ok(())
+            }
+        }
+    }
+
+    pub fn cycle_window_mode(&self) -> Result<(), WindowError> {
+        match self.get_playing_instance() {
+            Some(instance_arc) => instance_arc.cycle_window_mode(),
+            None => {
+                println!("No playing instances to change window mode");
+                Ok(())
+            }
+        }
+    }
+
     pub fn lock(&mut self, instance_num: u32) {
         let instance = self.get_instance_by_instance_num(instance_num).unwrap();
+        // Locked instances are on the wall too, in the locked bar
+        if instance.locked.load(SeqCst) {
+            return;
+        }
         instance.lock();
         self.locked_instances.push(instance.clone());
+        self.events.publish(Event::Locked { instance_num });
     }
     pub fn unlock(&mut self, instance_num: u32) {
         let instance = self.get_instance_by_instance_num(instance_num).unwrap();
+        if instance.locked.load(SeqCst) {
+            self.events.publish(Event::Unlocked { instance_num });
+        }
         instance.unlock();
         self.locked_instances
             .retain(|locked_instance| locked_instance.instance_info.instance_num != instance.instance_info.instance_num);
     }
+    /// Unlocks the instance and puts it back on the wall when it has a preview to show.
+    pub fn unlock_to_wall(&mut self, instance_num: u32) {
+        let instance = self.get_instance_by_instance_num(instance_num).unwrap();
+        let was_locked = instance.locked.load(SeqCst);
+        self.unlock(instance_num);
+        // Locking took the instance out of the queue, and only a reset would put it back
+        if was_locked
+            && matches!(instance.state.load(SeqCst), InstanceState::Idle | InstanceState::Preview)
+            && !self.preview_unlocked_wall_queue.contains(instance_num)
+        {
+            self.preview_unlocked_wall_queue.push(instance);
+        }
+    }
+    /// Reports instances whose game process has exited and takes them off the wall.
+    /// Returns the instances that crashed since the last check.
+    pub fn check_for_crashes(&mut self) -> Vec<u32> {
+        let crashed: Vec<u32> = self
+            .instances
+            .iter()
+            .filter(|instance| !self.crashed.contains(&instance.instance_info.instance_num) && !instance.is_alive())
+            .map(|instance| instance.instance_info.instance_num)
+            .collect();
+        for &instance_num in &crashed {
+            self.mark_crashed(instance_num);
+        }
+        crashed
+    }
+
+    /// Takes the crashed instance off the wall and stops its reset.
+    pub fn mark_crashed(&mut self, instance_num: u32) {
+        println!("Instance {instance_num} crashed");
+        self.unlock(instance_num);
+        self.preview_unlocked_wall_queue.remove_by_instance_num(instance_num);
+        if let Some(sender) = self.reset_cancel_channels.remove(&instance_num) {
+            let _ = sender.try_send(());
+        }
+        self.events.publish(Event::Crashed { instance_num });
+        self.crashed.push(instance_num);
+    }
+
     pub fn get_instance_by_instance_num(&self, instance_num: u32) -> Option<Arc<Instance>> {
         self.instances
             .iter()
@@ -163,7 +428,7 @@ impl InstanceManager {
             .iter()
             .filter(|instance| {
                 instance.state.load(SeqCst) == InstanceState::Idle
-                    && instance.locked.load(SeqCst) == false
+                    && !instance.locked.load(SeqCst)
             })
             .map(Arc::clone)
             .collect()
@@ -228,7 +493,7 @@ impl WallQueue {
     pub fn pop(&mut self) -> Vec<Arc<Instance>> {
         let maybe_instances = self.queue.drain(0..self.bag_size);
         let ret_instances = maybe_instances
-            .filter_map(|maybe_instance| maybe_instance)
+            .flatten()
             .collect::<Vec<_>>();
         self.queue = self.queue.iter().filter(|maybe_instance| maybe_instance.is_some()).cloned().collect::<Vec<_>>();
         ret_instances
@@ -246,6 +511,12 @@ impl WallQueue {
     pub fn len(&self) -> usize {
         self.queue.len()
     }
+    pub fn contains(&self, instance_num: u32) -> bool {
+        self.queue
+            .iter()
+            .flatten()
+            .any(|instance| instance.instance_info.instance_num == instance_num)
+    }
     pub fn remove_by_instance_num(&mut self, instance_num: u32) {
         // Replaces the instance with the given instance number with None
         let index = self.queue
@@ -257,17 +528,83 @@ impl WallQueue {
                     .unwrap_or(false)
             });
 
-        match index {
-            Some(index) => {
-                self.queue[index] = None;
+        if let Some(index) = index {
+            self.queue[index] = None;
+        }
+    }
+}
+
+/// What the manager does outside of the instance windows, so a replay can run without it.
+pub trait SessionEffects: Send {
+    /// Starts polling the state file of the instance until the reset started on it is done or cancelled.
+    fn spawn_reset(
+        &self,
+        instance: Arc<Instance>,
+        cancel_receiver: Receiver<()>,
+        preview_ready_sender: Sender<u32>,
+        preview_percent_sender: Sender<u32>,
+    );
+    /// Tells sleepbg whether an instance is being played.
+    fn set_sleepbg_lock(&self, playing: bool);
+}
+
+/// Resets run as tokio tasks, and sleepbg slows down the other instances while `sleepbg.lock` exists.
+pub struct SystemEffects {
+    sleepbg_lock: Option<PathBuf>,
+}
+
+impl SystemEffects {
+    /// Uses `~/sleepbg.lock`, or no lock at all without a home directory.
+    pub fn new() -> Self {
+        Self::with_sleepbg_lock(env::var_os("HOME").map(|home| PathBuf::from(home).join("sleepbg.lock")))
+    }
+
+    pub fn with_sleepbg_lock(sleepbg_lock: Option<PathBuf>) -> Self {
+        Self { sleepbg_lock }
+    }
+}
+
+impl SessionEffects for SystemEffects {
+    fn spawn_reset(
+        &self,
+        instance: Arc<Instance>,
+        cancel_receiver: Receiver<()>,
+        preview_ready_sender: Sender<u32>,
+        preview_percent_sender: Sender<u32>,
+    ) {
+        tokio::spawn(async move {
+            instance.reset(cancel_receiver, preview_ready_sender, preview_percent_sender).await;
+        });
+    }
+
+    fn set_sleepbg_lock(&self, playing: bool) {
+        let Some(path) = &self.sleepbg_lock else {
+            return;
+        };
+        if playing {
+            if let Err(err) = File::create(path) {
+                println!("Failed to create sleepbg.lock: {err}");
             }
-            None => (),
+            return;
+        }
+        match fs::remove_file(path) {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => println!("Failed to remove sleepbg.lock: {err}"),
         }
-            
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Bumped whenever a field of the wall file changes meaning or is removed
+pub const WALL_FILE_VERSION: u32 = 2;
+
+#[derive(Serialize)]
+struct WallFile<'a> {
+    version: u32,
+    instances: &'a [WallFileInstance],
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct WallFileInstance {
     pub instance_num: u32,
     pub width: usize,
@@ -276,114 +613,432 @@ pub struct WallFileInstance {
     pub y: usize,
     playing: bool,
     freeze: bool,
+    pub state: String,
+    pub locked: bool,
+    pub preview_percent: usize,
+    /// Which bag of the wall queue the instance is in, if it is queued
+    pub bag_index: Option<usize>,
+    pub gamedir: String,
 }
 
-pub fn write_wall_queue_to_json_file(
-    wall_queue: &WallQueue,
-    all_instances: &Vec<Arc<Instance>>,
-) -> Vec<WallFileInstance> {
-    let mut file = File::create("wall_queue.json").unwrap();
+impl WallFileInstance {
+    fn new(instance: &Instance, x: usize, y: usize, width: usize, height: usize, bag_index: Option<usize>) -> Self {
+        let state = instance.state.load(SeqCst);
+        let preview_percent = instance.preview_percent.load(SeqCst);
+        Self {
+            instance_num: instance.instance_info.instance_num,
+            width,
+            height,
+            x,
+            y,
+            playing: state == InstanceState::Playing,
+            freeze: (state == InstanceState::Idle || state == InstanceState::Preview) && preview_percent > instance.freeze_percent,
+            state: state.to_string(),
+            locked: instance.locked.load(SeqCst),
+            preview_percent,
+            bag_index,
+            gamedir: instance.instance_info.gamedir.clone(),
+        }
+    }
+
+    /// Instances that aren't on the wall are written as a 1x1 rectangle
+    pub fn is_hidden(&self) -> bool {
+        self.width <= 1 && self.height <= 1
+    }
+}
+
+/// Writes the wall state for OBS scripts, replacing the file atomically so readers never see half of it.
+pub struct WallFileWriter {
+    path: PathBuf,
+    last_contents: String,
+}
+
+impl WallFileWriter {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            last_contents: String::new(),
+        }
+    }
+
+    pub fn write(&mut self, instances: &[WallFileInstance]) {
+        let json_string = match serde_json::to_string(&WallFile {
+            version: WALL_FILE_VERSION,
+            instances,
+        }) {
+            Ok(json_string) => json_string,
+            Err(e) => {
+                println!("error writing json to file: {}", e);
+                return;
+            }
+        };
+        if json_string == self.last_contents {
+            return;
+        }
 
-    let mut index = 0;
+        match write_atomically(&self.path, json_string.as_bytes()) {
+            Ok(_) => self.last_contents = json_string,
+            Err(e) => println!("Failed to write {}: {}", self.path.display(), e),
+        }
+    }
+}
 
-    let screen_width = 1920;
-    let screen_height = 1080;
+/// Writes a temp file next to the path and renames it over the path, so readers never see half of it.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.to_path_buf().into_os_string();
+    temp_path.push(".tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
 
-    let instance_width = screen_width / wall_queue.bag_size;
-    let instance_height = screen_height / wall_queue.bag_size;
-    let bag_size = wall_queue.bag_size;
-    let bags_horizontal = 2;
-    let bags_vertical = 2;
-    let bag_width = screen_width / bags_horizontal;
-    let bag_height = screen_height / bags_vertical;
-    let bag_cols = 2;
+/// Places every instance according to the layout. Instances that aren't on the wall get a 1x1 rectangle off-screen.
+pub fn build_wall_layout(
+    wall_queue: &WallQueue,
+    locked_instances: &[Arc<Instance>],
+    all_instances: &[Arc<Instance>],
+    layout: &dyn Layout,
+    screen: Rect,
+) -> Vec<WallFileInstance> {
+    // Instances that aren't on the wall are parked just right of the screen
+    let offscreen_x = (screen.x as i32 + screen.width as i32).max(0) as usize;
+    let arrangement = layout.arrange(screen, wall_queue.queue.len(), locked_instances.len());
     // Create an empty vector to store the instances in
     let mut instances: Vec<WallFileInstance> = Vec::new();
-    let mut already_written_instances = Vec::new();
     let in_play_mode = all_instances
         .iter()
         .any(|instance| instance.state.load(SeqCst) == InstanceState::Playing);
-    for instance in &wall_queue.queue {
-        if index >= wall_queue.bag_size * wall_queue.bag_size {
-            break;
-        }
-        match instance {
-            Some(instance) => {
-                // Calculate which bag we're in
-                let bag_index = index / bag_size;
-                let bag_x_pos = bag_index % bags_horizontal;
-                let bag_y_pos = bag_index / bags_vertical;
-
-                let bag_x = screen_width - bag_x_pos * bag_width - bag_width;
-                let bag_y = screen_height - bag_y_pos * bag_height - bag_height;
-                // Calculate which position in the bag we should be in
-                let instance_x = bag_x + (index % bag_cols) * instance_width;
-                let instance_y =
-                    bag_y + ((index - bag_index * bag_size) / bag_cols) * instance_height;
-
-                let _row = index / wall_queue.bag_size;
-                let _col = index % wall_queue.bag_size;
-                let instance_json: WallFileInstance = WallFileInstance {
-                    instance_num: instance.instance_info.instance_num,
-                    width: instance_width,
-                    height: instance_height,
-                    x: if in_play_mode {
-                        screen_width
-                    } else {
-                        instance_x
-                    },
-                    y: instance_y,
-                    playing: instance.state.load(SeqCst) == InstanceState::Playing,
-                    freeze: (instance.state.load(SeqCst) == InstanceState::Idle || instance.state.load(SeqCst) == InstanceState::Preview) && instance.preview_percent.load(SeqCst) > 80,
-
-                };
-
-                // println!("Index {}, Bag {} is at ({},{}), instance is at ({},{}), instance is {}",index, bag_index, bag_x, bag_y, instance_x, instance_y, instance.instance_num);
-                // println!(
-                //     "Index {}, instance is at ({},{}), instance is {}",
-                //     index, instance_json.x, instance_json.y, instance.instance_num
-                // );
-
-                instances.push(instance_json);
-                already_written_instances.push(instance.clone());
-            }
-            None => {},
-        }
 
-        index += 1;
+    // Empty slots left behind by locked instances keep their place until the bag is reset
+    let queued_instances = wall_queue
+        .queue
+        .iter()
+        .enumerate()
+        .map(|(index, instance)| (instance.as_ref(), Some(index / wall_queue.bag_size)))
+        .zip(&arrangement.queue);
+    let locked_instances = locked_instances
+        .iter()
+        .map(|instance| (Some(instance), None))
+        .zip(&arrangement.locked);
+    for ((instance, bag_index), rect) in queued_instances.chain(locked_instances) {
+        if let Some(instance) = instance {
+            let x = if in_play_mode {
+                offscreen_x
+            } else {
+                rect.x.max(0) as usize
+            };
+            instances.push(WallFileInstance::new(
+                instance,
+                x,
+                rect.y.max(0) as usize,
+                rect.width as usize,
+                rect.height as usize,
+                bag_index,
+            ));
+        }
     }
 
     for instance in all_instances {
-        if !already_written_instances
+        if !instances
             .iter()
-            .find(|inst| inst.instance_info.instance_num == instance.instance_info.instance_num)
-            .is_some()
+            .any(|wall_instance| wall_instance.instance_num == instance.instance_info.instance_num)
         {
-            let instance_json: WallFileInstance = WallFileInstance {
-                instance_num: instance.instance_info.instance_num,
-                width: 1,
-                height: 1,
-                x: screen_width,
-                y: 0,
-                playing: instance.state.load(SeqCst) == InstanceState::Playing,
-                freeze: (instance.state.load(SeqCst) == InstanceState::Idle || instance.state.load(SeqCst) == InstanceState::Preview) && instance.preview_percent.load(SeqCst) > 80,
-            };
-            instances.push(instance_json);
+            let bag_index = wall_queue
+                .queue
+                .iter()
+                .position(|queued| {
+                    queued.as_ref().is_some_and(|queued| {
+                        queued.instance_info.instance_num == instance.instance_info.instance_num
+                    })
+                })
+                .map(|index| index / wall_queue.bag_size);
+            instances.push(WallFileInstance::new(
+                instance,
+                offscreen_x,
+                screen.y.max(0) as usize,
+                1,
+                1,
+                bag_index,
+            ));
+        }
+    }
+    instances
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use tempfile::TempDir;
 
+    use super::*;
+    use crate::{backend::FakeBackend, control::{self, ControlCommand}, instance::ResetStep, resetcounter::ResetCounterConfig};
+
+    const SCREEN: Rect = Rect {
+        x: 0,
+        y: 0,
+        width: 1920,
+        height: 1080,
+    };
+    const WINDOWED: Rect = Rect {
+        x: 0,
+        y: 0,
+        width: 854,
+        height: 480,
+    };
+    const WALL_WINDOW: u32 = 1;
+    const KEY_ESCAPE: u8 = 9;
+    const KEY_F6: u8 = 72;
+
+    /// Leaves the resets to `finish_reset` and keeps sleepbg.lock in memory
+    struct FakeEffects {
+        sleepbg_lock: Arc<AtomicBool>,
+    }
+
+    impl SessionEffects for FakeEffects {
+        fn spawn_reset(&self, _: Arc<Instance>, _: Receiver<()>, _: Sender<u32>, _: Sender<u32>) {}
+
+        fn set_sleepbg_lock(&self, playing: bool) {
+            self.sleepbg_lock.store(playing, SeqCst);
         }
-        // println!("Is in play mode: {}", in_play_mode);
     }
 
-    // write the instances to a string
-    let json_string = serde_json::to_string(&instances);
-    match json_string {
-        Ok(json_string) => {
-            file.write_all(json_string.as_bytes()).unwrap();
+    /// A manager of instances 1 to 4 in windows 101 to 104 on the fake backend, resetting in bags of two
+    struct Setup {
+        backend: Arc<FakeBackend>,
+        manager: InstanceManager,
+        sleepbg_lock: Arc<AtomicBool>,
+        _dir: TempDir,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            let config = Config {
+                bag_size: 2,
+                layout: LayoutConfig::BagGrid {
+                    bag_size: 2,
+                    bag_cols: 2,
+                    bags_horizontal: 2,
+                    bags_vertical: 2,
+                },
+                wall_file: dir.path().join("wall.json"),
+                stats_file: None,
+                reset_counter: ResetCounterConfig {
+                    total_file: None,
+                    outputs: Vec::new(),
+                },
+                ..Config::default()
+            };
+            let backend = Arc::new(FakeBackend::new(SCREEN));
+            backend.state().wall_window = Some(WALL_WINDOW);
+            let infos: Vec<InstanceInfo> = (1..=4)
+                .map(|instance_num| InstanceInfo {
+                    window: 100 + instance_num,
+                    pid: 0,
+                    gamedir: format!("/tmp/RSG {instance_num}/"),
+                    instance_num,
+                })
+                .collect();
+            for info in &infos {
+                backend.add_window(info.clone(), "Minecraft* 1.16.1", WINDOWED);
+            }
+            let sleepbg_lock = Arc::new(AtomicBool::new(false));
+            let effects = FakeEffects {
+                sleepbg_lock: sleepbg_lock.clone(),
+            };
+            let mut manager = InstanceManager::initialize(
+                channel(1).0,
+                channel(1).0,
+                infos,
+                backend.clone(),
+                Box::new(effects),
+                None,
+                &config,
+            );
+            manager.update_wall();
+            Self {
+                backend,
+                manager,
+                sleepbg_lock,
+                _dir: dir,
+            }
         }
-        Err(e) => {
-            println!("error writing json to file: {}", e);
+
+        fn queue(&self) -> Vec<Option<u32>> {
+            self.manager
+                .preview_unlocked_wall_queue
+                .queue
+                .iter()
+                .map(|instance| instance.as_ref().map(|instance| instance.instance_info.instance_num))
+                .collect()
+        }
+
+        fn locked(&self) -> Vec<u32> {
+            self.manager.locked_instances.iter().map(|instance| instance.instance_info.instance_num).collect()
+        }
+
+        fn state(&self, instance_num: u32) -> InstanceState {
+            self.manager.get_instance_by_instance_num(instance_num).unwrap().state.load(SeqCst)
+        }
+
+        fn keys(&self, instance_num: u32) -> Vec<(u8, bool)> {
+            self.backend.state().windows[&(100 + instance_num)].keys.clone()
+        }
+
+        fn geometry(&self, instance_num: u32) -> Rect {
+            self.backend.state().windows[&(100 + instance_num)].rect
+        }
+
+        /// Walks the instance through what the game writes while it resets, like the polling task would
+        fn finish_reset(&mut self, instance_num: u32) {
+            let instance = self.manager.get_instance_by_instance_num(instance_num).unwrap();
+            assert_eq!(instance.on_world_preview_state("generating"), ResetStep::Generating);
+            assert_eq!(instance.on_world_preview_state("previewing,0"), ResetStep::PreviewReady);
+            self.manager.on_preview_ready(instance_num);
+            assert_eq!(instance.on_world_preview_state("inworld,paused"), ResetStep::Done);
+            self.manager.update_wall();
+        }
+
+        /// Center of the instance on the wall
+        fn wall_position(&self, instance_num: u32) -> (i16, i16) {
+            let wall_instance = self
+                .manager
+                .wall_instances
+                .iter()
+                .find(|wall_instance| wall_instance.instance_num == instance_num)
+                .unwrap();
+            ((wall_instance.x + wall_instance.width / 2) as i16, (wall_instance.y + wall_instance.height / 2) as i16)
         }
     }
-    println!("Instances len: {}", instances.len());
-    instances
+
+    #[test]
+    fn lock_reset_bag_play_and_exit() {
+        let mut setup = Setup::new();
+        assert_eq!(setup.queue(), vec![Some(1), Some(2), Some(3), Some(4)]);
+
+        let (x, y) = setup.wall_position(2);
+        setup.manager.lock_at(x, y);
+        assert_eq!(setup.locked(), vec![2]);
+        assert_eq!(setup.queue(), vec![Some(1), None, Some(3), Some(4)]);
+        // Nothing is on the wall outside of the screen
+        setup.manager.lock_at(-10, -10);
+        assert_eq!(setup.locked(), vec![2]);
+
+        // The first bag holds the locked slot, so only instance 1 is reset
+        setup.manager.reset_bag().unwrap();
+        assert_eq!(setup.queue(), vec![Some(3), Some(4)]);
+        assert_eq!(setup.state(1), InstanceState::Resetting);
+        assert_eq!(setup.keys(1), vec![(KEY_F6, true), (KEY_F6, false)]);
+        assert_eq!(setup.state(2), InstanceState::Idle);
+        assert!(setup.keys(2).is_empty());
+        assert_eq!(setup.manager.reset_counter.session, 1);
+
+        setup.finish_reset(1);
+        assert_eq!(setup.state(1), InstanceState::Idle);
+        assert_eq!(setup.queue(), vec![Some(3), Some(4), Some(1)]);
+
+        // Once the wall can't fill another bag, the locked instance is played
+        setup.manager.reset_bag().unwrap();
+        assert_eq!(setup.queue(), vec![Some(1)]);
+        assert_eq!(setup.state(3), InstanceState::Resetting);
+        assert_eq!(setup.state(4), InstanceState::Resetting);
+        assert_eq!(setup.state(2), InstanceState::Playing);
+        assert!(setup.locked().is_empty());
+        assert_eq!(setup.backend.state().active, Some(102));
+        assert_eq!(setup.geometry(2), SCREEN);
+        assert_eq!(setup.keys(2).iter().filter(|key| **key == (KEY_ESCAPE, true)).count(), 3);
+        assert!(setup.sleepbg_lock.load(SeqCst));
+
+        setup.manager.exit_instance().unwrap();
+        assert_eq!(setup.geometry(2), WINDOWED);
+        assert_eq!(setup.backend.state().active, Some(WALL_WINDOW));
+        assert_eq!(setup.state(2), InstanceState::Resetting);
+        assert!(setup.manager.get_playing_instance().is_none());
+        assert!(!setup.sleepbg_lock.load(SeqCst));
+        assert_eq!(setup.manager.reset_counter.session, 4);
+    }
+
+    #[test]
+    fn unlocked_instances_go_back_on_the_wall() {
+        let mut setup = Setup::new();
+        control::handle_command(&mut setup.manager, ControlCommand::Lock(2));
+        assert_eq!(setup.locked(), vec![2]);
+        assert_eq!(setup.queue(), vec![Some(1), None, Some(3), Some(4)]);
+
+        control::handle_command(&mut setup.manager, ControlCommand::Unlock(2));
+        assert!(setup.locked().is_empty());
+        assert_eq!(setup.queue(), vec![Some(1), None, Some(3), Some(4), Some(2)]);
+        let wall_instance = setup.manager.wall_instances.iter().find(|instance| instance.instance_num == 2).unwrap();
+        assert!(!wall_instance.is_hidden());
+        assert!(!wall_instance.locked);
+
+        // Unlocking what isn't locked leaves the queue alone
+        control::handle_command(&mut setup.manager, ControlCommand::Unlock(2));
+        assert_eq!(setup.queue(), vec![Some(1), None, Some(3), Some(4), Some(2)]);
+    }
+
+    #[test]
+    fn only_idle_instances_are_played() {
+        let mut setup = Setup::new();
+        let instance = setup.manager.get_instance_by_instance_num(1).unwrap();
+        setup.manager.reset_instance(instance.clone());
+        assert!(matches!(setup.manager.play_instance(instance.clone()), Err(WindowError::NotIdle(101))));
+        assert_eq!(setup.state(1), InstanceState::Resetting);
+        assert_eq!(setup.backend.state().active, None);
+        assert_eq!(setup.geometry(1), WINDOWED);
+
+        // Resetting again takes over the running reset instead of counting another one
+        setup.manager.reset_instance(instance);
+        assert_eq!(setup.manager.reset_counter.session, 1);
+        assert_eq!(setup.keys(1), vec![(KEY_F6, true), (KEY_F6, false)]);
+    }
+
+    #[test]
+    fn failed_play_leaves_the_wall_alone() {
+        let mut setup = Setup::new();
+        setup.manager.lock(2);
+        setup.manager.preview_unlocked_wall_queue.remove_by_instance_num(2);
+        setup.backend.state().focus_refused = true;
+        let affinity = setup.manager.get_instance_by_instance_num(2).unwrap().affinity_mask.load(SeqCst);
+
+        for instance_num in [2, 3] {
+            let instance = setup.manager.get_instance_by_instance_num(instance_num).unwrap();
+            let window = 100 + instance_num;
+            assert!(matches!(setup.manager.play_instance(instance), Err(WindowError::Timeout(w)) if w == window));
+            assert_eq!(setup.state(instance_num), InstanceState::Idle);
+            assert_eq!(setup.geometry(instance_num), WINDOWED);
+        }
+        assert_eq!(setup.locked(), vec![2]);
+        assert_eq!(setup.queue(), vec![Some(1), None, Some(3), Some(4)]);
+        assert_eq!(setup.manager.get_instance_by_instance_num(2).unwrap().affinity_mask.load(SeqCst), affinity);
+        assert!(!setup.sleepbg_lock.load(SeqCst));
+        assert!(setup.manager.get_playing_instance().is_none());
+
+        setup.backend.state().focus_refused = false;
+        let instance = setup.manager.get_instance_by_instance_num(2).unwrap();
+        setup.manager.play_instance(instance).unwrap();
+        assert_eq!(setup.state(2), InstanceState::Playing);
+        assert!(setup.locked().is_empty());
+        assert!(setup.sleepbg_lock.load(SeqCst));
+    }
+
+    #[test]
+    fn failed_exit_keeps_the_instance_playing() {
+        let mut setup = Setup::new();
+        let instance = setup.manager.get_instance_by_instance_num(3).unwrap();
+        setup.manager.play_instance(instance).unwrap();
+        assert_eq!(setup.state(3), InstanceState::Playing);
+
+        let window = setup.backend.state().windows.remove(&103).unwrap();
+        assert!(matches!(setup.manager.exit_instance(), Err(WindowError::NoWindow(103))));
+        assert_eq!(setup.state(3), InstanceState::Playing);
+        assert_eq!(setup.backend.state().active, Some(103));
+
+        setup.backend.state().windows.insert(103, window);
+        setup.manager.exit_instance().unwrap();
+        assert_eq!(setup.state(3), InstanceState::Resetting);
+        assert_eq!(setup.backend.state().active, Some(WALL_WINDOW));
+    }
 }
@@ -2,14 +2,31 @@ use std::process::Command;
 
 use regex::Regex;
 
-pub fn get_instance_dir(pid: u32) -> String {
-    let str:String = String::from_utf8_lossy( &Command::new("sh").arg("-c").arg(format!("pwdx {pid}")).output().unwrap().stdout.as_slice()).into();
+/// The working directory of the process, or `None` when `pwdx` can't tell
+pub fn get_instance_dir(pid: u32) -> Option<String> {
+    let output = Command::new("sh").arg("-c").arg(format!("pwdx {pid}")).output().ok()?;
+    let str:String = String::from_utf8_lossy(output.stdout.as_slice()).into();
     // Remove {pid} : from the start of the string
-    str.replace(format!("{pid}: ", pid=pid).as_str(), "").trim().to_string()
+    let dir = str.replace(format!("{pid}: ", pid=pid).as_str(), "").trim().to_string();
+    (output.status.success() && !dir.is_empty()).then_some(dir)
 }
 
-pub fn get_instance_num(instance_dir:String) -> u32{
-    let instance_number_regex = Regex::new("RSG (.*?)/").unwrap();
-    let instance_number = instance_number_regex.captures(&instance_dir).unwrap().get(1).unwrap().as_str();
-    instance_number.parse::<u32>().unwrap()
-}
\ No newline at end of file
+/// The first capture of the regex in the game directory, if it is a number
+pub fn get_instance_num(instance_dir: &str, instance_number_regex: &Regex) -> Option<u32> {
+    let instance_number = instance_number_regex.captures(instance_dir)?.get(1)?.as_str();
+    instance_number.parse::<u32>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instance_num_comes_from_the_game_directory() {
+        let regex = Regex::new("RSG (.*?)/").unwrap();
+        assert_eq!(get_instance_num("/home/me/MultiMC/instances/RSG 12/.minecraft", &regex), Some(12));
+        assert_eq!(get_instance_num("/home/me/.minecraft", &regex), None);
+        assert_eq!(get_instance_num("/home/me/RSG two/.minecraft", &regex), None);
+        assert_eq!(get_instance_num("", &regex), None);
+    }
+}
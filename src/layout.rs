@@ -0,0 +1,443 @@
+use serde::{Deserialize, Serialize};
+
+use crate::x11::Rect;
+
+/// Rectangles for the queued instances in queue order, and for the locked instances in lock order
+pub struct Arrangement {
+    pub queue: Vec<Rect>,
+    pub locked: Vec<Rect>,
+}
+
+pub trait Layout {
+    /// Places up to `queue_len` queued and `locked_len` locked instances on the screen without any overlap.
+    /// Instances that don't fit are left out of the arrangement.
+    fn arrange(&self, screen: Rect, queue_len: usize, locked_len: usize) -> Arrangement;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LayoutConfig {
+    Grid {
+        rows: Option<usize>,
+        cols: Option<usize>,
+    },
+    BagGrid {
+        bag_size: usize,
+        bag_cols: usize,
+        bags_horizontal: usize,
+        bags_vertical: usize,
+    },
+    FocusGrid {
+        focus_rows: usize,
+        focus_cols: usize,
+        background_rows: usize,
+        background_cols: usize,
+        focus_width_percent: usize,
+        locked_bar_height_percent: usize,
+    },
+}
+
+impl Default for LayoutConfig {
+    fn default() -> Self {
+        LayoutConfig::BagGrid {
+            bag_size: 4,
+            bag_cols: 2,
+            bags_horizontal: 2,
+            bags_vertical: 2,
+        }
+    }
+}
+
+/// A strip along the bottom of the screen showing the locked instances in the order they were locked
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockedBarConfig {
+    pub instance_width: u16,
+    pub instance_height: u16,
+    /// No strip is reserved when this is 0
+    pub max_count: usize,
+}
+
+/// Reserves the locked bar at the bottom of the screen and gives the rest of it to the inner layout.
+/// This replaces any locked bar the inner layout has of its own.
+pub struct LockedBarLayout {
+    pub inner: Box<dyn Layout + Send>,
+    pub bar: LockedBarConfig,
+}
+
+impl Layout for LockedBarLayout {
+    fn arrange(&self, screen: Rect, queue_len: usize, locked_len: usize) -> Arrangement {
+        let bar_height = self.bar.instance_height.min(screen.height);
+        let main = Rect {
+            height: screen.height - bar_height,
+            ..screen
+        };
+        let bar = Rect {
+            y: screen.y + main.height as i16,
+            height: bar_height,
+            ..screen
+        };
+
+        let instance_width = self.bar.instance_width.max(1) as usize;
+        let cols = (bar.width as usize / instance_width).min(self.bar.max_count);
+        let locked = (0..locked_len.min(cols))
+            .map(|index| Rect {
+                x: bar.x + (index * instance_width) as i16,
+                y: bar.y,
+                width: instance_width as u16,
+                height: bar_height,
+            })
+            .collect();
+        Arrangement {
+            queue: self.inner.arrange(main, queue_len, 0).queue,
+            locked,
+        }
+    }
+}
+
+pub fn build_layout(layout: &LayoutConfig, locked_bar: &LockedBarConfig) -> Box<dyn Layout + Send> {
+    if locked_bar.max_count == 0 || locked_bar.instance_height == 0 {
+        return layout.build();
+    }
+    Box::new(LockedBarLayout {
+        inner: layout.build(),
+        bar: locked_bar.clone(),
+    })
+}
+
+impl LayoutConfig {
+    pub fn build(&self) -> Box<dyn Layout + Send> {
+        match *self {
+            LayoutConfig::Grid { rows, cols } => Box::new(GridLayout { rows, cols }),
+            LayoutConfig::BagGrid {
+                bag_size,
+                bag_cols,
+                bags_horizontal,
+                bags_vertical,
+            } => Box::new(BagGridLayout {
+                bag_size,
+                bag_cols,
+                bags_horizontal,
+                bags_vertical,
+            }),
+            LayoutConfig::FocusGrid {
+                focus_rows,
+                focus_cols,
+                background_rows,
+                background_cols,
+                focus_width_percent,
+                locked_bar_height_percent,
+            } => Box::new(FocusGridLayout {
+                focus_rows,
+                focus_cols,
+                background_rows,
+                background_cols,
+                focus_width_percent,
+                locked_bar_height_percent,
+            }),
+        }
+    }
+}
+
+/// One grid over the whole screen. Missing dimensions are picked so every instance fits.
+pub struct GridLayout {
+    pub rows: Option<usize>,
+    pub cols: Option<usize>,
+}
+
+impl Layout for GridLayout {
+    fn arrange(&self, screen: Rect, queue_len: usize, _locked_len: usize) -> Arrangement {
+        let (rows, cols) = match (self.rows, self.cols) {
+            (Some(rows), Some(cols)) => (rows, cols),
+            (Some(rows), None) => (rows, queue_len.div_ceil(rows.max(1))),
+            (None, Some(cols)) => (queue_len.div_ceil(cols.max(1)), cols),
+            (None, None) => {
+                let cols = (1..).find(|cols| cols * cols >= queue_len).unwrap();
+                (queue_len.div_ceil(cols), cols)
+            }
+        };
+        Arrangement {
+            queue: grid(screen, rows, cols, queue_len),
+            locked: Vec::new(),
+        }
+    }
+}
+
+/// Bags of `bag_size` instances, the first bag in the bottom right corner and the following ones
+/// filling the screen right to left, then bottom to top.
+pub struct BagGridLayout {
+    pub bag_size: usize,
+    pub bag_cols: usize,
+    pub bags_horizontal: usize,
+    pub bags_vertical: usize,
+}
+
+impl Layout for BagGridLayout {
+    fn arrange(&self, screen: Rect, queue_len: usize, _locked_len: usize) -> Arrangement {
+        let bag_size = self.bag_size.max(1);
+        let bag_cols = self.bag_cols.max(1);
+        let bag_rows = bag_size.div_ceil(bag_cols);
+        let bags = grid(screen, self.bags_vertical, self.bags_horizontal, usize::MAX);
+        let capacity = bags.len() * bag_size;
+
+        let queue = (0..queue_len.min(capacity))
+            .map(|index| {
+                let bag_index = index / bag_size;
+                let bag_x_pos = bag_index % self.bags_horizontal;
+                let bag_y_pos = bag_index / self.bags_horizontal;
+                let bag_col = self.bags_horizontal - 1 - bag_x_pos;
+                let bag_row = self.bags_vertical - 1 - bag_y_pos;
+                let bag = bags[bag_row * self.bags_horizontal + bag_col];
+
+                let index_in_bag = index % bag_size;
+                cell(bag, bag_rows, bag_cols, index_in_bag / bag_cols, index_in_bag % bag_cols)
+            })
+            .collect();
+        Arrangement {
+            queue,
+            locked: Vec::new(),
+        }
+    }
+}
+
+/// A large grid for the first instances in the queue, a smaller grid for the ones after it,
+/// and a bar along the bottom for the locked instances.
+pub struct FocusGridLayout {
+    pub focus_rows: usize,
+    pub focus_cols: usize,
+    pub background_rows: usize,
+    pub background_cols: usize,
+    pub focus_width_percent: usize,
+    pub locked_bar_height_percent: usize,
+}
+
+impl Layout for FocusGridLayout {
+    fn arrange(&self, screen: Rect, queue_len: usize, locked_len: usize) -> Arrangement {
+        let (main, bar) = split_bottom(screen, self.locked_bar_height_percent);
+        let (focus, background) = split_left(main, self.focus_width_percent);
+
+        let focus_capacity = self.focus_rows * self.focus_cols;
+        let mut queue = grid(focus, self.focus_rows, self.focus_cols, queue_len);
+        queue.extend(grid(
+            background,
+            self.background_rows,
+            self.background_cols,
+            queue_len.saturating_sub(focus_capacity),
+        ));
+        Arrangement {
+            queue,
+            locked: bar_cells(bar, locked_len),
+        }
+    }
+}
+
+/// Splits off a strip of `percent` of the height at the bottom of the area.
+pub fn split_bottom(area: Rect, percent: usize) -> (Rect, Rect) {
+    let bar_height = (area.height as usize * percent.min(100) / 100) as u16;
+    let top = Rect {
+        height: area.height - bar_height,
+        ..area
+    };
+    let bottom = Rect {
+        y: area.y + top.height as i16,
+        height: bar_height,
+        ..area
+    };
+    (top, bottom)
+}
+
+/// Splits the area into a left part of `percent` of the width and the remaining right part.
+pub fn split_left(area: Rect, percent: usize) -> (Rect, Rect) {
+    let left_width = (area.width as usize * percent.min(100) / 100) as u16;
+    let left = Rect {
+        width: left_width,
+        ..area
+    };
+    let right = Rect {
+        x: area.x + left_width as i16,
+        width: area.width - left_width,
+        ..area
+    };
+    (left, right)
+}
+
+/// Places up to `count` 16:9 cells left to right in a horizontal bar.
+pub fn bar_cells(bar: Rect, count: usize) -> Vec<Rect> {
+    if bar.height == 0 {
+        return Vec::new();
+    }
+    let cell_width = (bar.height as usize * 16 / 9).max(1);
+    let cols = (bar.width as usize / cell_width).max(1);
+    grid(bar, 1, cols, count)
+}
+
+/// Splits the area into `rows` x `cols` cells and returns the first `count` of them in row-major order.
+/// Cell edges are rounded so that neighbouring cells share an edge and never overlap.
+pub fn grid(area: Rect, rows: usize, cols: usize, count: usize) -> Vec<Rect> {
+    if rows == 0 || cols == 0 {
+        return Vec::new();
+    }
+    (0..(rows * cols).min(count))
+        .map(|index| cell(area, rows, cols, index / cols, index % cols))
+        .collect()
+}
+
+fn cell(area: Rect, rows: usize, cols: usize, row: usize, col: usize) -> Rect {
+    let x0 = area.width as usize * col / cols;
+    let x1 = area.width as usize * (col + 1) / cols;
+    let y0 = area.height as usize * row / rows;
+    let y1 = area.height as usize * (row + 1) / rows;
+    Rect {
+        x: area.x + x0 as i16,
+        y: area.y + y0 as i16,
+        width: (x1 - x0) as u16,
+        height: (y1 - y0) as u16,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCREEN: Rect = Rect {
+        x: 100,
+        y: 50,
+        width: 1920,
+        height: 1080,
+    };
+
+    fn layouts() -> Vec<LayoutConfig> {
+        vec![
+            LayoutConfig::Grid { rows: None, cols: None },
+            LayoutConfig::Grid { rows: Some(2), cols: None },
+            LayoutConfig::Grid { rows: None, cols: Some(3) },
+            LayoutConfig::Grid {
+                rows: Some(2),
+                cols: Some(3),
+            },
+            LayoutConfig::default(),
+            LayoutConfig::BagGrid {
+                bag_size: 3,
+                bag_cols: 2,
+                bags_horizontal: 3,
+                bags_vertical: 2,
+            },
+            LayoutConfig::FocusGrid {
+                focus_rows: 2,
+                focus_cols: 2,
+                background_rows: 3,
+                background_cols: 4,
+                focus_width_percent: 60,
+                locked_bar_height_percent: 20,
+            },
+        ]
+    }
+
+    fn locked_bars() -> Vec<LockedBarConfig> {
+        vec![
+            LockedBarConfig::default(),
+            LockedBarConfig {
+                instance_width: 320,
+                instance_height: 180,
+                max_count: 4,
+            },
+        ]
+    }
+
+    fn overlaps(a: Rect, b: Rect) -> bool {
+        let (ax, ay, bx, by) = (a.x as i32, a.y as i32, b.x as i32, b.y as i32);
+        ax < bx + b.width as i32 && bx < ax + a.width as i32 && ay < by + b.height as i32 && by < ay + a.height as i32
+    }
+
+    fn inside(rect: Rect, area: Rect) -> bool {
+        rect.x >= area.x
+            && rect.y >= area.y
+            && rect.x as i32 + rect.width as i32 <= area.x as i32 + area.width as i32
+            && rect.y as i32 + rect.height as i32 <= area.y as i32 + area.height as i32
+    }
+
+    #[test]
+    fn every_layout_stays_on_screen_without_overlap() {
+        for layout in layouts() {
+            for locked_bar in locked_bars() {
+                let built = build_layout(&layout, &locked_bar);
+                for count in 0..=16 {
+                    let arrangement = built.arrange(SCREEN, count, count);
+                    assert!(arrangement.queue.len() <= count, "{layout:?} {locked_bar:?} {count}");
+                    assert!(arrangement.locked.len() <= count, "{layout:?} {locked_bar:?} {count}");
+                    let rects: Vec<Rect> = arrangement.queue.iter().chain(&arrangement.locked).copied().collect();
+                    for (index, &rect) in rects.iter().enumerate() {
+                        assert!(rect.width > 0 && rect.height > 0, "{layout:?} {locked_bar:?} {count}: {rect:?}");
+                        assert!(inside(rect, SCREEN), "{layout:?} {locked_bar:?} {count}: {rect:?}");
+                        for &other in &rects[index + 1..] {
+                            assert!(
+                                !overlaps(rect, other),
+                                "{layout:?} {locked_bar:?} {count}: {rect:?} overlaps {other:?}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn grid_without_dimensions_fits_everything() {
+        for count in 0..=16 {
+            let arrangement = LayoutConfig::Grid { rows: None, cols: None }.build().arrange(SCREEN, count, 0);
+            assert_eq!(arrangement.queue.len(), count);
+        }
+    }
+
+    #[test]
+    fn locked_bar_takes_the_bottom_of_the_screen() {
+        let bar = LockedBarConfig {
+            instance_width: 320,
+            instance_height: 180,
+            max_count: 2,
+        };
+        let arrangement = build_layout(&LayoutConfig::default(), &bar).arrange(SCREEN, 16, 3);
+        assert_eq!(
+            arrangement.locked,
+            vec![
+                Rect { x: 100, y: 950, width: 320, height: 180 },
+                Rect { x: 420, y: 950, width: 320, height: 180 },
+            ]
+        );
+        assert!(arrangement.queue.iter().all(|rect| rect.y as i32 + rect.height as i32 <= 950));
+    }
+
+    // The hard-coded wall used to divide by bags_vertical to find the row of a bag,
+    // which put the third bag of a 3x2 wall on the top row on top of the sixth one
+    #[test]
+    fn bags_fill_rows_of_bags_horizontal() {
+        let layout = LayoutConfig::BagGrid {
+            bag_size: 1,
+            bag_cols: 1,
+            bags_horizontal: 3,
+            bags_vertical: 2,
+        };
+        let screen = Rect {
+            x: 0,
+            y: 0,
+            width: 1920,
+            height: 1080,
+        };
+        let origins: Vec<(i16, i16)> =
+            layout.build().arrange(screen, 6, 0).queue.iter().map(|rect| (rect.x, rect.y)).collect();
+        assert_eq!(origins, vec![(1280, 540), (640, 540), (0, 540), (1280, 0), (640, 0), (0, 0)]);
+    }
+
+    #[test]
+    fn locked_bar_config_fills_in_missing_keys_and_rejects_unknown_ones() {
+        let bar: LockedBarConfig = toml::from_str("max_count = 3").unwrap();
+        assert_eq!(
+            bar,
+            LockedBarConfig {
+                max_count: 3,
+                ..LockedBarConfig::default()
+            }
+        );
+        assert!(toml::from_str::<LockedBarConfig>("max_cuont = 3").is_err());
+    }
+}
@@ -1,28 +1,96 @@
+use std::{collections::HashMap, path::PathBuf, sync::{atomic::{AtomicBool, Ordering::SeqCst}, Arc}, time::{Duration, Instant}};
+
+use instancemanager::{InstanceManager, SystemEffects};
 use tokio::sync::mpsc::channel;
-use x11::find_instances;
-use x11rb::{connection::Connection, protocol::{xproto::{self, ChangeWindowAttributesAux, EventMask, Keycode}, Event}};
 
-use crate::x11::grab_key;
+use clap::Parser;
+
+use crate::{backend::WindowBackend, cli::{Cli, Command}, config::{Config, ConfigWatcher, HotkeysConfig}, instance::WindowMode, recording::{Input, Recorder}, x11::{Rect, X11Backend}};
 
+mod backend;
+mod bench;
+mod cli;
+mod config;
+mod control;
+mod doctor;
+mod events;
+mod http;
 mod instance;
 // mod instancemanager;
 // mod keyboardutils;
 mod x11;
 mod instanceutils;
 mod instancemanager;
+mod layout;
+mod movingwall;
+mod obs;
+mod projector;
+mod recording;
+mod resetcounter;
+mod speedrunigt;
+mod stats;
+mod tuner;
+mod worldcleaner;
+
 #[tokio::main]
 async fn main() {
-    let (conn, screen_num) = x11rb::connect(None).unwrap();
-    let screen = &conn.setup().roots[screen_num];
-    let instances = find_instances(&conn, screen.root).unwrap();
-    xproto::change_window_attributes(
-        &conn,
-        screen.root,
-        &ChangeWindowAttributesAux::new().event_mask(EventMask::KEY_PRESS),
-    ).unwrap();
+    let cli = Cli::parse();
+    let config_path = cli.config_path();
+    let config = cli::load_config(config_path.as_deref());
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(config, config_path).await,
+        Command::List => cli::list(&config),
+        Command::Doctor => doctor::run(&config, config_path.as_deref()),
+        Command::Send { action } => cli::send(&config, &action),
+        Command::Stats => cli::stats(&config),
+        Command::Clean { dry_run } => cli::clean(&config, dry_run),
+        Command::Bench { resets, minutes, playing } => cli::bench(&config, resets, minutes, playing).await,
+        Command::Tune { minutes_per_trial, dry_run } => {
+            cli::tune(&config, config_path.as_deref(), minutes_per_trial, dry_run).await
+        }
+        Command::Replay { recording, until, events } => cli::replay(&recording, until, events.as_deref()),
+    }
+}
+
+async fn run(mut config: Config, config_path: Option<PathBuf>) {
+    let backend = Arc::new(X11Backend::connect().unwrap());
+    let mut config_watcher = config_path.map(ConfigWatcher::new);
+    let instances = backend
+        .find_instances(&config.instances.window_name, &config.instances.instance_num_regex())
+        .unwrap();
 
-    grab_key(&conn, 25, screen.root).unwrap();
+    for (_, key) in config.hotkeys.all() {
+        backend.grab_key(key).unwrap();
+    }
     println!("Found {} instances", instances.len());
+    let recorder = config.record_file.as_ref().and_then(|path| match Recorder::create(path) {
+        Ok(recorder) => {
+            println!("Recording the session to {}", path.display());
+            Some(recorder)
+        }
+        Err(err) => {
+            println!("Failed to create the recording {}: {err}", path.display());
+            None
+        }
+    });
+    let record = |input| {
+        if let Some(recorder) = &recorder {
+            recorder.record(input);
+        }
+    };
+    if recorder.is_some() {
+        let monitor = backend.primary_monitor_geometry().unwrap_or(Rect {
+            x: 0,
+            y: 0,
+            width: 1920,
+            height: 1080,
+        });
+        record(Input::Started {
+            config: Box::new(config.clone()),
+            instances: instances.clone(),
+            monitor,
+        });
+    }
 
     // for instance in instances {
     //     // print instance pid and window
@@ -34,174 +102,168 @@ async fn main() {
     // let mut hotkeys_channel = channel(100);
     // let mut wall_instances: Vec<WallFileInstance> = Vec::new();
     let mut instance_manager =
-        instancemanager::InstanceManager::initialize(preview_becomes_ready_channel.0,percent_sender.0, instances, &conn);
-    loop{
-        if let Ok(event) = conn.poll_for_event(){
-            if let Some(event) = event{
-                match event {
-                    Event::KeyPress(event) => println!("Key pressed: {}", event.detail),
-                    e => {println!("Event: {:?}", e)},
-                    // other => panic!("Unexpected event {:?}", other),
-                };
+        instancemanager::InstanceManager::initialize(preview_becomes_ready_channel.0,percent_sender.0, instances, backend.clone(), Box::new(SystemEffects::new()), recorder.clone(), &config);
+    if config.projector {
+        match projector::spawn(&instance_manager.instances) {
+            Ok(projector) => instance_manager.projector = Some(projector),
+            Err(err) => println!("Failed to open the wall projector: {err}"),
+        }
+    }
+    let mut control_channel = channel(100);
+    // Kept alive until shutdown so the socket file is removed on the way out
+    let _control_socket = config.control_socket.as_ref().and_then(|path| {
+        match control::spawn(path, control_channel.0.clone(), instance_manager.events.clone()) {
+            Ok(control_socket) => {
+                println!("Listening for commands on {}", path.display());
+                Some(control_socket)
             }
+            Err(err) => {
+                println!("Failed to open the control socket {}: {err}", path.display());
+                None
+            }
+        }
+    });
+    if let Some(address) = config.http {
+        match http::spawn(address, control_channel.0.clone(), instance_manager.events.clone()) {
+            Ok(_) => println!("Serving the overlay on http://{address}"),
+            Err(err) => println!("Failed to start the HTTP server on {address}: {err}"),
         }
     }
-    // tokio::spawn(async move {
-    //     hotkeys::setup_listeners(hotkeys_channel.0).await;
-    // });
-    // loop {
-    //     select! {
-    //         Some(percent) = percent_sender.1.recv() => {
-    //             println!("Percent: {}", percent);
-    //         },
-    //         instance_num = preview_becomes_ready_channel.1.recv() =>{
-    //             let instance_arc = instance_manager.get_instance_by_instance_num(instance_num.unwrap());
-    //             match instance_arc {
-    //                 Some(instance_arc) => {
-    //                     instance_manager.preview_unlocked_wall_queue.push(instance_arc.clone());
-    //                 }
-    //                 None => {
-    //                     panic!("Received a preview_becomes_ready_channel message for an instance that doesn't exist");
-    //                 }
-    //             }
-    //         },
-    //         Some(hotkey) = hotkeys_channel.1.recv() => {
-    //                 println!("Received hotkey: {}", hotkey);
-    //                 match hotkey.as_str() {
-    //                     "lock" => {
-    //                     let left_click = hwndutils::get_mouse_pos();
-    //                     wall_instances.iter().for_each(|wall_instance|{
-
-    //                    if (wall_instance.x < left_click.0 as usize && wall_instance.x + wall_instance.width > left_click.0 as usize) && (wall_instance.y < left_click.1 as usize && wall_instance.y + wall_instance.height > left_click.1 as usize){
-    //                        println!("Found instance: {}", wall_instance.instance_num);
-    //                        let instance_arc = instance_manager.get_instance_by_instance_num(wall_instance.instance_num);
-    //                        match instance_arc {
-    //                            Some(instance_arc) => {
-    //                                instance_manager.preview_unlocked_wall_queue.remove_by_instance_num(instance_arc.instance_num);
-    //                                instance_manager.lock(instance_arc.instance_num);
-    //                            }
-    //                            None => {
-    //                                println!("Received a lock message for an instance that doesn't exist");
-    //                            }
-    //                        }
-    //                    }
-    //                });
-    //                     },
-
-    //                     "reset_bag" => {
-    //                         println!("Resetting all instances");
-    //                         if instance_manager.preview_unlocked_wall_queue.can_pop() {
-    //                             instance_manager.reset_wall_bag();
-    //                         }
-    //                         if !instance_manager.preview_unlocked_wall_queue.can_pop() {
-    //                             match instance_manager.get_first_idle_locked_instance(){
-    //                                 Some(instance_arc) => {
-    //                                     instance_manager.locked_instances.retain(|instance| instance.instance_num != instance_arc.instance_num);
-    //                                     instance_arc.set_affinity(((1 << 28) - 1) << 4);
-
-    //                                     let mut file = File::create(r#"C:\Users\Boyen\sleepbg.lock"#).unwrap();
-    //                                     file.write_all(b"").unwrap();
-    //                                     instance_arc.play();
-    //                                 }
-    //                                 None => {
-    //                                     println!("No idle instances to play");
-    //                                 }
-    //                             }
-    //                         }
-
-    //                     },
-    //                     "exit_instance"=>{
-    //                         // Exit the currently playing instance
-    //                         match instance_manager.get_playing_instance(){
-    //                             Some(instance_arc) => {
-    //                                 fs::remove_file(r#"C:\Users\Boyen\sleepbg.lock"#).unwrap();
-    //                                 let clone = instance_arc.clone();
-    //                                 clone.state.store(instance::InstanceState::Idle,std::sync::atomic::Ordering::SeqCst);
-    //                                 wall_instances = write_wall_queue_to_json_file(
-    //                                     &instance_manager.preview_unlocked_wall_queue,
-    //                                     &instance_manager.instances,
-    //                                 );
-    //                                 println!("Exiting instance: {}", clone.instance_num);
-    //                                 clone.exit();
-    //                                 loop{
-    //                                     if !is_full_screen(clone.hwnd) {
-    //                                         break;
-    //                                     }
-    //                                     thread::sleep(std::time::Duration::from_millis(5));
-
-    //                                 }
-    //                                 activate_hwnd(get_wall_hwnd());
-    //                                 instance_manager.reset_instance(instance_arc.clone());
-
-    //                             }
-    //                             None => {
-    //                                 println!("No playing instances to exit");
-    //                             }
-    //                         }
-    //                     }
-    //                     "toggle_thin"=>{
-    //                         // Thin macro
-    //                         match instance_manager.get_playing_instance(){
-    //                             Some(instance_arc) => {
-    //                                 let clone = instance_arc.clone();
-    //                                 clone.thin();
-    //                             },
-    //                             None => {
-    //                                 println!("No playing instances to make thin");
-    //                             }
-    //                         }
-    //                     },
-    //                     _
-    //         "preview_unlocked_wall_queue: {}",
-    //         instance_manager.preview_unlocked_wall_queue.len()
-    //     );
-    //     wall_instances = write_wall_queue_to_json_file(
-    //         &instance_manager.preview_unlocked_wall_queue,
-    //         &instance_manager.instances,
-    //     );
-    // }
+    let gamedirs: HashMap<u32, PathBuf> = instance_manager
+        .instances
+        .iter()
+        .map(|instance| (instance.instance_info.instance_num, PathBuf::from(&instance.instance_info.gamedir)))
+        .collect();
+    if let Some(path) = &config.stats_file {
+        stats::spawn_recorder(
+            &instance_manager.events,
+            path.clone(),
+            gamedirs.clone(),
+            config.speedrunigt.records_dir.clone(),
+        );
+    }
+    if config.world_cleanup.enabled {
+        worldcleaner::spawn(config.world_cleanup.clone(), gamedirs, config.stats_file.clone());
+    }
+    if let Some(path) = &config.event_log {
+        events::spawn_log_writer(&instance_manager.events, path.clone());
+    }
+    instance_manager.update_wall();
+    record(Input::WallUpdated);
+    let mut last_crash_check = Instant::now();
+    let running = Arc::new(AtomicBool::new(true));
+    tokio::spawn({
+        let running = running.clone();
+        async move {
+            tokio::signal::ctrl_c().await.unwrap();
+            running.store(false, SeqCst);
+        }
+    });
+    while running.load(SeqCst) {
+        let mut wall_changed = false;
+        let mut commands_handled = false;
+        while let Ok(instance_num) = preview_becomes_ready_channel.1.try_recv() {
+            record(Input::PreviewReady { instance_num });
+            instance_manager.on_preview_ready(instance_num);
+            wall_changed = true;
+        }
+        while let Ok(percent) = percent_sender.1.try_recv() {
+            println!("Percent: {}", percent);
+            wall_changed = true;
+        }
+        if let Some(new_config) = config_watcher.as_mut().and_then(ConfigWatcher::poll) {
+            println!("Reloaded the config");
+            record(Input::ConfigReloaded {
+                config: Box::new(new_config.clone()),
+            });
+            if config.needs_restart(&new_config) {
+                println!("Some of the changes only take effect after restarting rulti");
+            }
+            if new_config.hotkeys != config.hotkeys {
+                regrab_hotkeys(&*backend, &config.hotkeys, &new_config.hotkeys);
+            }
+            instance_manager.apply_config(&new_config);
+            config = new_config;
+            wall_changed = true;
+        }
+        if last_crash_check.elapsed() >= config.timing.crash_check_interval() {
+            last_crash_check = Instant::now();
+            let crashed = instance_manager.check_for_crashes();
+            for &instance_num in &crashed {
+                record(Input::Crashed { instance_num });
+            }
+            wall_changed |= !crashed.is_empty();
+        }
+        while let Ok(request) = control_channel.1.try_recv() {
+            println!("Received command: {:?}", request.command);
+            record(Input::Command {
+                command: request.command.to_string(),
+            });
+            let reply = control::handle_command(&mut instance_manager, request.command);
+            let _ = request.reply_sender.send(reply);
+            commands_handled = true;
+        }
+        if let Some(key) = backend.poll_hotkey() {
+            if recorder.is_some() {
+                let pointer = backend.pointer_position().unwrap_or_default();
+                record(Input::Hotkey { key, pointer });
+            }
+            match config.hotkeys.action(key) {
+                Some(hotkey) => {
+                    println!("Received hotkey: {}", hotkey);
+                    handle_hotkey(&mut instance_manager, hotkey);
+                    wall_changed = true;
+                }
+                None => println!("Key pressed: {}", key),
+            }
+        }
+        if wall_changed {
+            instance_manager.update_wall();
+            record(Input::WallUpdated);
+        }
+        // Playing, locking and resetting all move instances between the CPU sets
+        if wall_changed || commands_handled {
+            instance_manager.update_affinities();
+        }
+        // Everything above is polled, so give the reset tasks the core in between
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    println!("Shutting down");
+    instance_manager.shutdown();
 }
 
-// #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
-// async fn it_works() {
-//     let mut preview_becomes_ready_channel = channel(100);
-//     let mut percent_sender = channel(100);
-
-//     let mut instance_manager = instancemanager::InstanceManager::initialize(
-//         preview_becomes_ready_channel.0,
-//         percent_sender.0,
-//     );
-
-//     for _ in 1..100 {
-//         let start_time = std::time::Instant::now();
-//         let mut successful_bag_resets: u32 = 0;
-
-//         loop {
-//             match preview_becomes_ready_channel.1.try_recv().map(|instance_num|{
-//             let instance_arc = instance_manager.get_instance_by_instance_num(instance_num);
-//             match instance_arc {
-//                 Some(instance_arc) => {
-//                     instance_manager.preview_unlocked_wall_queue.push(instance_arc.clone());
-//                 }
-//                 None => {
-//                     panic!("Received a preview_becomes_ready_channel message for an instance that doesn't exist");
-//                 }
-//             }
-//         }){
-//             Ok(_) => {},
-//             Err(_) => {}
-//         }
+fn regrab_hotkeys(backend: &dyn WindowBackend, old: &HotkeysConfig, new: &HotkeysConfig) {
+    for (_, key) in old.all() {
+        if let Err(err) = backend.ungrab_key(key) {
+            println!("Failed to release key {key}: {err}");
+        }
+    }
+    for (hotkey, key) in new.all() {
+        if let Err(err) = backend.grab_key(key) {
+            println!("Failed to grab key {key} for {hotkey}: {err}");
+        }
+    }
+}
 
-//             if instance_manager.preview_unlocked_wall_queue.can_pop() {
-//                 instance_manager.reset_wall_bag();
-//                 successful_bag_resets += 1;
-//             }
-//             if (successful_bag_resets == 100) {
-//                 break;
-//             }
-//             instance_manager.update_affinities();
-//         }
-//         let end_time = std::time::Instant::now();
-//         println!("Time taken: {:?}", end_time.duration_since(start_time));
-//     }
-// }
+fn handle_hotkey(instance_manager: &mut InstanceManager, hotkey: &str) {
+    let on_wall = instance_manager.get_playing_instance().is_none();
+    let result = match hotkey {
+        "reset_bag" if on_wall => instance_manager.reset_bag(),
+        "exit_instance" => instance_manager.exit_instance(),
+        "lock_or_thin" if on_wall => {
+            match instance_manager.backend.pointer_position() {
+                Ok((x, y)) => instance_manager.lock_at(x, y),
+                Err(err) => println!("Failed to query the mouse position: {err}"),
+            }
+            Ok(())
+        }
+        "lock_or_thin" => instance_manager.toggle_window_mode(WindowMode::Thin),
+        "toggle_tall" => instance_manager.toggle_window_mode(WindowMode::Tall),
+        "toggle_wide" => instance_manager.toggle_window_mode(WindowMode::Wide),
+        "cycle_window_mode" => instance_manager.cycle_window_mode(),
+        _ => Ok(()),
+    };
+    if let Err(err) = result {
+        println!("Failed to handle hotkey {hotkey}: {err}");
+    }
+}
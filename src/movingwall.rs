@@ -0,0 +1,34 @@
+use std::sync::{atomic::Ordering::SeqCst, Arc};
+
+use crate::{
+    instance::{Instance, InstanceState},
+    instancemanager::WallFileInstance,
+    x11::Rect,
+};
+
+/// Moves the real instance windows into the rectangles of the wall layout, so the wall can be played without an OBS projector.
+pub fn arrange_windows(instances: &[Arc<Instance>], wall_instances: &[WallFileInstance]) {
+    for wall_instance in wall_instances {
+        let instance = instances
+            .iter()
+            .find(|instance| instance.instance_info.instance_num == wall_instance.instance_num);
+        let Some(instance) = instance else {
+            continue;
+        };
+        if instance.state.load(SeqCst) == InstanceState::Playing {
+            continue;
+        }
+
+        let rect = Rect {
+            x: wall_instance.x as i16,
+            y: wall_instance.y as i16,
+            width: wall_instance.width as u16,
+            height: wall_instance.height as u16,
+        };
+        // Instances that aren't on the wall are parked off-screen, shrinking them would change their preview
+        let resize = !wall_instance.is_hidden();
+        if let Err(err) = instance.move_to(rect, resize) {
+            println!("Failed to move instance {}: {err}", wall_instance.instance_num);
+        }
+    }
+}
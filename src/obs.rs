@@ -0,0 +1,482 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::TcpStream,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+use crate::instancemanager::WallFileInstance;
+
+const RPC_VERSION: u32 = 1;
+
+const OP_HELLO: u64 = 0;
+const OP_IDENTIFY: u64 = 1;
+const OP_IDENTIFIED: u64 = 2;
+const OP_REQUEST: u64 = 6;
+const OP_REQUEST_RESPONSE: u64 = 7;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ObsConfig {
+    /// host:port of obs-websocket
+    pub address: String,
+    pub password: Option<String>,
+    pub wall_scene: String,
+    /// `{}` is replaced with the instance number, so every instance can have its own play scene
+    pub play_scene: String,
+    /// Name of the source showing an instance on the wall scene, `{}` is replaced with the instance number
+    pub source_name: String,
+}
+
+impl Default for ObsConfig {
+    fn default() -> Self {
+        Self {
+            address: "localhost:4455".into(),
+            password: None,
+            wall_scene: "Wall".into(),
+            play_scene: "Playing".into(),
+            source_name: "mc {}".into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ObsError {
+    WebSocket(Box<tungstenite::Error>),
+    Json(serde_json::Error),
+    Protocol(String),
+    Request { request_type: String, code: u64, comment: String },
+}
+
+impl fmt::Display for ObsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObsError::WebSocket(e) => write!(f, "WebSocket error: {}", e),
+            ObsError::Json(e) => write!(f, "Invalid message from obs-websocket: {}", e),
+            ObsError::Protocol(message) => write!(f, "obs-websocket protocol error: {}", message),
+            ObsError::Request {
+                request_type,
+                code,
+                comment,
+            } => write!(f, "{} failed with code {}: {}", request_type, code, comment),
+        }
+    }
+}
+
+impl From<tungstenite::Error> for ObsError {
+    fn from(e: tungstenite::Error) -> Self {
+        ObsError::WebSocket(Box::new(e))
+    }
+}
+
+impl From<serde_json::Error> for ObsError {
+    fn from(e: serde_json::Error) -> Self {
+        ObsError::Json(e)
+    }
+}
+
+/// Blocking obs-websocket v5 client.
+pub struct ObsClient {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    next_request_id: u64,
+    scene_item_ids: HashMap<(String, String), i64>,
+}
+
+impl ObsClient {
+    pub fn connect(address: &str, password: Option<&str>) -> Result<Self, ObsError> {
+        let (socket, _) = tungstenite::connect(format!("ws://{address}"))?;
+        let mut client = Self {
+            socket,
+            next_request_id: 0,
+            scene_item_ids: HashMap::new(),
+        };
+
+        let hello = client.receive(OP_HELLO)?;
+        let mut identify = json!({ "rpcVersion": RPC_VERSION, "eventSubscriptions": 0 });
+        if let Some(authentication) = hello.get("authentication") {
+            let (Some(challenge), Some(salt)) = (
+                authentication["challenge"].as_str(),
+                authentication["salt"].as_str(),
+            ) else {
+                return Err(ObsError::Protocol("Hello has an incomplete authentication challenge".into()));
+            };
+            let Some(password) = password else {
+                return Err(ObsError::Protocol("obs-websocket requires a password".into()));
+            };
+            identify["authentication"] = authentication_string(password, salt, challenge).into();
+        }
+        client.send(OP_IDENTIFY, identify)?;
+        client.receive(OP_IDENTIFIED)?;
+        Ok(client)
+    }
+
+    pub fn request(&mut self, request_type: &str, request_data: Value) -> Result<Value, ObsError> {
+        self.next_request_id += 1;
+        let request_id = self.next_request_id.to_string();
+        self.send(
+            OP_REQUEST,
+            json!({
+                "requestType": request_type,
+                "requestId": request_id,
+                "requestData": request_data,
+            }),
+        )?;
+
+        loop {
+            let response = self.receive(OP_REQUEST_RESPONSE)?;
+            if response["requestId"] != request_id.as_str() {
+                continue;
+            }
+            let status = &response["requestStatus"];
+            if status["result"].as_bool() != Some(true) {
+                return Err(ObsError::Request {
+                    request_type: request_type.into(),
+                    code: status["code"].as_u64().unwrap_or_default(),
+                    comment: status["comment"].as_str().unwrap_or_default().into(),
+                });
+            }
+            return Ok(response["responseData"].clone());
+        }
+    }
+
+    pub fn scene_item_id(&mut self, scene_name: &str, source_name: &str) -> Result<i64, ObsError> {
+        let key = (scene_name.to_string(), source_name.to_string());
+        if let Some(scene_item_id) = self.scene_item_ids.get(&key) {
+            return Ok(*scene_item_id);
+        }
+        let response = self.request(
+            "GetSceneItemId",
+            json!({ "sceneName": scene_name, "sourceName": source_name }),
+        )?;
+        let Some(scene_item_id) = response["sceneItemId"].as_i64() else {
+            return Err(ObsError::Protocol("GetSceneItemId response has no sceneItemId".into()));
+        };
+        self.scene_item_ids.insert(key, scene_item_id);
+        Ok(scene_item_id)
+    }
+
+    /// Moves and stretches the scene item to the rectangle, and hides it when it isn't on the wall.
+    pub fn apply_wall_instance(
+        &mut self,
+        scene_name: &str,
+        source_name: &str,
+        wall_instance: &WallFileInstance,
+    ) -> Result<(), ObsError> {
+        let scene_item_id = self.scene_item_id(scene_name, source_name)?;
+        let visible = !wall_instance.is_hidden();
+        if visible {
+            self.request(
+                "SetSceneItemTransform",
+                json!({
+                    "sceneName": scene_name,
+                    "sceneItemId": scene_item_id,
+                    "sceneItemTransform": {
+                        "positionX": wall_instance.x,
+                        "positionY": wall_instance.y,
+                        "boundsType": "OBS_BOUNDS_STRETCH",
+                        "boundsWidth": wall_instance.width,
+                        "boundsHeight": wall_instance.height,
+                    },
+                }),
+            )?;
+        }
+        self.request(
+            "SetSceneItemEnabled",
+            json!({
+                "sceneName": scene_name,
+                "sceneItemId": scene_item_id,
+                "sceneItemEnabled": visible,
+            }),
+        )?;
+        Ok(())
+    }
+
+    pub fn set_current_scene(&mut self, scene_name: &str) -> Result<(), ObsError> {
+        self.request("SetCurrentProgramScene", json!({ "sceneName": scene_name }))?;
+        Ok(())
+    }
+
+    fn send(&mut self, op: u64, d: Value) -> Result<(), ObsError> {
+        let message = json!({ "op": op, "d": d }).to_string();
+        self.socket.send(Message::Text(message))?;
+        Ok(())
+    }
+
+    /// Waits for the next message with the given opcode, skipping events and anything else in between.
+    fn receive(&mut self, op: u64) -> Result<Value, ObsError> {
+        loop {
+            let text = match self.socket.read()? {
+                Message::Text(text) => text,
+                Message::Close(_) => {
+                    return Err(ObsError::Protocol("obs-websocket closed the connection".into()))
+                }
+                _ => continue,
+            };
+            let mut message: Value = serde_json::from_str(&text)?;
+            if message["op"].as_u64() == Some(op) {
+                return Ok(message["d"].take());
+            }
+        }
+    }
+}
+
+/// `base64(sha256(base64(sha256(password + salt)) + challenge))`
+pub fn authentication_string(password: &str, salt: &str, challenge: &str) -> String {
+    let secret = BASE64.encode(Sha256::digest(format!("{password}{salt}")));
+    BASE64.encode(Sha256::digest(format!("{secret}{challenge}")))
+}
+
+pub enum ObsCommand {
+    Layout(Vec<WallFileInstance>),
+    Play(u32),
+    Exit,
+}
+
+/// Handle to the thread that keeps OBS in sync with the wall.
+pub struct ObsHandle {
+    sender: mpsc::Sender<ObsCommand>,
+}
+
+impl ObsHandle {
+    pub fn send(&self, command: ObsCommand) {
+        if self.sender.send(command).is_err() {
+            println!("OBS thread has stopped");
+        }
+    }
+}
+
+pub fn spawn(config: ObsConfig) -> ObsHandle {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || run(config, receiver));
+    ObsHandle { sender }
+}
+
+fn run(config: ObsConfig, receiver: Receiver<ObsCommand>) {
+    let mut client: Option<ObsClient> = None;
+    let mut applied: HashMap<u32, WallFileInstance> = HashMap::new();
+
+    while let Ok(mut command) = receiver.recv() {
+        // Only the newest layout matters when several are queued up
+        while let Ok(next) = receiver.try_recv() {
+            match (&command, &next) {
+                (ObsCommand::Layout(_), ObsCommand::Layout(_)) => command = next,
+                _ => {
+                    handle_command(&config, &mut client, &mut applied, command);
+                    command = next;
+                }
+            }
+        }
+        handle_command(&config, &mut client, &mut applied, command);
+    }
+}
+
+fn handle_command(
+    config: &ObsConfig,
+    client: &mut Option<ObsClient>,
+    applied: &mut HashMap<u32, WallFileInstance>,
+    command: ObsCommand,
+) {
+    if client.is_none() {
+        match ObsClient::connect(&config.address, config.password.as_deref()) {
+            Ok(connected) => {
+                println!("Connected to obs-websocket at {}", config.address);
+                applied.clear();
+                *client = Some(connected);
+            }
+            Err(err) => {
+                println!("Failed to connect to obs-websocket: {err}");
+                return;
+            }
+        }
+    }
+    let connected = client.as_mut().unwrap();
+
+    let result = match command {
+        ObsCommand::Layout(wall_instances) => wall_instances.iter().try_for_each(|wall_instance| {
+            if applied.get(&wall_instance.instance_num) == Some(wall_instance) {
+                return Ok(());
+            }
+            let source_name = config
+                .source_name
+                .replace("{}", &wall_instance.instance_num.to_string());
+            match connected.apply_wall_instance(&config.wall_scene, &source_name, wall_instance) {
+                Ok(()) => {
+                    applied.insert(wall_instance.instance_num, wall_instance.clone());
+                }
+                // One missing source shouldn't keep the other instances from moving
+                Err(err @ ObsError::Request { .. }) => println!("OBS request for {source_name} failed: {err}"),
+                Err(err) => return Err(err),
+            }
+            Ok(())
+        }),
+        ObsCommand::Play(instance_num) => {
+            connected.set_current_scene(&config.play_scene.replace("{}", &instance_num.to_string()))
+        }
+        ObsCommand::Exit => connected.set_current_scene(&config.wall_scene),
+    };
+
+    match result {
+        Ok(_) => {}
+        // A missing source or scene is a setup problem, the connection itself is still fine
+        Err(err @ ObsError::Request { .. }) => println!("OBS request failed: {err}"),
+        Err(err) => {
+            println!("Lost connection to obs-websocket: {err}");
+            *client = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::mpsc::Sender};
+
+    use super::*;
+
+    const PASSWORD: &str = "hunter2";
+    const SALT: &str = "lM1GncleQOaCu9lT1yeUZhFYnqhsLLP1G5lAGo3ixaI=";
+    const CHALLENGE: &str = "+IxH4CnCiqpX1rM9scsNynZzbOe4KhDeYcTNS3PDaeY=";
+
+    /// Answers like obs-websocket, except that `mc 2` doesn't exist. Sends every request it gets to `requests`.
+    fn serve(listener: TcpListener, requests: Sender<(String, Value)>) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut socket = tungstenite::accept(stream).unwrap();
+        let hello = json!({
+            "op": OP_HELLO,
+            "d": {
+                "obsWebSocketVersion": "5.0.0",
+                "rpcVersion": RPC_VERSION,
+                "authentication": { "challenge": CHALLENGE, "salt": SALT },
+            },
+        });
+        socket.send(Message::Text(hello.to_string())).unwrap();
+
+        let identify: Value = serde_json::from_str(&socket.read().unwrap().into_text().unwrap()).unwrap();
+        assert_eq!(identify["op"], OP_IDENTIFY);
+        assert_eq!(identify["d"]["rpcVersion"], RPC_VERSION);
+        assert_eq!(identify["d"]["authentication"], authentication_string(PASSWORD, SALT, CHALLENGE));
+        let identified = json!({ "op": OP_IDENTIFIED, "d": { "negotiatedRpcVersion": RPC_VERSION } });
+        socket.send(Message::Text(identified.to_string())).unwrap();
+
+        while let Ok(Message::Text(text)) = socket.read() {
+            let message: Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(message["op"], OP_REQUEST);
+            let request = &message["d"];
+            let request_type = request["requestType"].as_str().unwrap().to_string();
+            let data = request["requestData"].clone();
+            let (status, response_data) = match (request_type.as_str(), data["sourceName"].as_str()) {
+                ("GetSceneItemId", Some("mc 2")) => (json!({ "result": false, "code": 600, "comment": "No source" }), json!({})),
+                ("GetSceneItemId", Some(source_name)) => {
+                    let scene_item_id: i64 = source_name.trim_start_matches("mc ").parse::<i64>().unwrap() * 10;
+                    (json!({ "result": true, "code": 100 }), json!({ "sceneItemId": scene_item_id }))
+                }
+                _ => (json!({ "result": true, "code": 100 }), json!({})),
+            };
+            // Events are interleaved with the responses and have to be skipped
+            let event = json!({ "op": 5, "d": { "eventType": "SceneItemSelected", "eventIntent": 128 } });
+            socket.send(Message::Text(event.to_string())).unwrap();
+            let response = json!({
+                "op": OP_REQUEST_RESPONSE,
+                "d": {
+                    "requestType": request_type,
+                    "requestId": request["requestId"],
+                    "requestStatus": status,
+                    "responseData": response_data,
+                },
+            });
+            socket.send(Message::Text(response.to_string())).unwrap();
+            requests.send((request_type, data)).unwrap();
+        }
+    }
+
+    fn wall_instance(instance_num: u32, x: usize, y: usize, width: usize, height: usize) -> WallFileInstance {
+        serde_json::from_value(json!({
+            "instance_num": instance_num,
+            "width": width,
+            "height": height,
+            "x": x,
+            "y": y,
+            "playing": false,
+            "freeze": false,
+            "state": "Preview",
+            "locked": false,
+            "preview_percent": 0,
+            "bag_index": null,
+            "gamedir": "",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn authenticates_and_drives_the_scenes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = ObsConfig {
+            address: listener.local_addr().unwrap().to_string(),
+            password: Some(PASSWORD.into()),
+            play_scene: "Playing {}".into(),
+            ..ObsConfig::default()
+        };
+        let (request_sender, requests) = mpsc::channel();
+        let server = thread::spawn(move || serve(listener, request_sender));
+
+        let mut client = None;
+        let mut applied = HashMap::new();
+        let layout = vec![
+            wall_instance(1, 0, 0, 960, 540),
+            wall_instance(2, 960, 0, 960, 540),
+            wall_instance(3, 0, 0, 1, 1),
+        ];
+        handle_command(&config, &mut client, &mut applied, ObsCommand::Layout(layout.clone()));
+        assert!(client.is_some(), "a missing source must not drop the connection");
+        // Instances that didn't change aren't sent again, the failed one is retried
+        handle_command(&config, &mut client, &mut applied, ObsCommand::Layout(layout));
+        handle_command(&config, &mut client, &mut applied, ObsCommand::Play(3));
+        handle_command(&config, &mut client, &mut applied, ObsCommand::Exit);
+        drop(client);
+        server.join().unwrap();
+
+        let requests: Vec<(String, Value)> = requests.iter().collect();
+        let expected = vec![
+            ("GetSceneItemId", json!({ "sceneName": "Wall", "sourceName": "mc 1" })),
+            (
+                "SetSceneItemTransform",
+                json!({
+                    "sceneName": "Wall",
+                    "sceneItemId": 10,
+                    "sceneItemTransform": {
+                        "positionX": 0,
+                        "positionY": 0,
+                        "boundsType": "OBS_BOUNDS_STRETCH",
+                        "boundsWidth": 960,
+                        "boundsHeight": 540,
+                    },
+                }),
+            ),
+            ("SetSceneItemEnabled", json!({ "sceneName": "Wall", "sceneItemId": 10, "sceneItemEnabled": true })),
+            ("GetSceneItemId", json!({ "sceneName": "Wall", "sourceName": "mc 2" })),
+            ("GetSceneItemId", json!({ "sceneName": "Wall", "sourceName": "mc 3" })),
+            ("SetSceneItemEnabled", json!({ "sceneName": "Wall", "sceneItemId": 30, "sceneItemEnabled": false })),
+            ("GetSceneItemId", json!({ "sceneName": "Wall", "sourceName": "mc 2" })),
+            ("SetCurrentProgramScene", json!({ "sceneName": "Playing 3" })),
+            ("SetCurrentProgramScene", json!({ "sceneName": "Wall" })),
+        ];
+        let expected: Vec<(String, Value)> =
+            expected.into_iter().map(|(request_type, data)| (request_type.to_string(), data)).collect();
+        assert_eq!(requests, expected);
+    }
+
+    #[test]
+    fn authentication_string_matches_the_protocol_example() {
+        // From the obs-websocket protocol documentation
+        assert_eq!(
+            authentication_string("supersecretpassword", SALT, CHALLENGE),
+            "1Ct943GAT+6YQUUX47Ia/ncufilbe6+oD6lY+5kaCu4="
+        );
+    }
+}
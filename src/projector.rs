@@ -0,0 +1,320 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, Receiver, TryRecvError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        composite::{ConnectionExt as _, Redirect},
+        damage::{ConnectionExt as _, ReportLevel},
+        render::{ConnectionExt as _, Color, CreatePictureAux, PictOp, Pictformat, Picture, Transform},
+        xproto::{
+            AtomEnum, ConnectionExt as _, CreateWindowAux, EventMask, PropMode, Rectangle,
+            SubwindowMode, Visualid, Window, WindowClass,
+        },
+        Event,
+    },
+    rust_connection::RustConnection,
+    wrapper::ConnectionExt as _,
+    NONE,
+};
+
+use crate::{instance::Instance, instancemanager::WallFileInstance, x11::{Rect, WindowError}};
+
+/// Contains "Fullscreen Projector" so the exit flow focuses it like an OBS projector
+const PROJECTOR_TITLE: &str = "rulti Fullscreen Projector";
+
+/// Handle to the native wall projector, which draws scaled thumbnails of the instance windows on its own thread.
+pub struct WallProjector {
+    layout_sender: mpsc::Sender<Vec<WallFileInstance>>,
+}
+
+impl WallProjector {
+    pub fn update_layout(&self, wall_instances: Vec<WallFileInstance>) {
+        if self.layout_sender.send(wall_instances).is_err() {
+            println!("Wall projector has stopped, not updating its layout");
+        }
+    }
+}
+
+struct Thumbnail {
+    picture: Picture,
+    source_width: u16,
+    source_height: u16,
+    dest: Option<Rect>,
+    dirty: bool,
+}
+
+struct Projector {
+    conn: RustConnection,
+    window: Window,
+    picture: Picture,
+    width: u16,
+    height: u16,
+    // Keyed by the damage object, so damage events can be matched without a lookup by window
+    thumbnails: HashMap<u32, (u32, Thumbnail)>,
+}
+
+/// Opens the projector window and redirects every instance window into offscreen storage.
+pub fn spawn(instances: &[Arc<Instance>]) -> Result<WallProjector, WindowError> {
+    let projector = Projector::open(instances)?;
+    let (layout_sender, layout_receiver) = mpsc::channel();
+    thread::spawn(move || {
+        if let Err(err) = projector.run(layout_receiver) {
+            println!("Wall projector stopped: {err}");
+        }
+    });
+    Ok(WallProjector { layout_sender })
+}
+
+impl Projector {
+    fn open(instances: &[Arc<Instance>]) -> Result<Self, WindowError> {
+        let (conn, screen_num) = x11rb::connect(None)?;
+        // The extensions have to be told which version we speak before they can be used
+        conn.composite_query_version(0, 4)?.reply()?;
+        conn.damage_query_version(1, 1)?.reply()?;
+        conn.render_query_version(0, 11)?.reply()?;
+
+        let screen = &conn.setup().roots[screen_num];
+        let (root, root_visual) = (screen.root, screen.root_visual);
+        let (width, height) = (screen.width_in_pixels, screen.height_in_pixels);
+
+        let window = conn.generate_id()?;
+        conn.create_window(
+            x11rb::COPY_DEPTH_FROM_PARENT,
+            window,
+            root,
+            0,
+            0,
+            width,
+            height,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            root_visual,
+            &CreateWindowAux::new()
+                .background_pixel(screen.black_pixel)
+                .event_mask(EventMask::EXPOSURE | EventMask::STRUCTURE_NOTIFY),
+        )?
+        .check()?;
+        conn.change_property8(
+            PropMode::REPLACE,
+            window,
+            AtomEnum::WM_NAME,
+            AtomEnum::STRING,
+            PROJECTOR_TITLE.as_bytes(),
+        )?;
+        let wm_state = conn.intern_atom(false, b"_NET_WM_STATE")?.reply()?.atom;
+        let wm_state_fullscreen = conn
+            .intern_atom(false, b"_NET_WM_STATE_FULLSCREEN")?
+            .reply()?
+            .atom;
+        conn.change_property32(
+            PropMode::REPLACE,
+            window,
+            wm_state,
+            AtomEnum::ATOM,
+            &[wm_state_fullscreen],
+        )?;
+        conn.map_window(window)?;
+
+        let picture = conn.generate_id()?;
+        let format = find_visual_format(&conn, root_visual)?;
+        conn.render_create_picture(picture, window, format, &Default::default())?
+            .check()?;
+
+        let mut thumbnails = HashMap::new();
+        for instance in instances {
+            let instance_window = instance.instance_info.window;
+            conn.composite_redirect_window(instance_window, Redirect::AUTOMATIC)?
+                .check()?;
+
+            let visual = conn.get_window_attributes(instance_window)?.reply()?.visual;
+            let format = find_visual_format(&conn, visual)?;
+            let instance_picture = conn.generate_id()?;
+            conn.render_create_picture(
+                instance_picture,
+                instance_window,
+                format,
+                &CreatePictureAux::new()
+                    .subwindowmode(SubwindowMode::INCLUDE_INFERIORS),
+            )?
+            .check()?;
+            conn.render_set_picture_filter(instance_picture, b"bilinear", &[])?;
+
+            let damage = conn.generate_id()?;
+            conn.damage_create(damage, instance_window, ReportLevel::NON_EMPTY)?
+                .check()?;
+
+            let geometry = conn.get_geometry(instance_window)?.reply()?;
+            thumbnails.insert(
+                damage,
+                (
+                    instance.instance_info.instance_num,
+                    Thumbnail {
+                        picture: instance_picture,
+                        source_width: geometry.width,
+                        source_height: geometry.height,
+                        dest: None,
+                        dirty: false,
+                    },
+                ),
+            );
+        }
+        conn.flush()?;
+
+        Ok(Self {
+            conn,
+            window,
+            picture,
+            width,
+            height,
+            thumbnails,
+        })
+    }
+
+    fn run(mut self, layout_receiver: Receiver<Vec<WallFileInstance>>) -> Result<(), WindowError> {
+        loop {
+            match layout_receiver.try_recv() {
+                Ok(wall_instances) => {
+                    self.set_layout(&wall_instances);
+                    self.redraw()?;
+                }
+                Err(TryRecvError::Disconnected) => return Ok(()),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            while let Some(event) = self.conn.poll_for_event()? {
+                match event {
+                    Event::Expose(event) if event.count == 0 => self.redraw()?,
+                    Event::ConfigureNotify(event) if event.window == self.window => {
+                        self.width = event.width;
+                        self.height = event.height;
+                    }
+                    Event::DamageNotify(event) => {
+                        self.conn.damage_subtract(event.damage, NONE, NONE)?;
+                        if let Some((_, thumbnail)) = self.thumbnails.get_mut(&event.damage) {
+                            thumbnail.source_width = event.geometry.width;
+                            thumbnail.source_height = event.geometry.height;
+                            thumbnail.dirty = true;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            for (_, thumbnail) in self.thumbnails.values_mut() {
+                if thumbnail.dirty {
+                    thumbnail.dirty = false;
+                    draw_thumbnail(&self.conn, self.picture, thumbnail)?;
+                }
+            }
+            self.conn.flush()?;
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn set_layout(&mut self, wall_instances: &[WallFileInstance]) {
+        for (instance_num, thumbnail) in self.thumbnails.values_mut() {
+            thumbnail.dest = wall_instances
+                .iter()
+                .find(|wall_instance| wall_instance.instance_num == *instance_num)
+                .filter(|wall_instance| {
+                    !wall_instance.is_hidden()
+                        && wall_instance.x < self.width as usize
+                        && wall_instance.y < self.height as usize
+                })
+                .map(|wall_instance| Rect {
+                    x: wall_instance.x as i16,
+                    y: wall_instance.y as i16,
+                    width: wall_instance.width as u16,
+                    height: wall_instance.height as u16,
+                });
+        }
+    }
+
+    fn redraw(&mut self) -> Result<(), WindowError> {
+        let black = Color {
+            red: 0,
+            green: 0,
+            blue: 0,
+            alpha: 0xffff,
+        };
+        let background = Rectangle {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        };
+        self.conn
+            .render_fill_rectangles(PictOp::SRC, self.picture, black, &[background])?;
+        for (_, thumbnail) in self.thumbnails.values_mut() {
+            thumbnail.dirty = false;
+            draw_thumbnail(&self.conn, self.picture, thumbnail)?;
+        }
+        self.conn.flush()?;
+        Ok(())
+    }
+}
+
+fn draw_thumbnail(conn: &RustConnection, destination: Picture, thumbnail: &Thumbnail) -> Result<(), WindowError> {
+    let Some(dest) = thumbnail.dest else {
+        return Ok(());
+    };
+    if dest.width == 0 || dest.height == 0 {
+        return Ok(());
+    }
+    // The transform maps destination pixels back onto the source, so it scales by source / destination
+    let scale_x = thumbnail.source_width as f64 / dest.width as f64;
+    let scale_y = thumbnail.source_height as f64 / dest.height as f64;
+    conn.render_set_picture_transform(
+        thumbnail.picture,
+        Transform {
+            matrix11: to_fixed(scale_x),
+            matrix12: 0,
+            matrix13: 0,
+            matrix21: 0,
+            matrix22: to_fixed(scale_y),
+            matrix23: 0,
+            matrix31: 0,
+            matrix32: 0,
+            matrix33: to_fixed(1.0),
+        },
+    )?;
+    conn.render_composite(
+        PictOp::SRC,
+        thumbnail.picture,
+        NONE,
+        destination,
+        0,
+        0,
+        0,
+        0,
+        dest.x,
+        dest.y,
+        dest.width,
+        dest.height,
+    )?;
+    Ok(())
+}
+
+fn to_fixed(value: f64) -> i32 {
+    (value * 65536.0) as i32
+}
+
+fn find_visual_format(conn: &RustConnection, visual: Visualid) -> Result<Pictformat, WindowError> {
+    let formats = conn.render_query_pict_formats()?.reply()?;
+    let format = formats
+        .screens
+        .iter()
+        .flat_map(|screen| &screen.depths)
+        .flat_map(|depth| &depth.visuals)
+        .find(|pict_visual| pict_visual.visual == visual)
+        .map(|pict_visual| pict_visual.format);
+    Ok(format.unwrap_or(formats.screens[0].fallback))
+}
@@ -0,0 +1,499 @@
+use std::{
+    collections::BTreeSet,
+    env,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use x11rb::protocol::xproto::Keycode;
+
+use crate::{
+    backend::{FakeBackend, WindowBackend},
+    config::Config,
+    control::{self, ControlCommand},
+    events::Clock,
+    instance::{Instance, ResetStep},
+    instancemanager::{InstanceManager, SessionEffects},
+    resetcounter::ResetCounterConfig,
+    x11::{InstanceInfo, Rect},
+};
+
+/// Something from outside of rulti that it acted on
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "input", rename_all = "snake_case")]
+pub enum Input {
+    /// The config, instances and monitor the session started with
+    Started {
+        config: Box<Config>,
+        instances: Vec<InstanceInfo>,
+        monitor: Rect,
+    },
+    /// A grabbed key and where the mouse was when it was pressed
+    Hotkey { key: Keycode, pointer: (i16, i16) },
+    /// A line from the control socket or the HTTP API
+    Command { command: String },
+    /// What the instance wrote to `wpstateout.txt`
+    WorldPreviewState { instance_num: u32, state: String },
+    /// The main loop picked up that the preview of the instance is shown
+    PreviewReady { instance_num: u32 },
+    Crashed { instance_num: u32 },
+    ConfigReloaded { config: Box<Config> },
+    /// The main loop rebuilt the wall layout
+    WallUpdated,
+}
+
+/// An input with the time it happened in milliseconds since the Unix epoch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub input: Input,
+}
+
+/// Appends inputs to a recording as newline-delimited JSON. Cloning gives another handle to the same file.
+#[derive(Clone)]
+pub struct Recorder {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    /// Starts a new recording, replacing any old one at the path.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Arc::new(Mutex::new(File::create(path)?)),
+        })
+    }
+
+    pub fn record(&self, input: Input) {
+        let mut file = self.file.lock().unwrap();
+        // Timestamped while holding the file, so the records stay in order
+        let record = Record {
+            timestamp_ms: Clock::System.now_ms(),
+            input,
+        };
+        let mut line = serde_json::to_string(&record).unwrap();
+        line.push('\n');
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            println!("Failed to write to the recording {}: {err}", self.path.display());
+        }
+    }
+}
+
+/// The recorded config without anything that would touch files or programs outside of the replay
+fn replay_config(config: Config, wall_file: &Path) -> Config {
+    Config {
+        wall_file: wall_file.to_path_buf(),
+        record_file: None,
+        reset_counter: ResetCounterConfig {
+            total_file: None,
+            outputs: Vec::new(),
+        },
+        obs: None,
+        ..config
+    }
+}
+
+/// Resets advance on the recorded world preview states, and the replay leaves sleepbg alone
+struct ReplayEffects;
+
+impl SessionEffects for ReplayEffects {
+    fn spawn_reset(&self, _: Arc<Instance>, _: Receiver<()>, _: Sender<u32>, _: Sender<u32>) {}
+
+    fn set_sleepbg_lock(&self, _: bool) {}
+}
+
+/// Generating, PreviewReady and Frozen, after which a reset is Waiting or Done
+const RESET_STEPS: usize = 4;
+
+/// The reset of every instance keeps polling its state file, so it gets as far as the last state allows.
+fn settle_resets(instance_manager: &InstanceManager, crashed: &BTreeSet<u32>) {
+    for instance in &instance_manager.instances {
+        if crashed.contains(&instance.instance_info.instance_num) {
+            continue;
+        }
+        let state = instance.last_world_preview_state.lock().unwrap().clone();
+        for _ in 0..RESET_STEPS {
+            if matches!(instance.on_world_preview_state(&state), ResetStep::Waiting | ResetStep::Done) {
+                break;
+            }
+        }
+    }
+}
+
+/// Feeds a recording back into an instance manager on the fake window backend, with event timestamps
+/// taken from the recording. Writes the events to `events_path` and returns the status at the end.
+pub fn replay(path: &Path, until: Option<Duration>, events_path: Option<&Path>) -> Result<Value, String> {
+    let contents = fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {err}", path.display()))?;
+    let mut records = contents.lines().enumerate().map(|(index, line)| {
+        serde_json::from_str::<Record>(line)
+            .map_err(|err| format!("Line {} of {} is invalid: {err}", index + 1, path.display()))
+    });
+    let Some(Record {
+        timestamp_ms: start_ms,
+        input: Input::Started {
+            config,
+            instances,
+            monitor,
+        },
+    }) = records.next().transpose()?
+    else {
+        return Err(format!("{} doesn't start with the start of a session", path.display()));
+    };
+    let mut events_file = match events_path {
+        Some(events_path) => Some(
+            File::create(events_path).map_err(|err| format!("Failed to create {}: {err}", events_path.display()))?,
+        ),
+        None => None,
+    };
+    let wall_file = TempFile::new("json");
+    let wall_file = wall_file.path.as_path();
+    let mut config = replay_config(*config, wall_file);
+
+    let backend = Arc::new(FakeBackend::new(monitor));
+    // The recorded processes are gone, or their pids belong to something else by now
+    let instances: Vec<InstanceInfo> = instances.into_iter().map(|info| InstanceInfo { pid: 0, ..info }).collect();
+    for info in &instances {
+        backend.add_window(info.clone(), &config.instances.window_name, monitor);
+    }
+    for (_, key) in config.hotkeys.all() {
+        backend.grab_key(key).map_err(|err| err.to_string())?;
+    }
+    // Nothing polls the state files during a replay, so nothing is sent on these
+    let preview_channel = channel(1);
+    let percent_channel = channel(1);
+    let mut instance_manager = InstanceManager::initialize(
+        preview_channel.0,
+        percent_channel.0,
+        instances,
+        backend.clone(),
+        Box::new(ReplayEffects),
+        None,
+        &config,
+    );
+    let clock = Clock::fake(start_ms);
+    instance_manager.events.set_clock(clock.clone());
+    let mut events = instance_manager.events.subscribe();
+    let mut crashed = BTreeSet::new();
+
+    for record in records {
+        let record = record?;
+        if until.is_some_and(|until| record.timestamp_ms > start_ms + until.as_millis() as u64) {
+            break;
+        }
+        clock.set_ms(record.timestamp_ms);
+        match record.input {
+            Input::Started { .. } => return Err(format!("{} holds more than one session", path.display())),
+            Input::Hotkey { key, pointer } => {
+                backend.state().pointer = pointer;
+                backend.press_hotkey(key);
+                while let Some(key) = backend.poll_hotkey() {
+                    if let Some(hotkey) = config.hotkeys.action(key) {
+                        crate::handle_hotkey(&mut instance_manager, hotkey);
+                    }
+                }
+            }
+            Input::Command { command } => {
+                control::handle_command(&mut instance_manager, command.parse::<ControlCommand>()?);
+            }
+            Input::WorldPreviewState { instance_num, state } => {
+                if let Some(instance) = instance_manager.get_instance_by_instance_num(instance_num) {
+                    *instance.last_world_preview_state.lock().unwrap() = state;
+                }
+            }
+            Input::PreviewReady { instance_num } => instance_manager.on_preview_ready(instance_num),
+            Input::Crashed { instance_num } => {
+                instance_manager.mark_crashed(instance_num);
+                crashed.insert(instance_num);
+            }
+            Input::ConfigReloaded { config: new_config } => {
+                let new_config = replay_config(*new_config, wall_file);
+                if new_config.hotkeys != config.hotkeys {
+                    crate::regrab_hotkeys(&*backend, &config.hotkeys, &new_config.hotkeys);
+                }
+                instance_manager.apply_config(&new_config);
+                config = new_config;
+            }
+            Input::WallUpdated => instance_manager.update_wall(),
+        }
+        settle_resets(&instance_manager, &crashed);
+        while let Some(event) = events.try_recv() {
+            if let Some(file) = &mut events_file {
+                file.write_all(event.to_line().as_bytes())
+                    .map_err(|err| format!("Failed to write the replayed events: {err}"))?;
+            }
+        }
+    }
+
+    Ok(control::handle_command(&mut instance_manager, ControlCommand::Status))
+}
+
+/// A file in the temp directory that is removed again when dropped, however the replay ends
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn new(extension: &str) -> Self {
+        // Replays running at the same time each get their own file
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, SeqCst);
+        Self {
+            path: env::temp_dir().join(format!("rulti-replay-{}-{count}.{extension}", process::id())),
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+    use crate::{
+        events::{Event, EventSubscription, TimedEvent},
+        layout::LayoutConfig,
+    };
+
+    const MONITOR: Rect = Rect {
+        x: 0,
+        y: 0,
+        width: 1920,
+        height: 1080,
+    };
+
+    /// Drives a manager on the fake backend the way the main loop does, recording every input
+    struct Live {
+        config: Config,
+        backend: Arc<FakeBackend>,
+        manager: InstanceManager,
+        recorder: Recorder,
+        events: EventSubscription,
+        crashed: BTreeSet<u32>,
+    }
+
+    impl Live {
+        fn start(dir: &Path) -> Self {
+            let config = Config {
+                bag_size: 2,
+                layout: LayoutConfig::BagGrid {
+                    bag_size: 2,
+                    bag_cols: 2,
+                    bags_horizontal: 2,
+                    bags_vertical: 2,
+                },
+                wall_file: dir.join("wall.json"),
+                stats_file: None,
+                reset_counter: ResetCounterConfig {
+                    total_file: None,
+                    outputs: Vec::new(),
+                },
+                ..Config::default()
+            };
+            let instances: Vec<InstanceInfo> = (1..=4)
+                .map(|instance_num| InstanceInfo {
+                    window: 100 + instance_num,
+                    pid: 0,
+                    gamedir: format!("/tmp/RSG {instance_num}/"),
+                    instance_num,
+                })
+                .collect();
+            let backend = Arc::new(FakeBackend::new(MONITOR));
+            for info in &instances {
+                backend.add_window(info.clone(), &config.instances.window_name, MONITOR);
+            }
+            for (_, key) in config.hotkeys.all() {
+                backend.grab_key(key).unwrap();
+            }
+            let recorder = Recorder::create(&dir.join("session.ndjson")).unwrap();
+            recorder.record(Input::Started {
+                config: Box::new(config.clone()),
+                instances: instances.clone(),
+                monitor: MONITOR,
+            });
+            let manager = InstanceManager::initialize(
+                channel(1).0,
+                channel(1).0,
+                instances,
+                backend.clone(),
+                // The resets are settled by hand instead of by polling tasks
+                Box::new(ReplayEffects),
+                Some(recorder.clone()),
+                &config,
+            );
+            let events = manager.events.subscribe();
+            let mut live = Self {
+                config,
+                backend,
+                manager,
+                recorder,
+                events,
+                crashed: BTreeSet::new(),
+            };
+            live.update_wall();
+            live
+        }
+
+        fn update_wall(&mut self) {
+            self.recorder.record(Input::WallUpdated);
+            self.manager.update_wall();
+        }
+
+        fn command(&mut self, command: &str) {
+            self.recorder.record(Input::Command {
+                command: command.into(),
+            });
+            control::handle_command(&mut self.manager, command.parse().unwrap());
+            settle_resets(&self.manager, &self.crashed);
+        }
+
+        fn hotkey(&mut self, hotkey: &str, pointer: (i16, i16)) {
+            let key = self.config.hotkeys.all().into_iter().find(|(name, _)| *name == hotkey).unwrap().1;
+            self.backend.state().pointer = pointer;
+            self.recorder.record(Input::Hotkey { key, pointer });
+            crate::handle_hotkey(&mut self.manager, self.config.hotkeys.action(key).unwrap());
+            settle_resets(&self.manager, &self.crashed);
+            self.update_wall();
+        }
+
+        /// What the game writes while it resets, with the preview showing up on the wall in between
+        fn reset_game(&mut self, instance_num: u32) {
+            for state in ["generating", "previewing,0", "previewing,90", "inworld,paused"] {
+                self.recorder.record(Input::WorldPreviewState {
+                    instance_num,
+                    state: state.into(),
+                });
+                let instance = self.manager.get_instance_by_instance_num(instance_num).unwrap();
+                *instance.last_world_preview_state.lock().unwrap() = state.into();
+                settle_resets(&self.manager, &self.crashed);
+                if state == "previewing,0" {
+                    self.recorder.record(Input::PreviewReady { instance_num });
+                    self.manager.on_preview_ready(instance_num);
+                    self.update_wall();
+                }
+            }
+        }
+
+        fn crash(&mut self, instance_num: u32) {
+            self.recorder.record(Input::Crashed { instance_num });
+            self.manager.mark_crashed(instance_num);
+            self.crashed.insert(instance_num);
+            self.update_wall();
+        }
+
+        fn wall_position(&self, instance_num: u32) -> (i16, i16) {
+            let wall_instance = self
+                .manager
+                .wall_instances
+                .iter()
+                .find(|wall_instance| wall_instance.instance_num == instance_num)
+                .unwrap();
+            ((wall_instance.x + wall_instance.width / 2) as i16, (wall_instance.y + wall_instance.height / 2) as i16)
+        }
+    }
+
+    fn read_events(path: &Path) -> Vec<TimedEvent> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn replay_ends_like_the_recorded_session() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+
+        let mut live = Live::start(dir);
+        live.command("reset-all");
+        for instance_num in 1..=4 {
+            live.reset_game(instance_num);
+        }
+        let (x, y) = live.wall_position(3);
+        live.hotkey("lock_or_thin", (x, y));
+        // Resets instances 1 and 2, after which the wall can't fill a bag and the locked instance is played
+        live.hotkey("reset_bag", (0, 0));
+        live.reset_game(1);
+        live.reset_game(2);
+        live.hotkey("exit_instance", (0, 0));
+        live.reset_game(3);
+        live.command("play 1");
+        live.command("reset 2");
+        live.crash(4);
+        live.hotkey("exit_instance", (0, 0));
+        let status = control::handle_command(&mut live.manager, ControlCommand::Status);
+        let mut events = Vec::new();
+        while let Some(event) = live.events.try_recv() {
+            events.push(event.event);
+        }
+        assert_eq!(status["reset_count"], 9);
+        assert_eq!(status["playing"], Value::Null);
+        for instance_num in [3, 1] {
+            assert!(events.contains(&Event::Played { instance_num }));
+            assert!(events.contains(&Event::Exited { instance_num }));
+        }
+        assert!(events.contains(&Event::Crashed { instance_num: 4 }));
+
+        let recording = dir.join("session.ndjson");
+        let events_path = dir.join("events.ndjson");
+        assert_eq!(replay(&recording, None, Some(&events_path)).unwrap(), status);
+        let replayed = read_events(&events_path);
+        assert_eq!(replayed.iter().map(|event| event.event.clone()).collect::<Vec<Event>>(), events);
+
+        // Replaying again gives the same events down to the timestamps taken from the recording
+        assert_eq!(replay(&recording, None, Some(&events_path)).unwrap(), status);
+        assert_eq!(read_events(&events_path), replayed);
+    }
+
+    #[test]
+    fn replay_needs_the_start_of_a_session() {
+        let dir = TempDir::new().unwrap();
+        let recording = dir.path().join("session.ndjson");
+        Recorder::create(&recording).unwrap().record(Input::WallUpdated);
+        assert!(replay(&recording, None, None).unwrap_err().contains("doesn't start with the start of a session"));
+        fs::write(&recording, "not json\n").unwrap();
+        assert!(replay(&recording, None, None).unwrap_err().starts_with("Line 1 of"));
+
+        let recorder = Recorder::create(&recording).unwrap();
+        recorder.record(Input::Started {
+            config: Box::default(),
+            instances: Vec::new(),
+            monitor: MONITOR,
+        });
+        recorder.record(Input::Started {
+            config: Box::default(),
+            instances: Vec::new(),
+            monitor: MONITOR,
+        });
+        assert!(replay(&recording, None, None).unwrap_err().contains("holds more than one session"));
+    }
+
+    #[test]
+    fn temp_files_are_removed_when_dropped() {
+        let first = TempFile::new("json");
+        let second = TempFile::new("json");
+        assert_ne!(first.path, second.path);
+        fs::write(&first.path, "{}").unwrap();
+        let path = first.path.clone();
+        drop(first);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::default_data_dir, instancemanager::write_atomically};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResetCounterConfig {
+    /// Where the total reset count is kept between sessions, not persisted when not set
+    pub total_file: Option<PathBuf>,
+    pub outputs: Vec<CounterOutput>,
+}
+
+impl Default for ResetCounterConfig {
+    fn default() -> Self {
+        Self {
+            total_file: default_data_dir().map(|dir| dir.join("reset_total.txt")),
+            outputs: Vec::new(),
+        }
+    }
+}
+
+/// A text file for overlays. `{session}` and `{total}` in the template are replaced with the counts.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CounterOutput {
+    pub path: PathBuf,
+    pub template: String,
+}
+
+pub struct ResetCounter {
+    pub session: u64,
+    pub total: u64,
+    total_file: Option<PathBuf>,
+    outputs: Vec<CounterOutput>,
+    dirty: bool,
+}
+
+impl ResetCounter {
+    pub fn new(config: &ResetCounterConfig) -> Self {
+        let mut total_file = config.total_file.clone();
+        let total = match &total_file {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(contents) => match contents.trim().parse() {
+                    Ok(total) => total,
+                    Err(err) => {
+                        // Starting over from 0 would overwrite the real count
+                        println!("{} doesn't hold a reset count, not persisting resets: {err}", path.display());
+                        total_file = None;
+                        0
+                    }
+                },
+                Err(err) if err.kind() == ErrorKind::NotFound => 0,
+                Err(err) => {
+                    println!("Failed to read {}, not persisting resets: {err}", path.display());
+                    total_file = None;
+                    0
+                }
+            },
+            None => 0,
+        };
+        Self {
+            session: 0,
+            total,
+            total_file,
+            outputs: config.outputs.clone(),
+            // Writes the outputs once at startup so they show the new session
+            dirty: true,
+        }
+    }
+
+    pub fn increment(&mut self) {
+        self.session += 1;
+        self.total += 1;
+        self.dirty = true;
+    }
+
+    /// Writes the counts if they changed since the last flush.
+    pub fn flush(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+        if let Some(path) = &self.total_file {
+            write_file(path, &self.total.to_string());
+        }
+        for output in &self.outputs {
+            let contents = output
+                .template
+                .replace("{session}", &self.session.to_string())
+                .replace("{total}", &self.total.to_string());
+            write_file(&output.path, &contents);
+        }
+    }
+}
+
+fn write_file(path: &Path, contents: &str) {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        let _ = fs::create_dir_all(dir);
+    }
+    if let Err(err) = write_atomically(path, contents.as_bytes()) {
+        println!("Failed to write {}: {err}", path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn config(total_file: Option<PathBuf>, outputs: Vec<CounterOutput>) -> ResetCounterConfig {
+        ResetCounterConfig { total_file, outputs }
+    }
+
+    #[test]
+    fn renders_templates() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let outputs = vec![
+            CounterOutput {
+                path: dir.join("both.txt"),
+                template: "Resets: {session} / {total}".into(),
+            },
+            CounterOutput {
+                path: dir.join("nested/session.txt"),
+                template: "{session}{session}".into(),
+            },
+            CounterOutput {
+                path: dir.join("plain.txt"),
+                template: "no counts".into(),
+            },
+        ];
+        let mut counter = ResetCounter::new(&config(None, outputs));
+        counter.flush();
+        assert_eq!(fs::read_to_string(dir.join("both.txt")).unwrap(), "Resets: 0 / 0");
+        counter.increment();
+        counter.increment();
+        counter.flush();
+        assert_eq!(fs::read_to_string(dir.join("both.txt")).unwrap(), "Resets: 2 / 2");
+        assert_eq!(fs::read_to_string(dir.join("nested/session.txt")).unwrap(), "22");
+        assert_eq!(fs::read_to_string(dir.join("plain.txt")).unwrap(), "no counts");
+    }
+
+    #[test]
+    fn keeps_the_total_between_sessions() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let total_file = dir.join("reset_total.txt");
+        let outputs = vec![CounterOutput {
+            path: dir.join("counter.txt"),
+            template: "{session} {total}".into(),
+        }];
+
+        let mut counter = ResetCounter::new(&config(Some(total_file.clone()), outputs.clone()));
+        assert_eq!((counter.session, counter.total), (0, 0));
+        for _ in 0..3 {
+            counter.increment();
+        }
+        counter.flush();
+        assert_eq!(fs::read_to_string(&total_file).unwrap(), "3");
+
+        let mut counter = ResetCounter::new(&config(Some(total_file.clone()), outputs));
+        assert_eq!((counter.session, counter.total), (0, 3));
+        counter.increment();
+        counter.flush();
+        assert_eq!(fs::read_to_string(&total_file).unwrap(), "4");
+        assert_eq!(fs::read_to_string(dir.join("counter.txt")).unwrap(), "1 4");
+    }
+
+    #[test]
+    fn leaves_an_unreadable_total_alone() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let total_file = dir.join("reset_total.txt");
+        fs::write(&total_file, "not a number").unwrap();
+        let mut counter = ResetCounter::new(&config(Some(total_file.clone()), Vec::new()));
+        assert_eq!(counter.total, 0);
+        counter.increment();
+        counter.flush();
+        assert_eq!(fs::read_to_string(&total_file).unwrap(), "not a number");
+    }
+}
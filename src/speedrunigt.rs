@@ -0,0 +1,145 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// SpeedRunIGT only writes the record once the world is closed, which happens during the reset after exiting
+const SCAN_DELAY: Duration = Duration::from_secs(2);
+const SCAN_ATTEMPTS: u32 = 5;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpeedRunIgtConfig {
+    /// Directory the records are written to, relative to the game directory
+    pub records_dir: PathBuf,
+}
+
+impl Default for SpeedRunIgtConfig {
+    fn default() -> Self {
+        Self {
+            records_dir: PathBuf::from("speedrunigt/records"),
+        }
+    }
+}
+
+/// The parts of a SpeedRunIGT record file rulti cares about
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RecordFile {
+    world_name: String,
+    category: String,
+    is_completed: bool,
+    final_igt: u64,
+    final_rta: u64,
+    timelines: Vec<RecordTimeline>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RecordTimeline {
+    name: String,
+    igt: u64,
+    rta: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Split {
+    pub name: String,
+    pub igt_ms: u64,
+    pub rta_ms: u64,
+}
+
+/// Outcome of one played instance, stored as the detail of a `run` stats record
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub instance_num: u32,
+    pub played_at_ms: u64,
+    pub world_name: String,
+    pub category: String,
+    pub completed: bool,
+    pub igt_ms: u64,
+    pub rta_ms: u64,
+    pub splits: Vec<Split>,
+}
+
+fn parse_record(path: &Path, instance_num: u32, played_at_ms: u64) -> Result<RunSummary, String> {
+    let contents = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let record: RecordFile = serde_json::from_str(&contents).map_err(|err| err.to_string())?;
+    Ok(RunSummary {
+        instance_num,
+        played_at_ms,
+        world_name: record.world_name,
+        category: record.category,
+        completed: record.is_completed,
+        igt_ms: record.final_igt,
+        rta_ms: record.final_rta,
+        splits: record
+            .timelines
+            .into_iter()
+            .map(|timeline| Split {
+                name: timeline.name,
+                igt_ms: timeline.igt,
+                rta_ms: timeline.rta,
+            })
+            .collect(),
+    })
+}
+
+/// Record files modified at or after `since`, oldest first
+fn find_new_records(dir: &Path, since: SystemTime) -> io::Result<Vec<PathBuf>> {
+    let mut records = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        if modified >= since {
+            records.push((modified, path));
+        }
+    }
+    records.sort();
+    Ok(records.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Waits for SpeedRunIGT to write the records of the run that was played since `played_at`,
+/// and sends a summary of each of them.
+pub fn spawn_ingest(
+    records_dir: PathBuf,
+    instance_num: u32,
+    played_at: SystemTime,
+    played_at_ms: u64,
+    run_sender: UnboundedSender<RunSummary>,
+) {
+    tokio::spawn(async move {
+        for _ in 0..SCAN_ATTEMPTS {
+            tokio::time::sleep(SCAN_DELAY).await;
+            let records = match find_new_records(&records_dir, played_at) {
+                Ok(records) => records,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    println!("Failed to read SpeedRunIGT records in {}: {err}", records_dir.display());
+                    return;
+                }
+            };
+            if records.is_empty() {
+                continue;
+            }
+            for path in records {
+                match parse_record(&path, instance_num, played_at_ms) {
+                    Ok(run) => {
+                        let _ = run_sender.send(run);
+                    }
+                    Err(err) => println!("Failed to parse SpeedRunIGT record {}: {err}", path.display()),
+                }
+            }
+            return;
+        }
+        println!("No SpeedRunIGT record for the run on instance {instance_num}");
+    });
+}
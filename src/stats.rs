@@ -0,0 +1,472 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::{self, OpenOptions},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::unbounded_channel;
+
+use crate::{
+    events::{Event, EventBus},
+    speedrunigt::{self, RunSummary},
+    worldcleaner::current_world,
+};
+
+/// One row of the stats file
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub timestamp_ms: u64,
+    /// `session_start`, `reset`, `preview`, `lock`, `play`, `exit` or `run`
+    pub kind: String,
+    pub instance_num: Option<u32>,
+    /// Extra data for the record: the world name of a `play`, the JSON `RunSummary` of a `run`
+    /// and empty for the other kinds
+    pub detail: String,
+}
+
+impl Record {
+    fn now(kind: &str, instance_num: Option<u32>) -> Self {
+        Self {
+            timestamp_ms: now_ms(),
+            kind: kind.into(),
+            instance_num,
+            detail: String::new(),
+        }
+    }
+
+    fn run(run: &RunSummary) -> Self {
+        Self {
+            detail: serde_json::to_string(run).unwrap(),
+            ..Self::now("run", Some(run.instance_num))
+        }
+    }
+
+    fn from_event(timestamp_ms: u64, event: &Event) -> Option<Self> {
+        let (kind, instance_num) = match event {
+            Event::Reset { instance_num } => ("reset", *instance_num),
+            Event::StateChanged { instance_num, to, .. } if to == "Preview" => ("preview", *instance_num),
+            Event::Locked { instance_num } => ("lock", *instance_num),
+            Event::Played { instance_num } => ("play", *instance_num),
+            Event::Exited { instance_num } => ("exit", *instance_num),
+            _ => return None,
+        };
+        Some(Self {
+            timestamp_ms,
+            kind: kind.into(),
+            instance_num: Some(instance_num),
+            detail: String::new(),
+        })
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Appends records to the stats file, writing the header when the file is new.
+pub struct StatsWriter {
+    path: PathBuf,
+    writer: csv::Writer<fs::File>,
+}
+
+impl StatsWriter {
+    pub fn open(path: &Path) -> Result<Self, csv::Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let is_new = fs::metadata(path).map(|metadata| metadata.len() == 0).unwrap_or(true);
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let writer = csv::WriterBuilder::new().has_headers(is_new).from_writer(file);
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer,
+        })
+    }
+
+    pub fn write(&mut self, record: &Record) {
+        let result = self.writer.serialize(record).and_then(|_| Ok(self.writer.flush()?));
+        if let Err(err) = result {
+            println!("Failed to write to {}: {err}", self.path.display());
+        }
+    }
+}
+
+/// Records a new session and then every reset, preview, lock, play and exit on the bus.
+/// A play records the name of the world being played, and runs are summarized from the SpeedRunIGT
+/// records in `records_dir` of the game directory once the instance is exited.
+pub fn spawn_recorder(bus: &EventBus, path: PathBuf, gamedirs: HashMap<u32, PathBuf>, records_dir: PathBuf) {
+    let mut writer = match StatsWriter::open(&path) {
+        Ok(writer) => writer,
+        Err(err) => {
+            println!("Failed to open the stats file {}: {err}", path.display());
+            return;
+        }
+    };
+    writer.write(&Record::now("session_start", None));
+    let mut subscription = bus.subscribe();
+    let (run_sender, mut run_receiver) = unbounded_channel();
+    tokio::spawn(async move {
+        let mut played_at: HashMap<u32, u64> = HashMap::new();
+        loop {
+            tokio::select! {
+                event = subscription.recv() => {
+                    let Some(event) = event else {
+                        return;
+                    };
+                    let Some(mut record) = Record::from_event(event.timestamp_ms, &event.event) else {
+                        continue;
+                    };
+                    match event.event {
+                        Event::Played { instance_num } => {
+                            played_at.insert(instance_num, event.timestamp_ms);
+                            if let Some(gamedir) = gamedirs.get(&instance_num) {
+                                record.detail = current_world(&gamedir.join("saves")).unwrap_or_default();
+                            }
+                        }
+                        Event::Exited { instance_num } => {
+                            if let (Some(played_at_ms), Some(gamedir)) =
+                                (played_at.remove(&instance_num), gamedirs.get(&instance_num))
+                            {
+                                speedrunigt::spawn_ingest(
+                                    gamedir.join(&records_dir),
+                                    instance_num,
+                                    UNIX_EPOCH + Duration::from_millis(played_at_ms),
+                                    played_at_ms,
+                                    run_sender.clone(),
+                                );
+                            }
+                        }
+                        _ => {}
+                    }
+                    writer.write(&record);
+                }
+                Some(run) = run_receiver.recv() => writer.write(&Record::run(&run)),
+            }
+        }
+    });
+}
+
+pub fn read_records(path: &Path) -> Result<Vec<Record>, csv::Error> {
+    csv::Reader::from_path(path)?.deserialize().collect()
+}
+
+#[derive(Default)]
+pub struct InstanceStats {
+    pub resets: u64,
+    pub preview_ms: Vec<u64>,
+}
+
+#[derive(Default)]
+pub struct Summary {
+    pub sessions: u64,
+    /// Time from the start of each session to its last record
+    pub active_ms: u64,
+    pub in_game_ms: u64,
+    pub resets: u64,
+    pub locks: u64,
+    pub plays: u64,
+    pub preview_ms: Vec<u64>,
+    pub instances: BTreeMap<u32, InstanceStats>,
+    /// Runs with the session they were played in, counting sessions from 1
+    pub runs: Vec<(u64, RunSummary)>,
+}
+
+impl Summary {
+    pub fn from_records(records: &[Record]) -> Self {
+        let mut summary = Summary::default();
+        let mut session_start: Option<u64> = None;
+        let mut last_timestamp = 0;
+        let mut play_start: Option<u64> = None;
+        let mut reset_at: HashMap<u32, u64> = HashMap::new();
+
+        for record in records {
+            if record.kind == "session_start" {
+                summary.end_session(session_start, last_timestamp, &mut play_start);
+                summary.sessions += 1;
+                session_start = Some(record.timestamp_ms);
+                reset_at.clear();
+            }
+            last_timestamp = record.timestamp_ms;
+            let Some(instance_num) = record.instance_num else {
+                continue;
+            };
+            match record.kind.as_str() {
+                "reset" => {
+                    summary.resets += 1;
+                    summary.instances.entry(instance_num).or_default().resets += 1;
+                    reset_at.insert(instance_num, record.timestamp_ms);
+                }
+                "preview" => {
+                    if let Some(reset_timestamp) = reset_at.remove(&instance_num) {
+                        let preview_ms = record.timestamp_ms.saturating_sub(reset_timestamp);
+                        summary.preview_ms.push(preview_ms);
+                        summary.instances.entry(instance_num).or_default().preview_ms.push(preview_ms);
+                    }
+                }
+                "lock" => summary.locks += 1,
+                "play" => {
+                    summary.plays += 1;
+                    play_start = Some(record.timestamp_ms);
+                }
+                "exit" => {
+                    if let Some(start) = play_start.take() {
+                        summary.in_game_ms += record.timestamp_ms.saturating_sub(start);
+                    }
+                }
+                "run" => match serde_json::from_str(&record.detail) {
+                    Ok(run) => summary.runs.push((summary.sessions, run)),
+                    Err(err) => println!("Skipping a run record at {}: {err}", record.timestamp_ms),
+                },
+                _ => {}
+            }
+        }
+        summary.end_session(session_start, last_timestamp, &mut play_start);
+        summary
+    }
+
+    /// A session ends with its last record, which also ends a run that was never exited
+    fn end_session(&mut self, session_start: Option<u64>, last_timestamp: u64, play_start: &mut Option<u64>) {
+        if let Some(start) = session_start {
+            self.active_ms += last_timestamp.saturating_sub(start);
+        }
+        if let Some(start) = play_start.take() {
+            self.in_game_ms += last_timestamp.saturating_sub(start);
+        }
+    }
+
+    pub fn wall_ms(&self) -> u64 {
+        self.active_ms.saturating_sub(self.in_game_ms)
+    }
+}
+
+fn per_hour(count: u64, ms: u64) -> f64 {
+    if ms == 0 {
+        return 0.0;
+    }
+    count as f64 * 3_600_000.0 / ms as f64
+}
+
+fn average_seconds(values: &[u64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.iter().sum::<u64>() as f64 / values.len() as f64 / 1000.0
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        return 0.0;
+    }
+    part as f64 * 100.0 / whole as f64
+}
+
+struct Hms(u64);
+
+impl fmt::Display for Hms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.0 / 1000;
+        write!(f, "{}h {:02}m {:02}s", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    }
+}
+
+/// In-game time as `m:ss.mmm`
+struct Igt(u64);
+
+impl fmt::Display for Igt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{:02}.{:03}", self.0 / 60_000, self.0 / 1000 % 60, self.0 % 1000)
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Sessions:         {}", self.sessions)?;
+        writeln!(f, "Active time:      {}", Hms(self.active_ms))?;
+        writeln!(
+            f,
+            "Resets:           {} ({:.1}/h)",
+            self.resets,
+            per_hour(self.resets, self.active_ms)
+        )?;
+        writeln!(f, "Average preview:  {:.2}s", average_seconds(&self.preview_ms))?;
+        writeln!(
+            f,
+            "On the wall:      {} ({:.0}%)",
+            Hms(self.wall_ms()),
+            percent(self.wall_ms(), self.active_ms)
+        )?;
+        writeln!(
+            f,
+            "In game:          {} ({:.0}%)",
+            Hms(self.in_game_ms),
+            percent(self.in_game_ms, self.active_ms)
+        )?;
+        writeln!(f, "Locks:            {}", self.locks)?;
+        writeln!(f, "Plays:            {}", self.plays)?;
+        writeln!(f)?;
+        writeln!(f, "{:>8}  {:>8}  {:>9}  {:>11}", "instance", "resets", "resets/h", "avg preview")?;
+        for (instance_num, stats) in &self.instances {
+            writeln!(
+                f,
+                "{:>8}  {:>8}  {:>9.1}  {:>10.2}s",
+                instance_num,
+                stats.resets,
+                per_hour(stats.resets, self.active_ms),
+                average_seconds(&stats.preview_ms)
+            )?;
+        }
+        if self.runs.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:>7}  {:>5}  {:>9}  {:>10}  {:>10}  {:<9}  splits", "session", "runs", "completed", "best igt", "igt", "instance")?;
+        let mut sessions: BTreeMap<u64, Vec<&RunSummary>> = BTreeMap::new();
+        for (session, run) in &self.runs {
+            sessions.entry(*session).or_default().push(run);
+        }
+        for (session, runs) in sessions {
+            let completed: Vec<_> = runs.iter().filter(|run| run.completed).collect();
+            let best = completed.iter().map(|run| run.igt_ms).min();
+            writeln!(
+                f,
+                "{:>7}  {:>5}  {:>9}  {:>10}",
+                session,
+                runs.len(),
+                completed.len(),
+                best.map(|igt| Igt(igt).to_string()).unwrap_or_else(|| "-".into())
+            )?;
+            for run in runs {
+                let splits: Vec<String> = run
+                    .splits
+                    .iter()
+                    .map(|split| format!("{} {}", split.name, Igt(split.igt_ms)))
+                    .collect();
+                writeln!(
+                    f,
+                    "{:>7}  {:>5}  {:>9}  {:>10}  {:>10}  {:<9}  {}",
+                    "",
+                    "",
+                    if run.completed { "yes" } else { "no" },
+                    "",
+                    Igt(run.igt_ms),
+                    run.instance_num,
+                    splits.join(", ")
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp_ms: u64, kind: &str, instance_num: Option<u32>) -> Record {
+        Record {
+            timestamp_ms,
+            kind: kind.into(),
+            instance_num,
+            detail: String::new(),
+        }
+    }
+
+    #[test]
+    fn nothing_recorded() {
+        let summary = Summary::from_records(&[]);
+        assert_eq!((summary.sessions, summary.active_ms, summary.in_game_ms, summary.resets), (0, 0, 0, 0));
+        assert!(summary.preview_ms.is_empty() && summary.instances.is_empty() && summary.runs.is_empty());
+        // Nothing to divide by must not turn into NaN
+        let text = summary.to_string();
+        assert!(text.contains("Resets:           0 (0.0/h)"), "{text}");
+        assert!(text.contains("Average preview:  0.00s"), "{text}");
+    }
+
+    #[test]
+    fn only_a_session_start() {
+        let summary = Summary::from_records(&[record(5_000, "session_start", None)]);
+        assert_eq!(summary.sessions, 1);
+        assert_eq!(summary.active_ms, 0);
+        assert_eq!(summary.wall_ms(), 0);
+    }
+
+    #[test]
+    fn one_session() {
+        let records = [
+            record(0, "session_start", None),
+            record(1_000, "reset", Some(1)),
+            record(1_500, "reset", Some(2)),
+            record(3_000, "preview", Some(1)),
+            record(4_500, "preview", Some(2)),
+            // A preview without a reset before it has no time to measure
+            record(5_000, "preview", Some(3)),
+            record(6_000, "lock", Some(2)),
+            record(10_000, "play", Some(2)),
+            record(70_000, "exit", Some(2)),
+            record(71_000, "reset", Some(2)),
+            record(100_000, "reset", Some(1)),
+        ];
+        let summary = Summary::from_records(&records);
+        assert_eq!(summary.sessions, 1);
+        assert_eq!(summary.active_ms, 100_000);
+        assert_eq!(summary.in_game_ms, 60_000);
+        assert_eq!(summary.wall_ms(), 40_000);
+        assert_eq!((summary.resets, summary.locks, summary.plays), (4, 1, 1));
+        assert_eq!(summary.preview_ms, vec![2_000, 3_000]);
+        assert_eq!(summary.instances[&1].resets, 2);
+        assert_eq!(summary.instances[&1].preview_ms, vec![2_000]);
+        assert_eq!(summary.instances[&2].resets, 2);
+        assert_eq!(summary.instances[&2].preview_ms, vec![3_000]);
+        assert!(!summary.instances.contains_key(&3));
+        let text = summary.to_string();
+        assert!(text.contains("Resets:           4 (144.0/h)"), "{text}");
+        assert!(text.contains("Average preview:  2.50s"), "{text}");
+        assert!(text.contains("In game:          0h 01m 00s (60%)"), "{text}");
+    }
+
+    #[test]
+    fn sessions_end_with_their_last_record() {
+        let run = RunSummary {
+            instance_num: 1,
+            played_at_ms: 2_000,
+            world_name: "Random Speedrun #1".into(),
+            category: "ANY".into(),
+            completed: true,
+            igt_ms: 754_321,
+            rta_ms: 800_000,
+            splits: Vec::new(),
+        };
+        let records = [
+            record(0, "session_start", None),
+            record(1_000, "reset", Some(1)),
+            record(2_000, "play", Some(1)),
+            // rulti stopped while playing, the run ends with the session
+            record(5_000, "lock", Some(2)),
+            record(1_000_000, "session_start", None),
+            // A preview in a new session doesn't pair with the reset from the last one
+            record(1_001_000, "preview", Some(1)),
+            Record {
+                detail: serde_json::to_string(&run).unwrap(),
+                ..record(1_002_000, "run", Some(1))
+            },
+            Record {
+                detail: "not json".into(),
+                ..record(1_003_000, "run", Some(1))
+            },
+        ];
+        let summary = Summary::from_records(&records);
+        assert_eq!(summary.sessions, 2);
+        assert_eq!(summary.active_ms, 5_000 + 3_000);
+        assert_eq!(summary.in_game_ms, 3_000);
+        assert!(summary.preview_ms.is_empty());
+        assert_eq!(summary.runs, vec![(2, run)]);
+        assert!(summary.to_string().contains("12:34.321"));
+    }
+}
@@ -0,0 +1,115 @@
+use std::{fs, io, path::Path, thread, time::Duration};
+
+use crate::{
+    bench::{self, BenchLimit},
+    config::{AffinityPolicy, Config, ConfigError},
+    instancemanager::write_atomically,
+};
+
+/// Thread counts worth trying on a machine with `cpus` CPUs
+fn thread_candidates(cpus: u32) -> Vec<u32> {
+    let mut candidates: Vec<u32> = [1, 2, 3, 4, 6, 8, 12, 16, 24, 32, 48, 64]
+        .into_iter()
+        .filter(|threads| *threads < cpus)
+        .collect();
+    candidates.push(cpus);
+    candidates
+}
+
+/// Benchmarks every candidate, returning the one with the most resets per minute.
+async fn best_of(
+    config: &Config,
+    candidates: &[u32],
+    trial_duration: Duration,
+    hold_playing: bool,
+    apply: impl Fn(&mut AffinityPolicy, u32),
+    name: &str,
+) -> Result<u32, String> {
+    let mut best = None;
+    for threads in candidates {
+        let mut trial_config = config.clone();
+        apply(&mut trial_config.affinity, *threads);
+        let limit = BenchLimit {
+            resets: None,
+            duration: Some(trial_duration),
+        };
+        let report = bench::run(&trial_config, limit, hold_playing).await?;
+        let resets_per_minute = report.resets_per_minute();
+        println!("  {name} = {threads}: {resets_per_minute:.1} resets/min");
+        if best.is_none_or(|(_, best_resets_per_minute)| resets_per_minute > best_resets_per_minute) {
+            best = Some((*threads, resets_per_minute));
+        }
+    }
+    best.map(|(threads, _)| threads).ok_or_else(|| format!("No {name} to try"))
+}
+
+/// Finds the thread counts with the highest reset throughput on the wall and while an instance is being played.
+/// Only the values a benchmark can measure are tuned, the rest of the policy is taken from the config.
+pub async fn tune(config: &Config, trial_duration: Duration) -> Result<AffinityPolicy, String> {
+    let cpus = thread::available_parallelism().map_or(1, |cpus| cpus.get()) as u32;
+    let candidates = thread_candidates(cpus);
+    let trials = candidates.len() * 2;
+    println!(
+        "Trying {trials} settings for {:.0}s each, about {:.0} minutes in total",
+        trial_duration.as_secs_f64(),
+        (trial_duration * trials as u32).as_secs_f64() / 60.0
+    );
+
+    let mut affinity = config.affinity.clone();
+    println!("On the wall:");
+    affinity.wall_threads = best_of(
+        config,
+        &candidates,
+        trial_duration,
+        false,
+        |affinity, threads| affinity.wall_threads = threads,
+        "wall_threads",
+    )
+    .await?;
+
+    println!("While playing:");
+    let playing_config = Config {
+        affinity: affinity.clone(),
+        ..config.clone()
+    };
+    affinity.background_threads = best_of(
+        &playing_config,
+        &candidates,
+        trial_duration,
+        true,
+        |affinity, threads| affinity.background_threads = threads,
+        "background_threads",
+    )
+    .await?;
+    Ok(affinity)
+}
+
+/// Puts the tuned thread counts into the `[affinity]` table of the config file and leaves the rest of it as is.
+/// The previous file is kept next to it, since rewriting drops its comments.
+pub fn write_affinity(path: &Path, affinity: &AffinityPolicy) -> Result<(), ConfigError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(ConfigError::Io(path.to_path_buf(), err)),
+    };
+    let mut table: toml::Table = toml::from_str(&contents).map_err(|err| ConfigError::Parse(path.to_path_buf(), err))?;
+    let affinity_table = table
+        .entry("affinity")
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    let Some(affinity_table) = affinity_table.as_table_mut() else {
+        return Err(ConfigError::Invalid(path.to_path_buf(), "affinity must be a table".into()));
+    };
+    affinity_table.insert("wall_threads".into(), i64::from(affinity.wall_threads).into());
+    affinity_table.insert("background_threads".into(), i64::from(affinity.background_threads).into());
+    let new_contents =
+        toml::to_string_pretty(&table).map_err(|err| ConfigError::Invalid(path.to_path_buf(), err.to_string()))?;
+
+    let io_error = |err| ConfigError::Io(path.to_path_buf(), err);
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(io_error)?;
+    }
+    if !contents.is_empty() {
+        fs::write(path.with_extension("toml.bak"), &contents).map_err(io_error)?;
+    }
+    write_atomically(path, new_contents.as_bytes()).map_err(io_error)
+}
@@ -0,0 +1,310 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{speedrunigt::RunSummary, stats::read_records};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorldCleanupConfig {
+    pub enabled: bool,
+    /// Only print what would be deleted
+    pub dry_run: bool,
+    /// Newest worlds kept in every instance's saves folder
+    pub keep_newest: usize,
+    /// Worlds modified more recently than this are never deleted
+    pub min_age_s: u64,
+    pub interval_s: u64,
+    /// Only worlds whose folder name matches are deleted, so practice maps are left alone
+    pub name_pattern: String,
+}
+
+impl Default for WorldCleanupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dry_run: false,
+            keep_newest: 10,
+            min_age_s: 120,
+            interval_s: 300,
+            name_pattern: r"^(New World( \(\d+\))?|Random Speedrun #\d+|Set Speedrun #\d+)$".into(),
+        }
+    }
+}
+
+/// The world the instance is in right now, which is the one it touched last
+pub fn current_world(saves_dir: &Path) -> Option<String> {
+    let worlds = list_worlds(saves_dir).ok()?;
+    worlds
+        .into_iter()
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, name)| name)
+}
+
+fn list_worlds(saves_dir: &Path) -> io::Result<Vec<(SystemTime, String)>> {
+    let mut worlds = Vec::new();
+    for entry in fs::read_dir(saves_dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_dir() {
+            continue;
+        }
+        worlds.push((metadata.modified()?, entry.file_name().to_string_lossy().into_owned()));
+    }
+    Ok(worlds)
+}
+
+/// Worlds per instance that were played or have a recorded run, according to the stats file
+fn protected_worlds(stats_file: Option<&Path>) -> HashMap<u32, HashSet<String>> {
+    let mut protected: HashMap<u32, HashSet<String>> = HashMap::new();
+    let Some(stats_file) = stats_file else {
+        return protected;
+    };
+    let records = match read_records(stats_file) {
+        Ok(records) => records,
+        Err(err) => {
+            println!("Failed to read {} for world cleanup: {err}", stats_file.display());
+            return protected;
+        }
+    };
+    for record in records {
+        let Some(instance_num) = record.instance_num else {
+            continue;
+        };
+        let world = match record.kind.as_str() {
+            "play" => record.detail,
+            "run" => match serde_json::from_str::<RunSummary>(&record.detail) {
+                Ok(run) => run.world_name,
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        if !world.is_empty() {
+            protected.entry(instance_num).or_default().insert(world);
+        }
+    }
+    protected
+}
+
+/// Deletes the old worlds of every instance once and returns how many were (or would be) deleted.
+pub fn clean_once(config: &WorldCleanupConfig, gamedirs: &HashMap<u32, PathBuf>, stats_file: Option<&Path>) -> usize {
+    let name_regex = Regex::new(&config.name_pattern).unwrap();
+    let protected = protected_worlds(stats_file);
+    let no_protected = HashSet::new();
+    let min_age = Duration::from_secs(config.min_age_s);
+    let now = SystemTime::now();
+    let mut deleted = 0;
+
+    for (instance_num, gamedir) in gamedirs {
+        let saves_dir = gamedir.join("saves");
+        let mut worlds = match list_worlds(&saves_dir) {
+            Ok(worlds) => worlds,
+            Err(err) => {
+                println!("Failed to list {}: {err}", saves_dir.display());
+                continue;
+            }
+        };
+        // Newest first, so the current world is always among the kept ones
+        worlds.sort_by(|a, b| b.cmp(a));
+        let protected = protected.get(instance_num).unwrap_or(&no_protected);
+
+        for (modified, name) in worlds.into_iter().skip(config.keep_newest.max(1)) {
+            let recent = now.duration_since(modified).map_or(true, |age| age < min_age);
+            if recent || !name_regex.is_match(&name) || protected.contains(&name) {
+                continue;
+            }
+            let path = saves_dir.join(&name);
+            if config.dry_run {
+                println!("Would delete {}", path.display());
+                deleted += 1;
+                continue;
+            }
+            match fs::remove_dir_all(&path) {
+                Ok(_) => deleted += 1,
+                Err(err) => println!("Failed to delete {}: {err}", path.display()),
+            }
+        }
+    }
+    deleted
+}
+
+/// Cleans the saves folders every `interval_s` on a thread with idle IO priority.
+pub fn spawn(config: WorldCleanupConfig, gamedirs: HashMap<u32, PathBuf>, stats_file: Option<PathBuf>) {
+    thread::spawn(move || {
+        set_idle_io_priority();
+        loop {
+            let deleted = clean_once(&config, &gamedirs, stats_file.as_deref());
+            if deleted > 0 {
+                let verb = if config.dry_run { "Would have deleted" } else { "Deleted" };
+                println!("{verb} {deleted} old worlds");
+            }
+            thread::sleep(Duration::from_secs(config.interval_s));
+        }
+    });
+}
+
+/// Puts the calling thread in the idle IO class, so deleting worlds never slows down world generation
+pub fn set_idle_io_priority() {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+    // A thread id of 0 is the calling thread, IO priorities are per thread on Linux
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0 as libc::c_long,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    if result != 0 {
+        println!("Failed to lower the IO priority of the world cleaner: {}", io::Error::last_os_error());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::stats::{Record, StatsWriter};
+
+    /// Two instances with worlds of every kind and a stats file protecting some of them
+    struct Setup {
+        _dir: TempDir,
+        gamedirs: HashMap<u32, PathBuf>,
+        stats_file: PathBuf,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            let temp_dir = TempDir::new().unwrap();
+            let dir = temp_dir.path();
+            let gamedirs = HashMap::from([(1, dir.join("instance-1")), (2, dir.join("instance-2")), (3, dir.join("missing"))]);
+            let worlds = [
+                (1, "Random Speedrun #10", 10),
+                (1, "Random Speedrun #9", 30),
+                (1, "Random Speedrun #8", 60),
+                (1, "Practice Map", 7200),
+                (1, "Random Speedrun #3", 7200),
+                (1, "Random Speedrun #2", 7200),
+                (1, "Random Speedrun #1", 7200),
+                (1, "New World (2)", 7200),
+                (2, "Random Speedrun #4", 10),
+                (2, "Random Speedrun #5", 20),
+                (2, "Random Speedrun #3", 7200),
+            ];
+            for (instance_num, name, age_s) in worlds {
+                let world = gamedirs[&instance_num].join("saves").join(name);
+                fs::create_dir_all(&world).unwrap();
+                let modified = SystemTime::now() - Duration::from_secs(age_s);
+                File::open(&world).unwrap().set_modified(modified).unwrap();
+            }
+            fs::write(gamedirs[&1].join("saves/notes.txt"), "not a world").unwrap();
+
+            let stats_file = dir.join("stats.csv");
+            let mut stats = StatsWriter::open(&stats_file).unwrap();
+            let run = RunSummary {
+                instance_num: 1,
+                played_at_ms: 0,
+                world_name: "Random Speedrun #2".into(),
+                category: "ANY".into(),
+                completed: false,
+                igt_ms: 0,
+                rta_ms: 0,
+                splits: Vec::new(),
+            };
+            let records = [
+                ("play", "Random Speedrun #3".to_string()),
+                ("run", serde_json::to_string(&run).unwrap()),
+            ];
+            for (kind, detail) in records {
+                stats.write(&Record {
+                    timestamp_ms: 0,
+                    kind: kind.into(),
+                    instance_num: Some(1),
+                    detail,
+                });
+            }
+            Self {
+                _dir: temp_dir,
+                gamedirs,
+                stats_file,
+            }
+        }
+
+        fn worlds(&self, instance_num: u32) -> Vec<String> {
+            let mut worlds: Vec<String> = list_worlds(&self.gamedirs[&instance_num].join("saves"))
+                .unwrap()
+                .into_iter()
+                .map(|(_, name)| name)
+                .collect();
+            worlds.sort();
+            worlds
+        }
+    }
+
+    fn config(dry_run: bool) -> WorldCleanupConfig {
+        WorldCleanupConfig {
+            enabled: true,
+            dry_run,
+            keep_newest: 2,
+            min_age_s: 120,
+            ..WorldCleanupConfig::default()
+        }
+    }
+
+    #[test]
+    fn deletes_only_old_unprotected_speedrun_worlds() {
+        let setup = Setup::new();
+        assert_eq!(clean_once(&config(false), &setup.gamedirs, Some(&setup.stats_file)), 3);
+        assert_eq!(
+            setup.worlds(1),
+            vec![
+                "Practice Map",       // Not a speedrun world
+                "Random Speedrun #10", // One of the newest two
+                "Random Speedrun #2",  // Recorded as a run
+                "Random Speedrun #3",  // Recorded as played
+                "Random Speedrun #8",  // Younger than min_age_s
+                "Random Speedrun #9",  // One of the newest two
+            ]
+        );
+        // Worlds are only protected in the instance they were played in
+        assert_eq!(setup.worlds(2), vec!["Random Speedrun #4", "Random Speedrun #5"]);
+        assert!(setup.gamedirs[&1].join("saves/notes.txt").exists());
+    }
+
+    #[test]
+    fn everything_old_goes_without_a_stats_file() {
+        let setup = Setup::new();
+        assert_eq!(clean_once(&config(false), &setup.gamedirs, None), 5);
+        assert_eq!(
+            setup.worlds(1),
+            vec!["Practice Map", "Random Speedrun #10", "Random Speedrun #8", "Random Speedrun #9"]
+        );
+    }
+
+    #[test]
+    fn dry_run_deletes_nothing() {
+        let setup = Setup::new();
+        let before = (setup.worlds(1), setup.worlds(2));
+        assert_eq!(clean_once(&config(true), &setup.gamedirs, Some(&setup.stats_file)), 3);
+        assert_eq!((setup.worlds(1), setup.worlds(2)), before);
+    }
+
+    #[test]
+    fn current_world_is_the_newest() {
+        let setup = Setup::new();
+        assert_eq!(current_world(&setup.gamedirs[&1].join("saves")).as_deref(), Some("Random Speedrun #10"));
+        assert_eq!(current_world(&setup.gamedirs[&3].join("saves")), None);
+    }
+}
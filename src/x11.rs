@@ -1,39 +1,106 @@
-use std::alloc::System;
-use std::fmt::format;
+use std::fmt;
 use std::mem::zeroed;
-use std::process::Command;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use rdev::Key;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use x11rb::connection::Connection;
-use x11rb::errors::ReplyOrIdError;
+use x11rb::errors::{ConnectError, ConnectionError, ReplyError, ReplyOrIdError};
+use x11rb::protocol::randr::ConnectionExt as _;
 use x11rb::protocol::xproto::{self, *};
 use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as _;
 
+use crate::backend::WindowBackend;
 use crate::instanceutils::{get_instance_dir, get_instance_num};
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct InstanceInfo {
     pub window: Window,
     pub pid: u32,
     pub gamedir: String,
     pub instance_num: u32,
 }
+
+#[derive(Debug)]
+pub enum WindowError {
+    Connect(ConnectError),
+    X11(ReplyOrIdError),
+    Timeout(Window),
+    NoWindow(Window),
+    /// The instance is still resetting, or already playing
+    NotIdle(Window),
+    /// The window doesn't say which process it belongs to
+    NoPid(Window),
+    NoGameDir(Window),
+    /// The game directory doesn't match the instance number regex
+    NoInstanceNum(Window, String),
+}
+
+impl fmt::Display for WindowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowError::Connect(e) => write!(f, "Failed to connect to the X server: {}", e),
+            WindowError::X11(e) => write!(f, "X11 error: {}", e),
+            WindowError::Timeout(window) => {
+                write!(f, "Timed out waiting for the window manager to update window {}", window)
+            }
+            WindowError::NoWindow(window) => write!(f, "Window {} doesn't exist", window),
+            WindowError::NotIdle(window) => write!(f, "The instance in window {} isn't idle", window),
+            WindowError::NoPid(window) => write!(f, "Window {} has no _NET_WM_PID", window),
+            WindowError::NoGameDir(window) => {
+                write!(f, "Failed to find the game directory of the process of window {}", window)
+            }
+            WindowError::NoInstanceNum(window, gamedir) => {
+                write!(f, "Window {} has no instance number in its game directory {}", window, gamedir)
+            }
+        }
+    }
+}
+
+impl From<ConnectError> for WindowError {
+    fn from(e: ConnectError) -> Self {
+        WindowError::Connect(e)
+    }
+}
+
+impl From<ReplyOrIdError> for WindowError {
+    fn from(e: ReplyOrIdError) -> Self {
+        WindowError::X11(e)
+    }
+}
+
+impl From<ReplyError> for WindowError {
+    fn from(e: ReplyError) -> Self {
+        WindowError::X11(e.into())
+    }
+}
+
+impl From<ConnectionError> for WindowError {
+    fn from(e: ConnectionError) -> Self {
+        WindowError::X11(e.into())
+    }
+}
+
 pub fn set_window_title(conn: &impl Connection, win: u32, title: &str) -> Result<(), ReplyOrIdError> {
     let atom = conn.intern_atom(true, b"WM_NAME")?.reply()?.atom;
     conn.change_property(PropMode::APPEND, win, atom, AtomEnum::STRING, 8, title.len() as u32, title.as_bytes())?;
     println!("Set window title to {} ( but not actually )", title);
     Ok(())
 }
-fn activate_window(conn: &impl Connection, win: u32) -> Result<(), x11rb::errors::ReplyError> {
+pub fn activate_window(conn: &impl Connection, win: u32) -> Result<(), ReplyError> {
     let active_window = conn
         .intern_atom(false, b"_NET_ACTIVE_WINDOW")?
         .reply()?
         .atom;
+    // The window manager only honours _NET_ACTIVE_WINDOW when it is sent to the root window
+    let root = conn.get_geometry(win)?.reply()?.root;
     let client_message_data = ClientMessageData::from([1, 0, 0, 0, 0]);
 
     let evt = ClientMessageEvent {
-        response_type: 33, // ClientMessage event
+        response_type: CLIENT_MESSAGE_EVENT,
         format: 32,
         sequence: 0,
         window: win,
@@ -42,14 +109,272 @@ fn activate_window(conn: &impl Connection, win: u32) -> Result<(), x11rb::errors
     };
 
     conn.send_event(
-        true,
-        win,
+        false,
+        root,
+        EventMask::SUBSTRUCTURE_NOTIFY | EventMask::SUBSTRUCTURE_REDIRECT,
+        evt,
+    )?;
+    conn.flush()?;
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rect {
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+}
+
+/// Returns the geometry of the RandR monitor the center of the window is on, falling back to the root window.
+pub fn get_monitor_geometry(conn: &impl Connection, win: u32) -> Result<Rect, ReplyError> {
+    let geometry = conn.get_geometry(win)?.reply()?;
+    let position = conn.translate_coordinates(win, geometry.root, 0, 0)?.reply()?;
+    let center_x = position.dst_x as i32 + geometry.width as i32 / 2;
+    let center_y = position.dst_y as i32 + geometry.height as i32 / 2;
+
+    let monitors = conn.randr_get_monitors(geometry.root, true)?.reply()?.monitors;
+    let monitor = monitors.iter().find(|monitor| {
+        (monitor.x as i32..monitor.x as i32 + monitor.width as i32).contains(&center_x)
+            && (monitor.y as i32..monitor.y as i32 + monitor.height as i32).contains(&center_y)
+    });
+    match monitor.or(monitors.iter().find(|monitor| monitor.primary)) {
+        Some(monitor) => Ok(Rect {
+            x: monitor.x,
+            y: monitor.y,
+            width: monitor.width,
+            height: monitor.height,
+        }),
+        None => {
+            let root_geometry = conn.get_geometry(geometry.root)?.reply()?;
+            Ok(Rect {
+                x: 0,
+                y: 0,
+                width: root_geometry.width,
+                height: root_geometry.height,
+            })
+        }
+    }
+}
+
+/// Returns the geometry of the primary RandR monitor, falling back to the first monitor and then the root window.
+pub fn get_primary_monitor_geometry(conn: &impl Connection, root: Window) -> Result<Rect, ReplyError> {
+    let monitors = conn.randr_get_monitors(root, true)?.reply()?.monitors;
+    match monitors.iter().find(|monitor| monitor.primary).or(monitors.first()) {
+        Some(monitor) => Ok(Rect {
+            x: monitor.x,
+            y: monitor.y,
+            width: monitor.width,
+            height: monitor.height,
+        }),
+        None => {
+            let root_geometry = conn.get_geometry(root)?.reply()?;
+            Ok(Rect {
+                x: 0,
+                y: 0,
+                width: root_geometry.width,
+                height: root_geometry.height,
+            })
+        }
+    }
+}
+
+pub fn get_window_geometry(conn: &impl Connection, win: u32) -> Result<Rect, ReplyError> {
+    let geometry = conn.get_geometry(win)?.reply()?;
+    let position = conn.translate_coordinates(win, geometry.root, 0, 0)?.reply()?;
+    Ok(Rect {
+        x: position.dst_x,
+        y: position.dst_y,
+        width: geometry.width,
+        height: geometry.height,
+    })
+}
+
+pub fn is_full_screen(conn: &impl Connection, win: u32) -> Result<bool, ReplyError> {
+    let geometry = conn.get_geometry(win)?.reply()?;
+    let monitor = get_monitor_geometry(conn, win)?;
+    Ok(geometry.width == monitor.width && geometry.height == monitor.height)
+}
+
+/// Toggles `_NET_WM_STATE_FULLSCREEN` and waits for the window manager to confirm it with a ConfigureNotify.
+pub fn set_fullscreen(
+    conn: &impl Connection,
+    win: u32,
+    fullscreen: bool,
+    timeout: Duration,
+) -> Result<(), WindowError> {
+    if is_full_screen(conn, win)? == fullscreen {
+        return Ok(());
+    }
+    let monitor = get_monitor_geometry(conn, win)?;
+    let wm_state = conn.intern_atom(false, b"_NET_WM_STATE")?.reply()?.atom;
+    let wm_state_fullscreen = conn
+        .intern_atom(false, b"_NET_WM_STATE_FULLSCREEN")?
+        .reply()?
+        .atom;
+    let root = conn.get_geometry(win)?.reply()?.root;
+    // _NET_WM_STATE_REMOVE = 0, _NET_WM_STATE_ADD = 1, source indication 1 = application
+    let action = if fullscreen { 1 } else { 0 };
+    let client_message_data = ClientMessageData::from([action, wm_state_fullscreen, 0, 1, 0]);
+
+    let evt = ClientMessageEvent {
+        response_type: CLIENT_MESSAGE_EVENT,
+        format: 32,
+        sequence: 0,
+        window: win,
+        type_: wm_state,
+        data: client_message_data,
+    };
+
+    select_structure_notify(conn, win)?;
+    conn.send_event(
+        false,
+        root,
         EventMask::SUBSTRUCTURE_NOTIFY | EventMask::SUBSTRUCTURE_REDIRECT,
         evt,
     )?;
+    conn.flush()?;
+    wait_for_configure(conn, win, timeout, |event| {
+        (event.width == monitor.width && event.height == monitor.height) == fullscreen
+    })
+}
+
+/// Moves and resizes the window and waits for the resulting ConfigureNotify.
+pub fn set_window_geometry(
+    conn: &impl Connection,
+    win: u32,
+    rect: Rect,
+    timeout: Duration,
+) -> Result<(), WindowError> {
+    let current = get_window_geometry(conn, win)?;
+    if current == rect {
+        return Ok(());
+    }
+    select_structure_notify(conn, win)?;
+    conn.configure_window(
+        win,
+        &ConfigureWindowAux::new()
+            .x(rect.x as i32)
+            .y(rect.y as i32)
+            .width(rect.width as u32)
+            .height(rect.height as u32),
+    )?;
+    conn.flush()?;
+    if current.width == rect.width && current.height == rect.height {
+        // A pure move only produces a synthetic ConfigureNotify on some window managers
+        return Ok(());
+    }
+    wait_for_configure(conn, win, timeout, |event| {
+        event.width == rect.width && event.height == rect.height
+    })
+}
+
+/// Moves the window without waiting for the window manager, optionally resizing it as well.
+pub fn move_window(conn: &impl Connection, win: u32, rect: Rect, resize: bool) -> Result<(), ConnectionError> {
+    let mut aux = ConfigureWindowAux::new().x(rect.x as i32).y(rect.y as i32);
+    if resize {
+        aux = aux.width(rect.width as u32).height(rect.height as u32);
+    }
+    conn.configure_window(win, &aux)?;
+    conn.flush()
+}
+
+fn select_structure_notify(conn: &impl Connection, win: u32) -> Result<(), ReplyError> {
+    conn.change_window_attributes(
+        win,
+        &ChangeWindowAttributesAux::new().event_mask(EventMask::STRUCTURE_NOTIFY),
+    )?
+    .check()
+}
+
+fn wait_for_configure(
+    conn: &impl Connection,
+    win: u32,
+    timeout: Duration,
+    done: impl Fn(&ConfigureNotifyEvent) -> bool,
+) -> Result<(), WindowError> {
+    let start = Instant::now();
+    loop {
+        while let Some(event) = conn.poll_for_event()? {
+            if let Event::ConfigureNotify(event) = event {
+                if event.window == win && done(&event) {
+                    return Ok(());
+                }
+            }
+        }
+        if start.elapsed() > timeout {
+            return Err(WindowError::Timeout(win));
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+pub const MWM_HINTS_DECORATIONS: u32 = 1 << 1;
+
+/// Strips window manager decorations through `_MOTIF_WM_HINTS`, returning the hints that were set before.
+pub fn remove_decorations(conn: &impl Connection, win: u32) -> Result<Option<[u32; 5]>, ReplyError> {
+    let motif_wm_hints = conn.intern_atom(false, b"_MOTIF_WM_HINTS")?.reply()?.atom;
+    let reply = conn
+        .get_property(false, win, motif_wm_hints, motif_wm_hints, 0, 5)?
+        .reply()?;
+    let original = reply.value32().and_then(|values| {
+        let values = values.collect::<Vec<_>>();
+        <[u32; 5]>::try_from(values).ok()
+    });
+
+    // flags, functions, decorations, input_mode, status
+    let mut hints = original.unwrap_or_default();
+    hints[0] |= MWM_HINTS_DECORATIONS;
+    hints[2] = 0;
+    conn.change_property32(PropMode::REPLACE, win, motif_wm_hints, motif_wm_hints, &hints)?
+        .check()?;
+    Ok(original)
+}
+
+pub fn restore_decorations(
+    conn: &impl Connection,
+    win: u32,
+    original: Option<[u32; 5]>,
+) -> Result<(), ReplyError> {
+    let motif_wm_hints = conn.intern_atom(false, b"_MOTIF_WM_HINTS")?.reply()?.atom;
+    match original {
+        Some(hints) => conn
+            .change_property32(PropMode::REPLACE, win, motif_wm_hints, motif_wm_hints, &hints)?
+            .check(),
+        None => conn.delete_property(win, motif_wm_hints)?.check(),
+    }
+}
+
+pub fn get_active_window(conn: &impl Connection, root: Window) -> Result<Option<Window>, ReplyError> {
+    let active_window = conn
+        .intern_atom(false, b"_NET_ACTIVE_WINDOW")?
+        .reply()?
+        .atom;
+    let reply = conn
+        .get_property(false, root, active_window, AtomEnum::WINDOW, 0, 1)?
+        .reply()?;
+    Ok(reply.value32().and_then(|mut value| value.next()))
+}
+
+pub fn wait_for_active_window(conn: &impl Connection, win: u32, timeout: Duration) -> Result<(), WindowError> {
+    let root = conn.get_geometry(win)?.reply()?.root;
+    let start = Instant::now();
+    while get_active_window(conn, root)? != Some(win) {
+        if start.elapsed() > timeout {
+            return Err(WindowError::Timeout(win));
+        }
+        thread::sleep(Duration::from_millis(2));
+    }
     Ok(())
 }
-fn send_key(
+
+pub fn find_wall_window(conn: &impl Connection, root: Window) -> Result<Option<Window>, ReplyOrIdError> {
+    let mut windows = vec![];
+    find_windows_matching_name(conn, root, "Fullscreen Projector", &mut windows)?;
+    Ok(windows.first().copied())
+}
+
+pub fn send_key(
     conn: &impl Connection,
     code: xproto::Keycode,
     state: bool,
@@ -68,7 +393,7 @@ fn send_key(
         event_x: 0,
         event_y: 0,
         same_screen: true,
-        response_type: KEY_PRESS_EVENT,
+        response_type: if state { KEY_PRESS_EVENT } else { KEY_RELEASE_EVENT },
         state: unsafe { zeroed() },
     };
 
@@ -81,19 +406,6 @@ fn send_key(
     .unwrap();
 }
 
-/*
-func (c *Client) GrabKey(key Key, win xproto.Window) error {
-	return xproto.GrabKeyChecked(
-		c.conn,
-		true,
-		win,
-		uint16(key.Mod),
-		key.Code,
-		xproto.GrabModeAsync,
-		xproto.GrabModeAsync,
-	).Check()
-} */
-
 pub fn grab_key(conn: &impl Connection, key: Keycode, win: u32) -> Result<(), ReplyOrIdError> {
     xproto::grab_key(
         conn,
@@ -107,72 +419,8 @@ pub fn grab_key(conn: &impl Connection, key: Keycode, win: u32) -> Result<(), Re
     Ok(())
 }
 
-pub fn calculate_offset(
-    conn: &impl Connection,
-    root: Window,
-) -> Result<xproto::Timestamp, ReplyOrIdError> {
-    let root = &conn.setup().roots[0].root;
-    xproto::change_window_attributes(
-        conn,
-        *root,
-        &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
-    )?;
-    // Get the current system time as u32
-
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u32;
-    let atom = conn.intern_atom(true, b"WM_NAME")?.reply()?.atom;
-    println!("current time: {now}");
-    conn.change_property(PropMode::APPEND, *root, atom, AtomEnum::STRING, 8, 0, &[])?;
-    println!("change property fired");
-    // Get the PropertyNotifyEvent
-    let event = conn.wait_for_event()?;
-    println!("wait_for_event fired");
-    // Get the timestamp from the PropertyNotifyEvent
-    let timestamp = match event {
-        Event::PropertyNotify(event) => event.time,
-        _ => panic!("Unexpected event: {:?}", event),
-    };
-    println!(
-        "now: {}, timestamp: {}, subtracted : {}",
-        now,
-        timestamp,
-        now - timestamp
-    );
-    return Ok(now - timestamp);
-}
-
-pub fn print_all_children_names(
-    conn: &impl Connection,
-    window: Window,
-    root: Window,
-) -> Result<(), ReplyOrIdError> {
-    let tree = conn.query_tree(window)?.reply()?;
-    for child in tree.children {
-        let name = conn
-            .get_property(false, child, AtomEnum::WM_NAME, AtomEnum::STRING, 0, 100)?
-            .reply()?;
-        let name = String::from_utf8_lossy(&name.value);
-        println!("Window: {} ", &name);
-        if (name.contains("Minecraft*")) {
-            println!("Found Minecraft");
-            activate_window(conn, child)?;
-            send_key(
-                conn,
-                72,
-                false,
-                child,
-                (SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis() as u32)
-                    - (calculate_offset(conn, root)? as u32),
-            );
-        }
-        print_all_children_names(conn, child, root)?;
-    }
+pub fn ungrab_key(conn: &impl Connection, key: Keycode, win: u32) -> Result<(), ReplyOrIdError> {
+    xproto::ungrab_key(conn, key, win, ModMask::CONTROL)?.check()?;
     Ok(())
 }
 
@@ -195,42 +443,69 @@ pub fn find_windows_matching_name(
     }
     Ok(())
 }
+/// Instances among the windows whose title contains `name`. Windows that aren't instances are skipped.
 pub fn find_instances(
     conn: &impl Connection,
     window: Window,
+    name: &str,
+    instance_num_regex: &Regex,
 ) -> Result<Vec<InstanceInfo>, ReplyOrIdError> {
-    let mut windows = vec![];
-    find_windows_matching_name(conn, window, "Minecraft", &mut windows)?;
-    Ok(windows
-        .iter()
-        .map(|w| {
-            let pid = get_window_pid(conn, *w).unwrap();
-            let gamedir = get_instance_dir(pid);
-            InstanceInfo {
-                window: *w,
-                pid,
-                gamedir: get_instance_dir(pid),
-                instance_num: get_instance_num(gamedir)
+    Ok(find_instance_windows(conn, window, name, instance_num_regex)?
+        .into_iter()
+        .filter_map(|(window, instance_info)| match instance_info {
+            Ok(instance_info) => Some(instance_info),
+            Err(err) => {
+                println!("Skipping window {window}: {err}");
+                None
             }
         })
         .collect())
 }
 
+/// A window whose title matched, with its instance or why it isn't one
+pub type InstanceWindow = (Window, Result<InstanceInfo, WindowError>);
 
-pub fn get_window_pid(conn: &impl Connection, window: Window) -> Result<u32, ReplyOrIdError> {
-    let pid = get_property_u32(conn, window, "_NET_WM_PID", AtomEnum::CARDINAL)?;
-    Ok(pid)
+/// Every window whose title contains `name`
+pub fn find_instance_windows(
+    conn: &impl Connection,
+    window: Window,
+    name: &str,
+    instance_num_regex: &Regex,
+) -> Result<Vec<InstanceWindow>, ReplyOrIdError> {
+    let mut windows = vec![];
+    find_windows_matching_name(conn, window, name, &mut windows)?;
+    Ok(windows
+        .into_iter()
+        .map(|window| (window, get_instance_info(conn, window, instance_num_regex)))
+        .collect())
 }
 
+fn get_instance_info(conn: &impl Connection, window: Window, instance_num_regex: &Regex) -> Result<InstanceInfo, WindowError> {
+    let pid = get_window_pid(conn, window)?;
+    let gamedir = get_instance_dir(pid).ok_or(WindowError::NoGameDir(window))?;
+    let instance_num =
+        get_instance_num(&gamedir, instance_num_regex).ok_or_else(|| WindowError::NoInstanceNum(window, gamedir.clone()))?;
+    Ok(InstanceInfo {
+        window,
+        pid,
+        gamedir,
+        instance_num,
+    })
+}
+
+pub fn get_window_pid(conn: &impl Connection, window: Window) -> Result<u32, WindowError> {
+    get_property_u32(conn, window, "_NET_WM_PID", AtomEnum::CARDINAL)?.ok_or(WindowError::NoPid(window))
+}
+
+/// The first value of the property, or `None` when the window doesn't have it
 pub fn get_property_u32(
     conn: &impl Connection,
     window: Window,
     name: &str,
     atom_enum: AtomEnum,
-) -> Result<u32, ReplyOrIdError> {
+) -> Result<Option<u32>, ReplyOrIdError> {
     let result = get_property(conn, window, name, atom_enum)?;
-    let result = u32::from_le_bytes(result[0..4].try_into().unwrap());
-    Ok(result)
+    Ok(result.get(0..4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap())))
 }
 pub fn get_property(
     conn: &impl Connection,
@@ -245,3 +520,111 @@ pub fn get_property(
         .reply()?
         .value)
 }
+
+/// The window system of an X server with an EWMH window manager
+pub struct X11Backend {
+    conn: RustConnection,
+    /// Hotkeys are read on their own connection, so waiting for window manager events can't swallow them
+    hotkey_conn: RustConnection,
+    root: Window,
+}
+
+impl X11Backend {
+    pub fn connect() -> Result<Self, WindowError> {
+        let (conn, screen_num) = x11rb::connect(None)?;
+        let (hotkey_conn, _) = x11rb::connect(None)?;
+        let root = conn.setup().roots[screen_num].root;
+        hotkey_conn
+            .change_window_attributes(root, &ChangeWindowAttributesAux::new().event_mask(EventMask::KEY_PRESS))?
+            .check()?;
+        Ok(Self {
+            conn,
+            hotkey_conn,
+            root,
+        })
+    }
+}
+
+impl WindowBackend for X11Backend {
+    fn find_instances(&self, name: &str, instance_num_regex: &Regex) -> Result<Vec<InstanceInfo>, WindowError> {
+        Ok(find_instances(&self.conn, self.root, name, instance_num_regex)?)
+    }
+
+    fn set_title(&self, window: Window, title: &str) -> Result<(), WindowError> {
+        Ok(set_window_title(&self.conn, window, title)?)
+    }
+
+    fn activate(&self, window: Window) -> Result<(), WindowError> {
+        Ok(activate_window(&self.conn, window)?)
+    }
+
+    fn wait_for_active(&self, window: Window, timeout: Duration) -> Result<(), WindowError> {
+        wait_for_active_window(&self.conn, window, timeout)
+    }
+
+    fn find_wall_window(&self) -> Result<Option<Window>, WindowError> {
+        Ok(find_wall_window(&self.conn, self.root)?)
+    }
+
+    fn geometry(&self, window: Window) -> Result<Rect, WindowError> {
+        Ok(get_window_geometry(&self.conn, window)?)
+    }
+
+    fn monitor_geometry(&self, window: Window) -> Result<Rect, WindowError> {
+        Ok(get_monitor_geometry(&self.conn, window)?)
+    }
+
+    fn primary_monitor_geometry(&self) -> Result<Rect, WindowError> {
+        Ok(get_primary_monitor_geometry(&self.conn, self.root)?)
+    }
+
+    fn set_geometry(&self, window: Window, rect: Rect, timeout: Duration) -> Result<(), WindowError> {
+        set_window_geometry(&self.conn, window, rect, timeout)
+    }
+
+    fn move_window(&self, window: Window, rect: Rect, resize: bool) -> Result<(), WindowError> {
+        Ok(move_window(&self.conn, window, rect, resize)?)
+    }
+
+    fn set_fullscreen(&self, window: Window, fullscreen: bool, timeout: Duration) -> Result<(), WindowError> {
+        set_fullscreen(&self.conn, window, fullscreen, timeout)
+    }
+
+    fn remove_decorations(&self, window: Window) -> Result<Option<[u32; 5]>, WindowError> {
+        Ok(remove_decorations(&self.conn, window)?)
+    }
+
+    fn restore_decorations(&self, window: Window, original: Option<[u32; 5]>) -> Result<(), WindowError> {
+        Ok(restore_decorations(&self.conn, window, original)?)
+    }
+
+    fn send_keys(&self, window: Window, keys: &[(Keycode, bool)]) -> Result<(), WindowError> {
+        for (key, pressed) in keys {
+            send_key(&self.conn, *key, *pressed, window, x11rb::CURRENT_TIME);
+        }
+        Ok(self.conn.flush()?)
+    }
+
+    fn grab_key(&self, key: Keycode) -> Result<(), WindowError> {
+        Ok(grab_key(&self.hotkey_conn, key, self.root)?)
+    }
+
+    fn ungrab_key(&self, key: Keycode) -> Result<(), WindowError> {
+        Ok(ungrab_key(&self.hotkey_conn, key, self.root)?)
+    }
+
+    fn poll_hotkey(&self) -> Option<Keycode> {
+        while let Ok(Some(event)) = self.hotkey_conn.poll_for_event() {
+            match event {
+                Event::KeyPress(event) => return Some(event.detail),
+                event => println!("Event: {:?}", event),
+            }
+        }
+        None
+    }
+
+    fn pointer_position(&self) -> Result<(i16, i16), WindowError> {
+        let pointer = self.hotkey_conn.query_pointer(self.root)?.reply()?;
+        Ok((pointer.root_x, pointer.root_y))
+    }
+}
@@ -0,0 +1,317 @@
+//! Runs rulti against simulated instances on a private Xvfb display.
+#![allow(dead_code)]
+
+pub mod wm;
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicU32, Ordering::SeqCst},
+    thread,
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use serde_json::Value;
+use x11rb::{
+    connection::Connection,
+    protocol::xproto::{AtomEnum, ConnectionExt, ImageFormat, Window},
+    rust_connection::RustConnection,
+};
+
+pub const SCREEN_WIDTH: u16 = 1920;
+pub const SCREEN_HEIGHT: u16 = 1080;
+/// The size rulti-sim opens its windows with
+pub const SIM_WIDTH: u16 = 854;
+pub const SIM_HEIGHT: u16 = 480;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+static SESSION_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Polls until the condition holds, panicking with the description after a while.
+pub fn wait_until(description: &str, mut condition: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        if start.elapsed() > TIMEOUT {
+            panic!("Timed out waiting until {description}");
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+struct Xvfb {
+    process: Child,
+    display: String,
+}
+
+impl Xvfb {
+    /// Starts Xvfb on a free display.
+    fn start() -> Self {
+        let screen = format!("{SCREEN_WIDTH}x{SCREEN_HEIGHT}x24");
+        // -displayfd picks a free display and writes its number once the server accepts connections
+        let mut process = match Command::new("Xvfb")
+            .args(["-displayfd", "1", "-screen", "0", &screen, "-nolisten", "tcp"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(process) => process,
+            Err(err) => panic!("Failed to start Xvfb, these tests need it installed: {err}"),
+        };
+        let mut line = String::new();
+        BufReader::new(process.stdout.take().unwrap()).read_line(&mut line).unwrap();
+        let display = format!(":{}", line.trim());
+        Self { process, display }
+    }
+}
+
+impl Drop for Xvfb {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Xvfb, the window manager, simulated instances and rulti itself, torn down on drop.
+/// Fields drop in order, so rulti and the instances go before the X server.
+pub struct Session {
+    rulti: Child,
+    sim: Child,
+    pub dir: PathBuf,
+    pub conn: RustConnection,
+    pub root: Window,
+    /// Window of every instance by instance number
+    pub windows: HashMap<u32, Window>,
+    xvfb: Xvfb,
+}
+
+impl Session {
+    /// Starts everything with the given extra config lines.
+    pub fn start(instance_nums: &[u32], extra_config: &str) -> Self {
+        let xvfb = Xvfb::start();
+        let dir = env::temp_dir().join(format!(
+            "rulti-test-{}-{}",
+            std::process::id(),
+            SESSION_COUNT.fetch_add(1, SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        wm::spawn(&xvfb.display);
+        let (conn, screen_num) = x11rb::connect(Some(&xvfb.display)).unwrap();
+        let root = conn.setup().roots[screen_num].root;
+
+        let sim = Command::new(env!("CARGO_BIN_EXE_rulti-sim"))
+            .env("DISPLAY", &xvfb.display)
+            .arg("--root")
+            .arg(&dir)
+            .args(["--leave-ms", "20", "--generate-ms", "100", "--preview-ms", "300", "--jitter", "0"])
+            .args(instance_nums.iter().map(u32::to_string))
+            .spawn()
+            .unwrap();
+        let mut windows = HashMap::new();
+        wait_until("every simulated instance has a window", || {
+            windows = instance_windows(&conn, root);
+            windows.len() == instance_nums.len()
+        });
+
+        let config_path = dir.join("rulti.toml");
+        let config = format!(
+            "wall_file = {wall_file:?}\ncontrol_socket = {socket:?}\nstats_file = {stats:?}\n{extra_config}\n\
+             [reset_counter]\ntotal_file = {total:?}\n",
+            wall_file = dir.join("wall.json"),
+            socket = dir.join("rulti.sock"),
+            stats = dir.join("stats.csv"),
+            total = dir.join("reset_total.txt"),
+        );
+        fs::write(&config_path, config).unwrap();
+        let rulti = Command::new(env!("CARGO_BIN_EXE_rulti"))
+            .env("DISPLAY", &xvfb.display)
+            .env("HOME", &dir)
+            .env("XDG_DATA_HOME", dir.join("data"))
+            .arg("--config")
+            .arg(&config_path)
+            .arg("run")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let session = Self {
+            rulti,
+            sim,
+            dir,
+            conn,
+            root,
+            windows,
+            xvfb,
+        };
+        wait_until("rulti listens on its control socket", || {
+            UnixStream::connect(session.dir.join("rulti.sock")).is_ok()
+        });
+        wait_until("rulti wrote the wall file", || session.dir.join("wall.json").exists());
+        session
+    }
+
+    /// Sends a control command and returns the JSON reply.
+    pub fn send(&self, command: &str) -> Value {
+        let mut stream = UnixStream::connect(self.dir.join("rulti.sock")).unwrap();
+        stream.write_all(format!("{command}\n").as_bytes()).unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).unwrap();
+        serde_json::from_str(&reply).unwrap()
+    }
+
+    /// Sends a command that has to succeed.
+    pub fn command(&self, command: &str) {
+        let reply = self.send(command);
+        assert_eq!(reply["ok"], true, "{command} failed: {reply}");
+    }
+
+    /// Subscribes to rulti's events, starting from now.
+    pub fn subscribe(&self) -> Events {
+        let mut stream = UnixStream::connect(self.dir.join("rulti.sock")).unwrap();
+        stream.write_all(b"subscribe\n").unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut events = Events(BufReader::new(stream));
+        assert_eq!(events.next()["ok"], true);
+        events
+    }
+
+    pub fn status(&self) -> Value {
+        self.send("status")
+    }
+
+    pub fn state(&self, instance_num: u32) -> String {
+        self.status()["instances"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|instance| instance["instance_num"] == instance_num)
+            .map(|instance| instance["state"].as_str().unwrap().to_string())
+            .unwrap()
+    }
+
+    pub fn wall_file(&self) -> Vec<Value> {
+        let contents = fs::read_to_string(self.dir.join("wall.json")).unwrap();
+        let wall: Value = serde_json::from_str(&contents).unwrap();
+        wall["instances"].as_array().unwrap().clone()
+    }
+
+    pub fn wall_instance(&self, instance_num: u32) -> Value {
+        self.wall_file()
+            .into_iter()
+            .find(|instance| instance["instance_num"] == instance_num)
+            .unwrap()
+    }
+
+    /// What the simulated instance last wrote to `wpstateout.txt`
+    pub fn world_preview_state(&self, instance_num: u32) -> String {
+        let path = self.dir.join(format!("RSG {instance_num}")).join(".minecraft").join("wpstateout.txt");
+        fs::read_to_string(path).unwrap_or_default()
+    }
+
+    /// Position and size of the instance window as (x, y, width, height)
+    pub fn geometry(&self, instance_num: u32) -> (i16, i16, u16, u16) {
+        let geometry = self.conn.get_geometry(self.windows[&instance_num]).unwrap().reply().unwrap();
+        (geometry.x, geometry.y, geometry.width, geometry.height)
+    }
+
+    pub fn active_window(&self) -> Option<Window> {
+        let atom = self.conn.intern_atom(false, b"_NET_ACTIVE_WINDOW").unwrap().reply().unwrap().atom;
+        let reply = self
+            .conn
+            .get_property(false, self.root, atom, AtomEnum::WINDOW, 0, 1)
+            .unwrap()
+            .reply()
+            .unwrap();
+        reply.value32().and_then(|mut value| value.next())
+    }
+
+    /// The top-level window with exactly this `WM_NAME`
+    pub fn window_named(&self, name: &str) -> Option<Window> {
+        let children = self.conn.query_tree(self.root).unwrap().reply().unwrap().children;
+        children.into_iter().find(|&window| {
+            let reply = self
+                .conn
+                .get_property(false, window, AtomEnum::WM_NAME, AtomEnum::STRING, 0, 256)
+                .unwrap()
+                .reply();
+            reply.is_ok_and(|reply| reply.value == name.as_bytes())
+        })
+    }
+
+    /// Colour of one pixel of the window as 0xRRGGBB, assuming the 24 bit TrueColor visual Xvfb starts with
+    pub fn pixel(&self, window: Window, x: i16, y: i16) -> u32 {
+        let image = self.conn.get_image(ImageFormat::Z_PIXMAP, window, x, y, 1, 1, !0).unwrap().reply().unwrap();
+        u32::from_le_bytes(image.data[..4].try_into().unwrap()) & 0xffffff
+    }
+
+    /// Waits until every given instance went through a whole reset and is paused in its new world.
+    pub fn wait_for_idle(&self, instance_nums: &[u32]) {
+        for instance_num in instance_nums {
+            wait_until(&format!("instance {instance_num} is idle"), || {
+                self.state(*instance_num) == "Idle"
+                    && self.world_preview_state(*instance_num) == "inworld,paused"
+            });
+        }
+    }
+}
+
+pub struct Events(BufReader<UnixStream>);
+
+impl Events {
+    pub fn next(&mut self) -> Value {
+        let mut line = String::new();
+        self.0.read_line(&mut line).expect("Timed out waiting for an event");
+        serde_json::from_str(&line).unwrap()
+    }
+
+    /// Skips events until one matches.
+    pub fn wait_for(&mut self, matches: impl Fn(&Value) -> bool) -> Value {
+        loop {
+            let event = self.next();
+            if matches(&event) {
+                return event;
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.rulti.kill();
+        let _ = self.rulti.wait();
+        // The instance processes exit by themselves once the X server is gone
+        let _ = self.sim.kill();
+        let _ = self.sim.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Windows with a `_NET_WM_PID` whose process runs in an `RSG N` directory, by instance number
+fn instance_windows(conn: &RustConnection, root: Window) -> HashMap<u32, Window> {
+    let instance_num_regex = Regex::new(r"RSG (\d+)/").unwrap();
+    let net_wm_pid = conn.intern_atom(false, b"_NET_WM_PID").unwrap().reply().unwrap().atom;
+    let mut windows = HashMap::new();
+    for window in conn.query_tree(root).unwrap().reply().unwrap().children {
+        let Ok(reply) = conn.get_property(false, window, net_wm_pid, AtomEnum::CARDINAL, 0, 1).unwrap().reply() else {
+            continue;
+        };
+        let Some(pid) = reply.value32().and_then(|mut value| value.next()) else {
+            continue;
+        };
+        let Ok(cwd) = fs::read_link(format!("/proc/{pid}/cwd")) else {
+            continue;
+        };
+        let cwd = format!("{}/", cwd.display());
+        if let Some(captures) = instance_num_regex.captures(&cwd) {
+            windows.insert(captures[1].parse().unwrap(), window);
+        }
+    }
+    windows
+}
+
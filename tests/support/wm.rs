@@ -0,0 +1,218 @@
+//! Just enough of an EWMH window manager for rulti: mapping, configuring, focus through
+//! `_NET_ACTIVE_WINDOW` and fullscreen through `_NET_WM_STATE`. Windows aren't reparented.
+
+use std::{collections::HashMap, sync::mpsc, thread};
+
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xproto::{
+            AtomEnum, ChangeWindowAttributesAux, ClientMessageEvent, ConfigureRequestEvent, ConfigureWindowAux,
+            ConnectionExt, CreateWindowAux, EventMask, InputFocus, PropMode, StackMode, Window, WindowClass,
+        },
+        Event,
+    },
+    rust_connection::RustConnection,
+    wrapper::ConnectionExt as _,
+    COPY_DEPTH_FROM_PARENT,
+};
+
+struct Atoms {
+    net_supported: u32,
+    net_supporting_wm_check: u32,
+    net_wm_name: u32,
+    utf8_string: u32,
+    net_active_window: u32,
+    net_wm_state: u32,
+    net_wm_state_fullscreen: u32,
+}
+
+impl Atoms {
+    fn intern(conn: &RustConnection) -> Self {
+        let intern = |name: &str| conn.intern_atom(false, name.as_bytes()).unwrap().reply().unwrap().atom;
+        Self {
+            net_supported: intern("_NET_SUPPORTED"),
+            net_supporting_wm_check: intern("_NET_SUPPORTING_WM_CHECK"),
+            net_wm_name: intern("_NET_WM_NAME"),
+            utf8_string: intern("UTF8_STRING"),
+            net_active_window: intern("_NET_ACTIVE_WINDOW"),
+            net_wm_state: intern("_NET_WM_STATE"),
+            net_wm_state_fullscreen: intern("_NET_WM_STATE_FULLSCREEN"),
+        }
+    }
+}
+
+struct WindowManager {
+    conn: RustConnection,
+    root: Window,
+    screen_width: u16,
+    screen_height: u16,
+    atoms: Atoms,
+    /// Geometry of fullscreen windows from before they went fullscreen
+    windowed: HashMap<Window, (i16, i16, u16, u16)>,
+}
+
+/// Starts the window manager on its own thread, returning once it manages the root window.
+pub fn spawn(display: &str) {
+    let (conn, screen_num) = x11rb::connect(Some(display)).unwrap();
+    let (ready_sender, ready_receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let screen = &conn.setup().roots[screen_num];
+        let (root, screen_width, screen_height) = (screen.root, screen.width_in_pixels, screen.height_in_pixels);
+        let atoms = Atoms::intern(&conn);
+        let mut wm = WindowManager {
+            conn,
+            root,
+            screen_width,
+            screen_height,
+            atoms,
+            windowed: HashMap::new(),
+        };
+        wm.manage_root();
+        ready_sender.send(()).unwrap();
+        // Ends with an error once the X server is gone
+        while let Ok(event) = wm.conn.wait_for_event() {
+            wm.handle(event);
+            let _ = wm.conn.flush();
+        }
+    });
+    ready_receiver.recv().unwrap();
+}
+
+impl WindowManager {
+    fn manage_root(&self) {
+        let mask = EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY;
+        self.conn
+            .change_window_attributes(self.root, &ChangeWindowAttributesAux::new().event_mask(mask))
+            .unwrap()
+            .check()
+            .expect("another window manager is running");
+
+        let check_window = self.conn.generate_id().unwrap();
+        self.conn
+            .create_window(
+                COPY_DEPTH_FROM_PARENT,
+                check_window,
+                self.root,
+                -1,
+                -1,
+                1,
+                1,
+                0,
+                WindowClass::INPUT_ONLY,
+                0,
+                &CreateWindowAux::new(),
+            )
+            .unwrap();
+        for window in [self.root, check_window] {
+            self.conn
+                .change_property32(
+                    PropMode::REPLACE,
+                    window,
+                    self.atoms.net_supporting_wm_check,
+                    AtomEnum::WINDOW,
+                    &[check_window],
+                )
+                .unwrap();
+        }
+        self.conn
+            .change_property8(PropMode::REPLACE, check_window, self.atoms.net_wm_name, self.atoms.utf8_string, b"wm-stand-in")
+            .unwrap();
+        let supported = [
+            self.atoms.net_active_window,
+            self.atoms.net_wm_state,
+            self.atoms.net_wm_state_fullscreen,
+        ];
+        self.conn
+            .change_property32(PropMode::REPLACE, self.root, self.atoms.net_supported, AtomEnum::ATOM, &supported)
+            .unwrap();
+        self.conn.flush().unwrap();
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::MapRequest(event) => {
+                let _ = self.conn.map_window(event.window);
+            }
+            Event::ConfigureRequest(event) => self.configure(&event),
+            Event::ClientMessage(event) if event.type_ == self.atoms.net_active_window => self.activate(event.window),
+            Event::ClientMessage(event) if event.type_ == self.atoms.net_wm_state => self.change_state(&event),
+            Event::DestroyNotify(event) => {
+                self.windowed.remove(&event.window);
+            }
+            _ => {}
+        }
+    }
+
+    fn configure(&self, event: &ConfigureRequestEvent) {
+        // Like real window managers, fullscreen windows keep covering the monitor
+        if self.windowed.contains_key(&event.window) {
+            return;
+        }
+        let _ = self
+            .conn
+            .configure_window(event.window, &ConfigureWindowAux::from_configure_request(event));
+    }
+
+    fn activate(&self, window: Window) {
+        let _ = self
+            .conn
+            .configure_window(window, &ConfigureWindowAux::new().stack_mode(StackMode::ABOVE));
+        let _ = self.conn.set_input_focus(InputFocus::POINTER_ROOT, window, x11rb::CURRENT_TIME);
+        let _ = self.conn.change_property32(
+            PropMode::REPLACE,
+            self.root,
+            self.atoms.net_active_window,
+            AtomEnum::WINDOW,
+            &[window],
+        );
+    }
+
+    fn change_state(&mut self, event: &ClientMessageEvent) {
+        let [action, first, second, ..] = event.data.as_data32();
+        if first != self.atoms.net_wm_state_fullscreen && second != self.atoms.net_wm_state_fullscreen {
+            return;
+        }
+        let fullscreen = self.windowed.contains_key(&event.window);
+        // _NET_WM_STATE_REMOVE = 0, _NET_WM_STATE_ADD = 1, _NET_WM_STATE_TOGGLE = 2
+        let want_fullscreen = match action {
+            0 => false,
+            1 => true,
+            _ => !fullscreen,
+        };
+        if want_fullscreen == fullscreen {
+            return;
+        }
+        let window = event.window;
+        if want_fullscreen {
+            let Ok(geometry) = self.conn.get_geometry(window).unwrap().reply() else {
+                return;
+            };
+            self.windowed
+                .insert(window, (geometry.x, geometry.y, geometry.width, geometry.height));
+            let aux = ConfigureWindowAux::new()
+                .x(0)
+                .y(0)
+                .width(self.screen_width as u32)
+                .height(self.screen_height as u32)
+                .stack_mode(StackMode::ABOVE);
+            let _ = self.conn.configure_window(window, &aux);
+            let _ = self.conn.change_property32(
+                PropMode::REPLACE,
+                window,
+                self.atoms.net_wm_state,
+                AtomEnum::ATOM,
+                &[self.atoms.net_wm_state_fullscreen],
+            );
+        } else {
+            let (x, y, width, height) = self.windowed.remove(&window).unwrap();
+            let aux = ConfigureWindowAux::new()
+                .x(x as i32)
+                .y(y as i32)
+                .width(width as u32)
+                .height(height as u32);
+            let _ = self.conn.configure_window(window, &aux);
+            let _ = self.conn.delete_property(window, self.atoms.net_wm_state);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Runs rulti against simulated instances under Xvfb. Ignored by default, run with `cargo test -- --ignored`.
+
+mod support;
+
+use std::collections::{BTreeMap, HashSet};
+
+use serde_json::Value;
+use support::{wait_until, Session, SCREEN_HEIGHT, SCREEN_WIDTH, SIM_HEIGHT, SIM_WIDTH};
+use x11rb::protocol::xproto::{ConnectionExt, MapState};
+
+fn instance_nums(event: &Value) -> Vec<u64> {
+    event["instance_nums"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|instance_num| instance_num.as_u64().unwrap())
+        .collect()
+}
+
+#[test]
+#[ignore = "needs Xvfb"]
+fn wall_shows_every_instance_in_a_grid() {
+    let session = Session::start(&[1, 2, 3, 4], "[layout]\ntype = \"grid\"");
+
+    let wall = session.wall_file();
+    assert_eq!(wall.len(), 4);
+    let rects: HashSet<_> = wall
+        .iter()
+        .map(|instance| {
+            assert_eq!(instance["locked"], false);
+            assert_eq!(instance["state"], "Idle");
+            (
+                instance["x"].as_u64().unwrap(),
+                instance["y"].as_u64().unwrap(),
+                instance["width"].as_u64().unwrap(),
+                instance["height"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    let (width, height) = (SCREEN_WIDTH as u64 / 2, SCREEN_HEIGHT as u64 / 2);
+    let expected = HashSet::from([
+        (0, 0, width, height),
+        (width, 0, width, height),
+        (0, height, width, height),
+        (width, height, width, height),
+    ]);
+    assert_eq!(rects, expected);
+
+    let status = session.status();
+    assert_eq!(status["queue_len"], 4);
+    assert_eq!(status["playing"], Value::Null);
+}
+
+#[test]
+#[ignore = "needs Xvfb"]
+fn resets_go_through_every_state() {
+    let session = Session::start(&[1, 2], "");
+    let mut events = session.subscribe();
+
+    session.command("reset-all");
+    let mut transitions: BTreeMap<u64, Vec<(String, String)>> = BTreeMap::new();
+    while transitions.values().filter(|states| states.last().is_some_and(|(_, to)| to == "Idle")).count() < 2 {
+        let event = events.wait_for(|event| event["event"] == "state_changed");
+        transitions.entry(event["instance_num"].as_u64().unwrap()).or_default().push((
+            event["from"].as_str().unwrap().to_string(),
+            event["to"].as_str().unwrap().to_string(),
+        ));
+    }
+    let expected: Vec<(String, String)> = [
+        ("Idle", "Resetting"),
+        ("Resetting", "LoadingScreen"),
+        ("LoadingScreen", "Preview"),
+        ("Preview", "Idle"),
+    ]
+    .into_iter()
+    .map(|(from, to)| (from.to_string(), to.to_string()))
+    .collect();
+    assert_eq!(transitions[&1], expected);
+    assert_eq!(transitions[&2], expected);
+
+    // Idle instances are paused with F3+Esc
+    session.wait_for_idle(&[1, 2]);
+    assert_eq!(session.status()["reset_count"], 2);
+}
+
+#[test]
+#[ignore = "needs Xvfb"]
+fn lock_bag_reset_play_and_exit() {
+    let session = Session::start(
+        &[1, 2, 3, 4],
+        "bag_size = 2\n[layout]\ntype = \"bag_grid\"\nbag_size = 2\nbag_cols = 2\nbags_horizontal = 2\nbags_vertical = 2",
+    );
+    let mut events = session.subscribe();
+    session.command("reset-all");
+    session.wait_for_idle(&[1, 2, 3, 4]);
+
+    session.command("lock 1");
+    assert_eq!(session.status()["locked"], serde_json::json!([1]));
+    wait_until("the wall file shows instance 1 locked", || {
+        session.wall_instance(1)["locked"] == true
+    });
+
+    // The locked instance stays out of the bag
+    session.command("reset-bag");
+    let bag = instance_nums(&events.wait_for(|event| event["event"] == "bag_reset"));
+    assert!(!bag.is_empty() && bag.len() <= 2, "unexpected bag {bag:?}");
+    assert!(!bag.contains(&1), "the locked instance was reset: {bag:?}");
+    let bag: Vec<u32> = bag.into_iter().map(|instance_num| instance_num as u32).collect();
+    session.wait_for_idle(&bag);
+    assert_eq!(session.state(1), "Idle");
+
+    session.command("play 1");
+    events.wait_for(|event| event["event"] == "played" && event["instance_num"] == 1);
+    let status = session.status();
+    assert_eq!(status["playing"], 1);
+    assert_eq!(status["locked"], serde_json::json!([]));
+    assert_eq!(session.active_window(), Some(session.windows[&1]));
+    assert_eq!(session.geometry(1), (0, 0, SCREEN_WIDTH, SCREEN_HEIGHT));
+    // Three presses of Escape take the game out of the F3+Esc pause
+    wait_until("instance 1 is unpaused", || session.world_preview_state(1) == "inworld,unpaused");
+    wait_until("the wall file shows instance 1 playing", || {
+        session.wall_instance(1)["playing"] == true
+    });
+    assert_eq!(session.send("reset-bag")["ok"], false);
+
+    session.command("exit");
+    events.wait_for(|event| event["event"] == "exited" && event["instance_num"] == 1);
+    events.wait_for(|event| event["event"] == "reset" && event["instance_num"] == 1);
+    assert_eq!(session.status()["playing"], Value::Null);
+    assert_eq!(session.geometry(1), (0, 0, SIM_WIDTH, SIM_HEIGHT));
+    session.wait_for_idle(&[1]);
+    let wall_instance = session.wall_instance(1);
+    assert_eq!(wall_instance["playing"], false);
+    assert_eq!(wall_instance["locked"], false);
+}
+
+#[test]
+#[ignore = "needs Xvfb"]
+fn projector_draws_every_instance_where_the_wall_has_it() {
+    let session = Session::start(&[1, 2, 3, 4], "projector = true\n[layout]\ntype = \"grid\"");
+    let mut projector = None;
+    wait_until("the projector window is open", || {
+        projector = session.window_named("rulti Fullscreen Projector");
+        projector.is_some()
+    });
+    let projector = projector.unwrap();
+    let attributes = session.conn.get_window_attributes(projector).unwrap().reply().unwrap();
+    assert_eq!(attributes.map_state, MapState::VIEWABLE);
+    let geometry = session.conn.get_geometry(projector).unwrap().reply().unwrap();
+    assert_eq!((geometry.x, geometry.y, geometry.width, geometry.height), (0, 0, SCREEN_WIDTH, SCREEN_HEIGHT));
+
+    // Every instance is scaled into its wall rect, showing rulti-sim's colour for a paused world
+    session.command("reset-all");
+    session.wait_for_idle(&[1, 2, 3, 4]);
+    for instance in session.wall_file() {
+        let instance_num = instance["instance_num"].as_u64().unwrap();
+        let x = (instance["x"].as_u64().unwrap() + instance["width"].as_u64().unwrap() / 2) as i16;
+        let y = (instance["y"].as_u64().unwrap() + instance["height"].as_u64().unwrap() / 2) as i16;
+        wait_until(&format!("the projector shows instance {instance_num} paused"), || {
+            session.pixel(projector, x, y) == 0x3a5a80
+        });
+    }
+
+    // The thumbnail follows the instance when it resets
+    session.command("reset 1");
+    let wall_instance = session.wall_instance(1);
+    let x = (wall_instance["x"].as_u64().unwrap() + 10) as i16;
+    let y = (wall_instance["y"].as_u64().unwrap() + 10) as i16;
+    wait_until("the projector shows instance 1 resetting", || session.pixel(projector, x, y) != 0x3a5a80);
+    session.wait_for_idle(&[1]);
+    wait_until("the projector shows instance 1 paused again", || session.pixel(projector, x, y) == 0x3a5a80);
+}